@@ -0,0 +1,168 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::info;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::{DbInfo, EngineStats, Handshake, KvStoreError, Protocol, Request, Response, Result};
+use crate::client::{
+    expect_dbs, expect_handshake, expect_key_results, expect_keys, expect_pairs, expect_stats, expect_unit,
+    expect_value, hello_request, key_value, with_deadline_request,
+};
+use crate::protocol::{self, read_frame_async, read_json_async, write_frame_async, Frame, JsonBuf, BINARY_MAGIC, MAX_FRAME_LEN};
+
+/// the async counterpart of `KvClient`, for tokio applications
+pub struct AsyncKvClient {
+    writer: BufWriter<OwnedWriteHalf>,
+    reader: BufReader<OwnedReadHalf>,
+    // the namespace attached to every request
+    namespace: Option<String>,
+    // the deadline attached to every request
+    deadline: Option<Duration>,
+    protocol: Protocol,
+    // id of the next request
+    next_id: u32,
+    // bytes of json responses read ahead
+    buf: JsonBuf,
+}
+
+impl AsyncKvClient {
+    /// connect to the server
+    pub async fn new(addr: SocketAddr) -> Result<AsyncKvClient> {
+        AsyncKvClient::with_protocol(addr, Protocol::Json).await
+    }
+
+    /// connect to the server talking the given protocol. over the binary protocol the server
+    /// only takes requests after a `hello`
+    pub async fn with_protocol(addr: SocketAddr, protocol: Protocol) -> Result<AsyncKvClient> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut writer = BufWriter::new(writer);
+        if protocol == Protocol::Binary {
+            writer.write_all(BINARY_MAGIC).await?;
+        }
+        info!("connected");
+        Ok(AsyncKvClient { writer, reader: BufReader::new(reader), namespace: None, deadline: None, protocol, next_id: 0, buf: JsonBuf::default() })
+    }
+
+    /// introduce the client to the server, see `KvClient::hello`
+    pub async fn hello(&mut self, client: &str, features: &[&str], token: Option<String>) -> Result<Handshake> {
+        expect_handshake(self.call(&hello_request(client, features, token)).await?)
+    }
+
+    /// send the following requests to namespace `name`, or the default namespace if None
+    pub fn use_namespace(&mut self, name: Option<String>) {
+        self.namespace = name;
+    }
+
+    /// give the following requests a deadline, see `KvClient::set_deadline`
+    pub fn set_deadline(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout;
+    }
+
+    /// set key to value
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        let command = Request::Set { key, value, namespace: self.namespace.clone() };
+        expect_unit(self.call(&command).await?)
+    }
+
+    /// get the value of key, a missing key gives `NotFound`
+    pub async fn get(&mut self, key: String) -> Result<String> {
+        let command = Request::Get { key, namespace: self.namespace.clone() };
+        expect_value(self.call(&command).await?)
+    }
+
+    /// remove key
+    pub async fn rm(&mut self, key: String) -> Result<()> {
+        let command = Request::Rm { key, namespace: self.namespace.clone() };
+        expect_unit(self.call(&command).await?)
+    }
+
+    /// get the values of keys with one request, a missing key gives `NotFound`
+    pub async fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Result<String>>> {
+        let command = Request::MGet { keys, namespace: self.namespace.clone() };
+        Ok(expect_key_results(self.call(&command).await?)?.into_iter().map(key_value).collect())
+    }
+
+    /// set the pairs with one request, see `KvClient::mset`
+    pub async fn mset(&mut self, pairs: Vec<(String, String)>, atomic: bool) -> Result<Vec<Result<()>>> {
+        let command = Request::MSet { pairs, atomic, namespace: self.namespace.clone() };
+        Ok(expect_key_results(self.call(&command).await?)?.into_iter().map(|res| key_value(res).map(|_| ())).collect())
+    }
+
+    /// remove keys with one request, a missing key gives `NotFound`
+    pub async fn mdel(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let command = Request::MDel { keys, namespace: self.namespace.clone() };
+        Ok(expect_key_results(self.call(&command).await?)?.into_iter().map(|res| key_value(res).map(|_| ())).collect())
+    }
+
+    /// get the pairs whose key starts with prefix, at most limit of them
+    pub async fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let command = Request::Scan { prefix, limit, namespace: self.namespace.clone() };
+        expect_pairs(self.call(&command).await?)
+    }
+
+    /// get the keys whose JSON value has value at the path of the secondary index
+    pub async fn query_index(&mut self, index: String, value: String) -> Result<Vec<String>> {
+        let command = Request::QueryIndex { index, value, namespace: self.namespace.clone() };
+        expect_keys(self.call(&command).await?)
+    }
+
+    /// get the statistics of the engine
+    pub async fn info(&mut self) -> Result<EngineStats> {
+        let command = Request::Info { namespace: self.namespace.clone() };
+        expect_stats(self.call(&command).await?)
+    }
+
+    /// switch the connection to logical database `db`
+    pub async fn select(&mut self, db: String) -> Result<()> {
+        expect_unit(self.call(&Request::Select { db }).await?)
+    }
+
+    /// get the statistics of the logical databases served by the server
+    pub async fn db_stats(&mut self) -> Result<Vec<DbInfo>> {
+        expect_dbs(self.call(&Request::DbStats).await?)
+    }
+
+    /// ask the server to write a checkpoint of the selected database into dest
+    pub async fn backup(&mut self, dest: String) -> Result<()> {
+        expect_unit(self.call(&Request::Backup { dest }).await?)
+    }
+
+    // send a request and wait for its response, an error response becomes the matching error
+    async fn call(&mut self, request: &Request) -> Result<Response> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let with_deadline;
+        let request = match self.deadline {
+            Some(timeout) => {
+                with_deadline = with_deadline_request(request, timeout);
+                &with_deadline
+            },
+            None => request,
+        };
+        let closed = || KvStoreError::Protocol(String::from("connection closed"));
+        let response = match self.protocol {
+            Protocol::Json => {
+                self.writer.write_all(&serde_json::to_vec(request)?).await?;
+                self.writer.flush().await?;
+                read_json_async(&mut self.reader, &mut self.buf, MAX_FRAME_LEN as usize).await?.ok_or_else(closed)?
+            },
+            Protocol::Binary => {
+                let frame = Frame { opcode: protocol::opcode(request), id, payload: bincode::serialize(request)? };
+                write_frame_async(&mut self.writer, &frame).await?;
+                self.writer.flush().await?;
+                let reply = read_frame_async(&mut self.reader).await?.ok_or_else(closed)?;
+                if reply.id != id {
+                    return Err(KvStoreError::Protocol(format!("expect reply to {}, got {}", id, reply.id)));
+                }
+                bincode::deserialize(&reply.payload)?
+            },
+        };
+        match response {
+            Response::Error { code, message } => Err(KvStoreError::from_code(code, message)),
+            response => Ok(response),
+        }
+    }
+}
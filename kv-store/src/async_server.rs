@@ -0,0 +1,364 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::{Databases, KvEngine, KvStoreError, Request, Response, Result};
+use crate::limits::{self, Limits, Slots, MAX_REJECTING, REJECT_WAIT};
+use crate::protocol::{read_request_async, write_frame_async, read_json_async, JsonBuf, Frame, BINARY_MAGIC, OP_ERROR};
+use crate::server::{error_response, Session};
+use crate::shutdown::{Connection, Shutdown, ShutdownHandle};
+
+/// a server on the tokio runtime. connections are tasks, so idle ones cost no thread,
+/// and engine calls run on the bounded blocking pool of the runtime.
+/// it speaks the json and the binary protocol of `KvServer`
+pub struct AsyncKvServer<E: KvEngine> {
+    addr: SocketAddr,
+    listener: std::net::TcpListener,
+    kvengine: E,
+    databases: Option<Arc<Databases<E>>>,
+    read_only: bool,
+    auth_token: Option<String>,
+    // Backup writes into sub directories of it
+    backup_dir: Option<PathBuf>,
+    // taken by every write to the default engine, see Session
+    write_lock: Arc<Mutex<()>>,
+    // threads running engine calls, at most
+    blocking_threads: usize,
+    limits: Limits,
+    shutdown: Arc<Shutdown>,
+    // how long serve waits for the open connections on shutdown
+    shutdown_timeout: Duration,
+}
+
+impl<E: KvEngine> AsyncKvServer<E> {
+    /// construct a new server, it listens on addr once constructed
+    pub fn new(addr: SocketAddr, engine: E) -> Result<AsyncKvServer<E>> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        // the port the system picked if addr has port 0
+        let addr = listener.local_addr()?;
+        info!("bind to {}", addr);
+        let shutdown = Arc::new(Shutdown::default());
+        shutdown.listen_on(addr);
+        Ok(AsyncKvServer {
+            addr,
+            listener,
+            kvengine: engine,
+            databases: None,
+            read_only: false,
+            auth_token: None,
+            backup_dir: None,
+            write_lock: Arc::new(Mutex::new(())),
+            blocking_threads: 16,
+            limits: Limits::default(),
+            shutdown,
+            shutdown_timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// serve multiple logical databases, like `KvServer::with_databases`
+    pub fn with_databases(mut self, databases: Databases<E>) -> AsyncKvServer<E> {
+        self.databases = Some(Arc::new(databases));
+        self
+    }
+
+    /// serve Get and Scan only, Set and Rm are refused
+    pub fn read_only(mut self, read_only: bool) -> AsyncKvServer<E> {
+        self.read_only = read_only;
+        self
+    }
+
+    /// require clients to send token in their Hello before any other request
+    pub fn auth_token(mut self, token: Option<String>) -> AsyncKvServer<E> {
+        self.auth_token = token;
+        self
+    }
+
+    /// allow Backup requests below dir, like `KvServer::backup_dir`
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> AsyncKvServer<E> {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// run at most this many engine calls at once, 16 by default. only used by `run`,
+    /// `serve` uses the blocking pool of the runtime it is called on
+    pub fn blocking_threads(mut self, threads: usize) -> AsyncKvServer<E> {
+        self.blocking_threads = threads.max(1);
+        self
+    }
+
+    /// serve at most max connections at once, like `KvServer::max_connections`
+    pub fn max_connections(mut self, max: usize) -> AsyncKvServer<E> {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// close a connection whose request does not fully arrive within timeout once it started,
+    /// zero waits forever, the default
+    pub fn read_timeout(mut self, timeout: Duration) -> AsyncKvServer<E> {
+        self.limits.read_timeout = limits::timeout(timeout);
+        self
+    }
+
+    /// close a connection which does not take a response within timeout, zero waits forever, the default
+    pub fn write_timeout(mut self, timeout: Duration) -> AsyncKvServer<E> {
+        self.limits.write_timeout = limits::timeout(timeout);
+        self
+    }
+
+    /// close a connection which sends no request for timeout, zero keeps it open, the default
+    pub fn idle_timeout(mut self, timeout: Duration) -> AsyncKvServer<E> {
+        self.limits.idle_timeout = limits::timeout(timeout);
+        self
+    }
+
+    /// refuse requests larger than bytes with `TooLarge`, 64 MiB by default
+    pub fn max_request_size(mut self, bytes: usize) -> AsyncKvServer<E> {
+        self.limits.max_request_size = bytes;
+        self
+    }
+
+    /// how long the server waits for the open connections on shutdown, like `KvServer::shutdown_timeout`
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> AsyncKvServer<E> {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// a handle which stops the server from another thread, like `KvServer::shutdown_handle`
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        Shutdown::handle(&self.shutdown)
+    }
+
+    /// the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// start a tokio runtime and serve connections on it
+    pub fn run(self) -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .max_blocking_threads(self.blocking_threads)
+            .enable_all()
+            .build()?;
+        runtime.block_on(self.serve())
+    }
+
+    /// serve connections on the current tokio runtime. returns after a shutdown, once the open
+    /// connections are done or the shutdown timeout passed and the engine is flushed
+    pub async fn serve(self) -> Result<()> {
+        let listener = TcpListener::from_std(self.listener)?;
+        let (connections, rejecting) = (Arc::new(Slots::default()), Arc::new(Slots::default()));
+        let limits = self.limits;
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("fail to accept connection: {}", e);
+                    continue;
+                },
+            };
+            if self.shutdown.is_requested() {
+                break;
+            }
+            info!("get connenction");
+            let (stream, connection) = match register(&self.shutdown, stream) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("fail to accept connection: {}", e);
+                    continue;
+                },
+            };
+            let slot = match Slots::take(&connections, limits.max_connections) {
+                Some(slot) => slot,
+                None => {
+                    match Slots::take(&rejecting, Some(MAX_REJECTING)) {
+                        Some(rejecting) => {
+                            warn!("too many connections, refuse one");
+                            tokio::spawn(async move {
+                                if let Err(e) = reject(stream, limits).await {
+                                    error!("fail to refuse connection: {}", e);
+                                }
+                                drop((rejecting, connection));
+                            });
+                        },
+                        None => warn!("too many connections, close one"),
+                    }
+                    continue;
+                },
+            };
+            let session = Session::new(self.kvengine.clone(), self.databases.clone(), self.read_only, self.auth_token.clone(), self.backup_dir.clone(), self.write_lock.clone());
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(session, stream, limits).await {
+                    error!("fail to serve connection: {}", e);
+                }
+                drop((slot, connection));
+            });
+        }
+        drop(listener);
+
+        // the connections end after the requests they already read, like in `KvServer::run`
+        let (shutdown, timeout) = (self.shutdown.clone(), self.shutdown_timeout);
+        let (engine, databases) = (self.kvengine.clone(), self.databases.clone());
+        let stopped = tokio::task::spawn_blocking(move || {
+            shutdown.drain(timeout);
+            engine.flush()?;
+            if let Some(databases) = databases {
+                databases.flush()?;
+            }
+            info!("server stopped");
+            Ok(())
+        });
+        stopped.await.map_err(|e| KvStoreError::Internal(e.to_string()))?
+    }
+}
+
+// track a connection until the returned guard is dropped, the shutdown closes it through a std stream
+fn register(shutdown: &Arc<Shutdown>, stream: TcpStream) -> io::Result<(TcpStream, Connection)> {
+    let stream = stream.into_std()?;
+    let connection = Shutdown::register(shutdown, &stream)?;
+    Ok((TcpStream::from_std(stream)?, connection))
+}
+
+// the first byte tells the protocols apart, like in `KvServer`
+async fn serve_connection<E: KvEngine>(mut session: Session<E>, stream: TcpStream, limits: Limits) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    if !wait_request(&mut reader, false, &limits).await? {
+        return Ok(());
+    }
+    if reader.buffer().first() == Some(&BINARY_MAGIC[0]) {
+        let mut magic = [0; 4];
+        within(limits.read_timeout, reader.read_exact(&mut magic)).await?;
+        if magic != *BINARY_MAGIC {
+            return Err(KvStoreError::Protocol(String::from("unknown handshake")));
+        }
+        session.require_hello();
+        // requests of a connection run one after another, replies keep their order
+        while wait_request(&mut reader, false, &limits).await? {
+            let (opcode, id, request) = match within(limits.read_timeout, read_request_async(&mut reader, limits.max_request_size)).await? {
+                Some(next) => next,
+                None => break,
+            };
+            let received = Instant::now();
+            let reply = match request {
+                Ok(request) => {
+                    let response;
+                    (session, response) = handle(session, request, received).await?;
+                    Frame { opcode, id, payload: bincode::serialize(&response)? }
+                },
+                Err(e) => {
+                    error!("fail to decode frame {}: {}", id, e);
+                    Frame { opcode: OP_ERROR, id, payload: bincode::serialize(&error_response(e))? }
+                },
+            };
+            within(limits.write_timeout, write_frame_async(&mut writer, &reply)).await?;
+            // pipelined requests are answered together
+            if session.is_closing() || reader.buffer().is_empty() {
+                within(limits.write_timeout, writer.flush()).await?;
+            }
+            if session.is_closing() {
+                break;
+            }
+        }
+    } else {
+        let mut buf = JsonBuf::default();
+        while wait_request(&mut reader, !buf.is_empty(), &limits).await? {
+            let request = match within(limits.read_timeout, read_json_async(&mut reader, &mut buf, limits.max_request_size)).await {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                // the rest of the document is not read, so the stream can not go on
+                Err(e @ KvStoreError::TooLarge(_)) => {
+                    let response = serde_json::to_vec(&error_response(e))?;
+                    within(limits.write_timeout, write_all(&mut writer, &response)).await?;
+                    break;
+                },
+                Err(e) => return Err(e),
+            };
+            let response;
+            (session, response) = handle(session, request, Instant::now()).await?;
+            let response = serde_json::to_vec(&response)?;
+            within(limits.write_timeout, write_all(&mut writer, &response)).await?;
+            if session.is_closing() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// write a json response and send it
+async fn write_all(writer: &mut BufWriter<OwnedWriteHalf>, buf: &[u8]) -> io::Result<()> {
+    writer.write_all(buf).await?;
+    writer.flush().await
+}
+
+// wait up to the idle timeout for the next request to start, like in `KvServer`
+async fn wait_request(reader: &mut BufReader<OwnedReadHalf>, pending: bool, limits: &Limits) -> Result<bool> {
+    if pending || !reader.buffer().is_empty() {
+        return Ok(true);
+    }
+    let filled = match limits.idle_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, reader.fill_buf()).await {
+            Ok(filled) => filled?,
+            Err(_) => {
+                info!("close idle connection");
+                return Ok(false);
+            },
+        },
+        None => reader.fill_buf().await?,
+    };
+    Ok(!filled.is_empty())
+}
+
+// run fut, failing with a timed out io error once timeout passed
+async fn within<T, Err: Into<KvStoreError>>(timeout: Option<Duration>, fut: impl Future<Output = std::result::Result<T, Err>>) -> Result<T> {
+    let res = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut).await.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => fut.await,
+    };
+    res.map_err(Into::into)
+}
+
+// answer the first request of a connection over the limit with Busy and close it
+async fn reject(stream: TcpStream, limits: Limits) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let busy = error_response(KvStoreError::Busy(String::from("too many connections, try again later")));
+    within(Some(REJECT_WAIT), async {
+        if reader.fill_buf().await?.first() == Some(&BINARY_MAGIC[0]) {
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic).await?;
+            // the client waits for the reply to its first request
+            if let Some((_, id, _)) = read_request_async(&mut reader, limits.max_request_size).await? {
+                write_frame_async(&mut writer, &Frame { opcode: OP_ERROR, id, payload: bincode::serialize(&busy)? }).await?;
+            }
+        } else {
+            // a request which fails to parse is refused all the same
+            let _ = read_json_async::<serde_json::Value>(&mut reader, &mut JsonBuf::default(), limits.max_request_size).await;
+            writer.write_all(&serde_json::to_vec(&busy)?).await?;
+        }
+        writer.flush().await?;
+        Ok::<_, KvStoreError>(())
+    })
+    .await
+}
+
+// run the request on the blocking pool, the session comes back with the response
+async fn handle<E: KvEngine>(mut session: Session<E>, request: Request, received: Instant) -> Result<(Session<E>, Response)> {
+    tokio::task::spawn_blocking(move || {
+        let response = session.handle_received(request, received);
+        (session, response)
+    })
+    .await
+    .map_err(|e| KvStoreError::Internal(format!("request failed: {}", e)))
+}
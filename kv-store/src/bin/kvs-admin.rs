@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use log::info;
+use structopt::StructOpt;
+
+use kvs::{Result, AnyEngine, EngineType, KvEngine, KvStoreError, KvStoreOptions, DataKey, DumpFilter, DumpKind, DumpRecord, ExportFormat, ValueEncoding, load_engine_meta, DEFAULT_ENGINE, ENGINE_META_PATH};
+
+// the data directory and how to open it
+#[derive(Debug, StructOpt)]
+struct StoreArgs {
+    /// data directory of the store
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+    /// engine of a new directory, must match engine.meta of an existing one
+    #[structopt(long, possible_values = &["kvs", "sled"], case_insensitive = true)]
+    engine: Option<EngineType>,
+    /// file containing the data key of an encrypted kvs store
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    #[structopt(long)]
+    namespace: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Export {
+    #[structopt(flatten)]
+    store: StoreArgs,
+    /// write to this file instead of stdout
+    #[structopt(long, short, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// jsonl or csv
+    #[structopt(long, default_value = "jsonl")]
+    format: ExportFormat,
+    /// plain, base64 or hex
+    #[structopt(long, default_value = "plain")]
+    encoding: ValueEncoding,
+    /// only export keys starting with prefix
+    #[structopt(long, default_value = "")]
+    prefix: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct Import {
+    #[structopt(flatten)]
+    store: StoreArgs,
+    /// read from this file instead of stdin
+    #[structopt(long, short, parse(from_os_str))]
+    input: Option<PathBuf>,
+    /// jsonl or csv
+    #[structopt(long, default_value = "jsonl")]
+    format: ExportFormat,
+    /// plain, base64 or hex
+    #[structopt(long, default_value = "plain")]
+    encoding: ValueEncoding,
+}
+
+#[derive(Debug, StructOpt)]
+struct Migrate {
+    /// data directory of the store
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+    /// the engine to switch to
+    #[structopt(long, possible_values = &["kvs", "sled"], case_insensitive = true)]
+    to: EngineType,
+    /// file containing the data key of an encrypted kvs store
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+}
+
+// a kvs data directory which is checked offline
+#[derive(Debug, StructOpt)]
+struct LogDir {
+    /// data directory of the store
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+    /// file containing the data key of an encrypted kvs store
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Dump {
+    #[structopt(flatten)]
+    log: LogDir,
+    /// only dump this namespace
+    #[structopt(long)]
+    namespace: Option<String>,
+    /// only dump records whose key starts with prefix
+    #[structopt(long, default_value = "")]
+    prefix: String,
+    /// only dump records at or after this offset
+    #[structopt(long)]
+    from: Option<u64>,
+    /// only dump records before this offset
+    #[structopt(long)]
+    to: Option<u64>,
+    /// print live and dead bytes per key prefix instead of the records
+    #[structopt(long)]
+    stats: bool,
+    /// with --stats, the prefix of a key is the part before this separator
+    #[structopt(long, default_value = ":")]
+    separator: String,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// write every live key-value pair into a file
+    #[structopt(name = "export")]
+    Export(Export),
+    /// load key-value pairs from a file
+    #[structopt(name = "import")]
+    Import(Import),
+    /// copy the store into another engine and switch engine.meta to it
+    #[structopt(name = "migrate")]
+    Migrate(Migrate),
+    /// check every record of a kvs store, exit with an error if anything is wrong
+    #[structopt(name = "verify")]
+    Verify(LogDir),
+    /// rewrite a kvs store from its readable records, dropping the corrupt ones
+    #[structopt(name = "repair")]
+    Repair(LogDir),
+    /// print the records of a kvs store
+    #[structopt(name = "dump")]
+    Dump(Dump),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-admin")]
+struct Arguments {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+// open the engine recorded in engine.meta of the directory
+// export only reads: the directory has to exist, engine.meta is not written and a kvs store is
+// opened read-only with the shared lock, so a store served read-only can be exported meanwhile.
+// import creates the directory
+fn open_engine(args: StoreArgs, read_only: bool) -> Result<AnyEngine> {
+    let mut options = store_options(args.key_file)?;
+    let engine_type = if read_only {
+        if !args.dir.is_dir() {
+            return Err(KvStoreError::StringErr(format!("{} is not a data directory", args.dir.display())));
+        }
+        if args.dir.join(ENGINE_META_PATH).exists() {
+            load_engine_meta(&args.dir, args.engine)?
+        } else {
+            args.engine.unwrap_or(DEFAULT_ENGINE)
+        }
+    } else {
+        std::fs::create_dir_all(&args.dir)?;
+        load_engine_meta(&args.dir, args.engine)?
+    };
+    // sled has no read-only mode and takes its own lock
+    if read_only && engine_type == EngineType::Kvs {
+        options = options.read_only(true);
+    }
+    let engine = AnyEngine::open(&args.dir, engine_type, options)?;
+    match args.namespace {
+        Some(name) => engine.namespace(&name),
+        None => Ok(engine),
+    }
+}
+
+fn store_options(key_file: Option<PathBuf>) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new();
+    if let Some(key_file) = key_file {
+        options = options.encryption_key(DataKey::from_file(key_file)?);
+    }
+    Ok(options)
+}
+
+// e.g. `default.1.log@0 len=45 seq=0 live set "key1" "value1"`
+fn format_record(record: &DumpRecord) -> String {
+    let position = format!("{}@{} len={}", record.file, record.offset, record.len);
+    let seq = record.seq.unwrap_or_default();
+    let state = if record.live { "live" } else { "dead" };
+    match record.kind {
+        DumpKind::Set { ref key, ref value } => format!("{} seq={} {} set {:?} {:?}", position, seq, state, key, value),
+        DumpKind::Rm { ref key } => format!("{} seq={} {} rm {:?}", position, seq, state, key),
+        DumpKind::Corrupt(ref reason) => format!("{} corrupt: {}", position, reason),
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(log::LevelFilter::Info).init();
+    let opt = Arguments::from_args();
+
+    match opt.command {
+        Command::Export(Export { store, output, format, encoding, prefix }) => {
+            let engine = open_engine(store, true)?;
+            let mut progress = |count| info!("exported {} pairs", count);
+            match output {
+                Some(path) => {
+                    let writer = BufWriter::new(File::create(path)?);
+                    kvs::export(&engine, &prefix, writer, format, encoding, &mut progress)?;
+                },
+                None => {
+                    let writer = BufWriter::new(io::stdout().lock());
+                    kvs::export(&engine, &prefix, writer, format, encoding, &mut progress)?;
+                },
+            }
+        },
+        Command::Import(Import { store, input, format, encoding }) => {
+            let engine = open_engine(store, false)?;
+            let mut progress = |count| info!("imported {} pairs", count);
+            match input {
+                Some(path) => {
+                    let reader = BufReader::new(File::open(path)?);
+                    kvs::import(&engine, reader, format, encoding, &mut progress)?;
+                },
+                None => {
+                    let reader = io::stdin().lock();
+                    kvs::import(&engine, reader, format, encoding, &mut progress)?;
+                },
+            }
+        },
+        Command::Migrate(Migrate { dir, to, key_file }) => {
+            let report = kvs::migrate_engine(&dir, to, store_options(key_file)?)?;
+            println!("migrated {} from {} to {}", dir.display(), report.from, report.to);
+            for (namespace, count) in report.namespaces {
+                println!("{}\t{} pairs", namespace, count);
+            }
+        },
+        Command::Verify(LogDir { dir, key_file }) => {
+            let report = kvs::verify_store(&dir, &store_options(key_file)?)?;
+            for range in &report.corrupt {
+                println!("corrupt {} bytes {}..{}: {}", range.file, range.start, range.end, range.reason);
+            }
+            for file in &report.orphaned {
+                println!("orphaned {}", file);
+            }
+            for problem in &report.inconsistencies {
+                println!("inconsistent {}", problem);
+            }
+            println!("{} records checked", report.records);
+            if !report.is_clean() {
+                std::process::exit(1);
+            }
+        },
+        Command::Repair(LogDir { dir, key_file }) => {
+            let report = kvs::repair_store(&dir, &store_options(key_file)?)?;
+            for range in &report.dropped {
+                println!("dropped {} bytes {}..{}: {}", range.file, range.start, range.end, range.reason);
+            }
+            for (namespace, count) in report.namespaces {
+                println!("{}\t{} keys kept", namespace, count);
+            }
+            println!("report written to {}", dir.join(kvs::REPAIR_REPORT_PATH).display());
+        },
+        Command::Dump(Dump { log: LogDir { dir, key_file }, namespace, prefix, from, to, stats, separator }) => {
+            let options = store_options(key_file)?;
+            if stats {
+                println!("prefix\tlive keys\tlive bytes\tdead bytes");
+                for stats in kvs::dump_stats(&dir, &options, namespace.as_deref(), &separator)? {
+                    println!("{}\t{}\t{}\t{}", stats.prefix, stats.live_keys, stats.live_bytes, stats.dead_bytes);
+                }
+                return Ok(());
+            }
+            let filter = DumpFilter { namespace, prefix, from, to };
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            let mut res = Ok(());
+            kvs::dump_store(&dir, &options, &filter, &mut |record| {
+                if res.is_ok() {
+                    res = writeln!(out, "{}", format_record(&record));
+                }
+            })?;
+            res?;
+            out.flush()?;
+        },
+    }
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::net::SocketAddr;
+use std::process;
 
-use kvs::{KvClient, Result, KvStoreError};
+use kvs::{KvClient, Result, KvStoreError, Protocol};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -9,6 +10,10 @@ struct Set {
     value: String,
     #[structopt(long="addr", default_value="127.0.0.1:4000")]
     addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -16,6 +21,10 @@ struct Get {
     key: String,
     #[structopt(long="addr", default_value="127.0.0.1:4000")]
     addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -23,6 +32,105 @@ struct Rm {
     key: String,
     #[structopt(long="addr", default_value="127.0.0.1:4000")]
     addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MGet {
+    #[structopt(required = true)]
+    keys: Vec<String>,
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MSet {
+    /// keys and values, one after another
+    #[structopt(required = true, min_values = 2)]
+    pairs: Vec<String>,
+    /// write every pair or none of them, needs an engine with atomic batches
+    #[structopt(long="atomic")]
+    atomic: bool,
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MDel {
+    #[structopt(required = true)]
+    keys: Vec<String>,
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Scan {
+    /// only keys starting with prefix
+    #[structopt(default_value="")]
+    prefix: String,
+    /// print at most this many pairs
+    #[structopt(long="limit")]
+    limit: Option<usize>,
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Query {
+    /// name of the secondary index
+    index: String,
+    value: String,
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Info {
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="namespace")]
+    namespace: Option<String>,
+    #[structopt(long="db")]
+    db: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Dbs {
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+}
+
+#[derive(Debug, StructOpt)]
+struct Backup {
+    /// destination directory, relative to the backup directory of the server
+    dest: String,
+    #[structopt(long="addr", default_value="127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[structopt(long="db")]
+    db: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -33,6 +141,22 @@ enum Command {
     Set(Set),
     #[structopt(name = "rm")]
     Rm(Rm),
+    #[structopt(name = "mget")]
+    MGet(MGet),
+    #[structopt(name = "mset")]
+    MSet(MSet),
+    #[structopt(name = "mdel")]
+    MDel(MDel),
+    #[structopt(name = "scan")]
+    Scan(Scan),
+    #[structopt(name = "query")]
+    Query(Query),
+    #[structopt(name = "info")]
+    Info(Info),
+    #[structopt(name = "dbs")]
+    Dbs(Dbs),
+    #[structopt(name = "backup")]
+    Backup(Backup),
 }
 
 #[derive(Debug, StructOpt)]
@@ -40,20 +164,43 @@ enum Command {
 struct Arguments {
     #[structopt(subcommand)]
     command: Command,
+    /// json or binary
+    #[structopt(long = "protocol", global = true, default_value = "json")]
+    protocol: Protocol,
+    /// auth token of the server
+    #[structopt(long = "token", global = true)]
+    token: Option<String>,
+}
+
+// connect to server, say hello and switch to database db if given
+fn connect(addr: SocketAddr, db: Option<String>, protocol: Protocol, token: &Option<String>) -> Result<KvClient> {
+    let mut client = KvClient::with_protocol(addr, protocol)?;
+    client.hello("kvs-client", &[], token.clone())?;
+    if let Some(db) = db {
+        client.select(db)?;
+    }
+    Ok(client)
 }
 
-fn main() -> Result<()> {
+fn main() {
     env_logger::builder().filter_level(log::LevelFilter::Info).init();
-    let opt = Arguments::from_args();
-    
+    // print the message of the error, its debug form hides the message of unit variants
+    if let Err(e) = run(Arguments::from_args()) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Arguments) -> Result<()> {    
     match opt.command {
-        Command::Get(Get { key, addr }) => {
-            let mut client = KvClient::new(addr)?;
+        Command::Get(Get { key, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
             match client.get(key) {
                 Ok(val) => {
                     println!("{}", val);
                 },
-                Err(KvStoreError::StringErr(err)) => {
+                Err(KvStoreError::StringErr(err)) | Err(KvStoreError::NotFound(err)) => {
                     println!("{}", err);
                 },
                 Err(KvStoreError::GetNonExistValue) => {
@@ -62,17 +209,103 @@ fn main() -> Result<()> {
                 Err(err) => { return Err(err); }
             }
         },
-        Command::Set(Set { key, value, addr }) => {
-            let mut client = KvClient::new(addr)?;
+        Command::Set(Set { key, value, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
             client.set(key, value)?;
         },
-        Command::Rm(Rm { key, addr }) => {
-            let mut client = KvClient::new(addr)?;
+        Command::Rm(Rm { key, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
             client.rm(key)?;
+        },
+        Command::MGet(MGet { keys, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
+            // one line per key, like get
+            for value in client.mget(keys)? {
+                match value {
+                    Ok(value) => println!("{}", value),
+                    Err(KvStoreError::NotFound(err)) => println!("{}", err),
+                    Err(err) => return Err(err),
+                }
+            }
+        },
+        Command::MSet(MSet { pairs, atomic, addr, namespace, db }) => {
+            if !pairs.len().is_multiple_of(2) {
+                return Err(KvStoreError::InvalidArgument(format!("missing the value of key {}", pairs[pairs.len() - 1])));
+            }
+            let keys: Vec<String> = pairs.iter().step_by(2).cloned().collect();
+            let pairs = pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
+            let results = client.mset(pairs, atomic)?;
+            key_errors(&keys, results)?;
+        },
+        Command::MDel(MDel { keys, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
+            let results = client.mdel(keys.clone())?;
+            key_errors(&keys, results)?;
+        },
+        Command::Scan(Scan { prefix, limit, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
+            for (key, value) in client.scan(prefix, limit)? {
+                println!("{}\t{}", key, value);
+            }
+        },
+        Command::Query(Query { index, value, addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
+            for key in client.query_index(index, value)? {
+                println!("{}", key);
+            }
+        },
+        Command::Info(Info { addr, namespace, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.use_namespace(namespace);
+            let stats = client.info()?;
+            println!("keys: {}", stats.keys);
+            println!("live_bytes: {}", stats.live_bytes);
+            println!("dead_bytes: {}", stats.dead_bytes);
+            println!("files: {}", stats.files);
+            println!("compactions: {}", stats.compactions);
+            if let (Some(finished), Some(ms)) = (stats.last_compaction, stats.last_compaction_ms) {
+                println!("last_compaction: {} ({} ms)", finished, ms);
+            }
+            for (op, counter) in [("get", stats.gets), ("set", stats.sets), ("remove", stats.removes)] {
+                println!("{}: {} hits, {} misses", op, counter.hits, counter.misses);
+            }
+        },
+        Command::Dbs(Dbs { addr }) => {
+            let mut client = connect(addr, None, opt.protocol, &opt.token)?;
+            for db in client.db_stats()? {
+                let engine = db.engine.unwrap_or_else(|| String::from("-"));
+                let state = if db.loaded { "loaded" } else { "unloaded" };
+                println!("{}\t{}\t{}\t{} requests", db.name, engine, state, db.requests);
+            }
+        },
+        Command::Backup(Backup { dest, addr, db }) => {
+            let mut client = connect(addr, db, opt.protocol, &opt.token)?;
+            client.backup(dest)?;
         }
     }
 
     Ok(())
+}
 
-    // println!("{:?}", opt);
-}
\ No newline at end of file
+// print the keys which failed, and fail if any did
+fn key_errors(keys: &[String], results: Vec<Result<()>>) -> Result<()> {
+    let mut failed = 0;
+    for (key, res) in keys.iter().zip(results) {
+        if let Err(err) = res {
+            eprintln!("{}: {}", key, err);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(KvStoreError::StringErr(format!("{} of {} keys failed", failed, keys.len())));
+    }
+    Ok(())
+}
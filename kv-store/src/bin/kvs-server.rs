@@ -1,95 +1,273 @@
-use std::{net::SocketAddr, env::current_dir};
-use clap::arg_enum;
+use std::{net::SocketAddr, env::current_dir, path::{Path, PathBuf}, thread, time::Duration};
 use log::info;
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
 use structopt::StructOpt;
-use std::fs;
 
-use kvs::{Result, KvStoreError, KvServer, KvStore, KvEngine, thread_pool::SharedQueueThreadPool, thread_pool::ThreadPool};
-
-const DEFAULT_ENGINE: Engine = Engine::kvs;
-const ENGINE_META_PATH: &str = "engine.meta";
-
-/// engine type, kvs or sled
-arg_enum! {
-    #[derive(Debug, Clone, Copy, PartialEq)]
-    enum Engine {
-        kvs,
-        sled,
-    }
-}
+use kvs::{Result, KvServer, AsyncKvServer, KvStoreError, KvStoreOptions, Compression, CompactionPolicy, SyncPolicy, EvictionPolicy, DataKey, AnyEngine, EngineType, Databases, ShutdownHandle, load_engine_meta, DEFAULT_ENGINE, ENGINE_META_PATH, thread_pool::SharedQueueThreadPool, thread_pool::ThreadPool};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Arguments {
     #[structopt(long, default_value="127.0.0.1:4000")]
     addr: SocketAddr,
+    /// also accept redis clients speaking RESP2 on this address
     #[structopt(long)]
-    engine: Option<Engine>,
-}
-
-/// load_engine_meta is used to read engine type from the persistent file
-/// if the engine type is not specified, use DEFAULT_ENGINE and write it to disk
-fn load_engine_meta(engine_arg: Option<Engine>) -> Result<Engine> {
-    
-    // persist the engine type into a file
-    let mut path = current_dir()?;
-    path.push(ENGINE_META_PATH);
-
-    if path.clone().as_path().exists() {
-        // .parse()实际上是需要实现FromStr trait的，但是由于arg_enum!宏自动实现了FromStr trait，因此这里不需要单独实现
-        match fs::read_to_string(path)?.parse() {
-            Ok(engine) => {
-                // if the args contain --engine, check ENGINE-NAME == engine(meta data)
-                if let Some(engine_selected) = engine_arg {
-                    if engine_selected != engine {
-                        return Err(KvStoreError::WrongEngineError);
-                    }    
-                }
-                // if the args do not contain --eigine, or ENGINE-NAME == engine, return Ok(engine)
-                Ok(engine)
-            },
-            Err(e) => {
-                Err(KvStoreError::StringErr(e))
-            },
-        }
-    } else {
-        // if args do not conatain --engine, use DEFAULT_ENGINE, otherwise ENGINE-NAME
-        let engine = match engine_arg {
-            Some(engine_selected) => { engine_selected },
-            None => {DEFAULT_ENGINE},
-        };
-        // persist engine meta to disk
-        fs::write(path, engine.to_string())?;
-        Ok(engine)
-    }
+    resp_addr: Option<SocketAddr>,
+    /// also serve the REST gateway on this address
+    #[structopt(long)]
+    http_addr: Option<SocketAddr>,
+    #[structopt(long, possible_values = &["kvs", "sled"], case_insensitive = true)]
+    engine: Option<EngineType>,
+    /// file containing the data key used to encrypt the kvs log
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    /// file containing a retired data key, records encrypted with it are rewritten on compaction
+    #[structopt(long, parse(from_os_str))]
+    old_key_file: Vec<PathBuf>,
+    /// directory holding one sub directory per logical database, selected with `Select`
+    #[structopt(long, parse(from_os_str))]
+    databases: Option<PathBuf>,
+    /// create the directory of a database selected for the first time
+    #[structopt(long)]
+    create_databases: bool,
+    /// databases open at once, selecting one more is refused
+    #[structopt(long, default_value = "64")]
+    max_databases: usize,
+    /// open the store read-only and refuse writes, nothing in the data directory is changed. kvs only
+    #[structopt(long)]
+    read_only: bool,
+    /// clients have to send this token in their Hello
+    #[structopt(long)]
+    auth_token: Option<String>,
+    /// allow Backup into sub directories of this directory, it also needs --auth-token
+    #[structopt(long, parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+    /// threads serving connections, one per open connection. the kvs, RESP and HTTP listeners
+    /// share them, so idle keep-alive connections of one hold up the others
+    #[structopt(long, default_value = "4")]
+    threads: u32,
+    /// threads running pipelined reads of binary connections, 0 runs them one after another
+    #[structopt(long, default_value = "4")]
+    request_threads: u32,
+    /// seconds to wait for open connections on SIGINT or SIGTERM before they are cut off
+    #[structopt(long, default_value = "10")]
+    shutdown_timeout: u64,
+    /// connections served at once, more are refused
+    #[structopt(long)]
+    max_connections: Option<usize>,
+    /// seconds a request may take to arrive once it started, 0 waits forever, the default
+    #[structopt(long, default_value = "0")]
+    read_timeout: u64,
+    /// seconds writing a response may block, 0 waits forever, the default
+    #[structopt(long, default_value = "0")]
+    write_timeout: u64,
+    /// seconds a connection may sit without a request before it is closed, 0 keeps it open, the default
+    #[structopt(long, default_value = "0")]
+    idle_timeout: u64,
+    /// bytes of a request at most
+    #[structopt(long)]
+    max_request_size: Option<usize>,
+    /// serve connections as tasks on a tokio runtime instead of one thread each
+    #[structopt(long = "async")]
+    async_server: bool,
+    /// threads running the engine calls of the async server
+    #[structopt(long, default_value = "16")]
+    blocking_threads: usize,
+    /// toml file with the store options, the flags below override it
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// none, lz4 or zstd
+    #[structopt(long)]
+    compression: Option<Compression>,
+    /// manual, or the stale bytes which trigger compaction
+    #[structopt(long)]
+    compaction: Option<CompactionPolicy>,
+    /// never, always, or an interval like 100ms
+    #[structopt(long)]
+    sync: Option<SyncPolicy>,
+    /// start a new log generation once the active one grows past this many bytes
+    #[structopt(long)]
+    max_file_size: Option<u64>,
+    /// bytes of values cached in memory
+    #[structopt(long)]
+    cache_size: Option<usize>,
+    /// limit the bytes of the live records of every namespace
+    #[structopt(long)]
+    max_bytes: Option<u64>,
+    /// limit the number of keys of every namespace
+    #[structopt(long)]
+    max_keys: Option<u64>,
+    /// none, lru, lfu, random or ttl
+    #[structopt(long)]
+    eviction: Option<EvictionPolicy>,
 }
 
 fn main() -> Result<()> {
     env_logger::builder().filter_level(log::LevelFilter::Info).init();
     let opt = Arguments::from_args();
-    let engine_type = load_engine_meta(opt.engine)?;
+    let path = current_dir()?;
+    let options = store_options(&opt)?;
+    let read_only = options.is_read_only();
+    let engine_type = engine_meta(&path, opt.engine, read_only)?;
 
     // print server info
     info!("kvs-server version: {}", env!("CARGO_PKG_VERSION"));
     info!("engine: {}", engine_type);
     info!("ip addr: {}", opt.addr);
+    if let Some(addr) = opt.resp_addr {
+        info!("resp addr: {}", addr);
+    }
+    if let Some(addr) = opt.http_addr {
+        info!("http addr: {}", addr);
+    }
+    if read_only {
+        info!("read-only");
+    }
 
-    let path = current_dir()?;
-    match engine_type {
-        Engine::kvs => {
-            run_server(KvStore::open(path)?, opt.addr)?;
-        },
-        Engine::sled => {
-            run_server(KvStore::open(path)?, opt.addr)?;
-        },
+    let engine = AnyEngine::open(path, engine_type, options.clone())?;
+    let databases = opt.databases.clone().map(|root| {
+        info!("serve databases in {}", root.display());
+        Databases::new(root, move |dir| {
+            // a new database uses the engine of the server, an existing one keeps its own
+            let engine_arg = if dir.join(ENGINE_META_PATH).exists() { None } else { Some(engine_type) };
+            let engine_type = engine_meta(dir, engine_arg, read_only)?;
+            AnyEngine::open(dir, engine_type, options.clone())
+        })
+        .create(opt.create_databases)
+        .max_open(opt.max_databases)
+    });
+    run_server(engine, databases, &opt, read_only)
+}
+
+// the options of the config file, overridden by the flags
+fn store_options(opt: &Arguments) -> Result<KvStoreOptions> {
+    let mut options = match opt.config {
+        Some(ref config) => KvStoreOptions::from_toml_file(config)?,
+        None => KvStoreOptions::new(),
+    };
+    if opt.read_only {
+        options = options.read_only(true);
     }
-    Ok(())
+    if let Some(ref key_file) = opt.key_file {
+        options = options.encryption_key(DataKey::from_file(key_file)?);
+    }
+    for key_file in &opt.old_key_file {
+        options = options.old_key(DataKey::from_file(key_file)?);
+    }
+    if let Some(compression) = opt.compression {
+        options = options.compression(compression);
+    }
+    if let Some(compaction) = opt.compaction {
+        options = options.compaction(compaction);
+    }
+    if let Some(sync) = opt.sync {
+        options = options.sync(sync);
+    }
+    if let Some(bytes) = opt.max_file_size {
+        options = options.max_file_size(bytes);
+    }
+    if let Some(bytes) = opt.cache_size {
+        options = options.cache_size(bytes);
+    }
+    if let Some(bytes) = opt.max_bytes {
+        options = options.max_bytes(bytes);
+    }
+    if let Some(keys) = opt.max_keys {
+        options = options.max_keys(keys);
+    }
+    if let Some(policy) = opt.eviction {
+        options = options.eviction(policy);
+    }
+    Ok(options)
 }
 
-fn run_server<E: KvEngine>(engine: E, addr: SocketAddr) -> Result<()> {
+// like load_engine_meta, but a read-only server never writes engine.meta
+fn engine_meta(dir: &Path, engine_arg: Option<EngineType>, read_only: bool) -> Result<EngineType> {
+    if read_only && !dir.join(ENGINE_META_PATH).exists() {
+        return Ok(engine_arg.unwrap_or(DEFAULT_ENGINE));
+    }
+    load_engine_meta(dir, engine_arg)
+}
+
+fn run_server(engine: AnyEngine, databases: Option<Databases<AnyEngine>>, opt: &Arguments, read_only: bool) -> Result<()> {
+    if opt.async_server {
+        return run_async_server(engine, databases, opt, read_only);
+    }
     info!("run server");
-    let thread_pool = SharedQueueThreadPool::new(4)?;
-    let mut server = KvServer::new(addr, engine, thread_pool)?;
+    if opt.threads == 0 {
+        return Err(KvStoreError::InvalidArgument(String::from("--threads needs at least one thread")));
+    }
+    let thread_pool = SharedQueueThreadPool::new(opt.threads)?;
+    let mut server = KvServer::new(opt.addr, engine, thread_pool)?
+        .read_only(read_only)
+        .auth_token(opt.auth_token.clone())
+        .shutdown_timeout(Duration::from_secs(opt.shutdown_timeout))
+        .read_timeout(Duration::from_secs(opt.read_timeout))
+        .write_timeout(Duration::from_secs(opt.write_timeout))
+        .idle_timeout(Duration::from_secs(opt.idle_timeout));
+    if let Some(max) = opt.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(bytes) = opt.max_request_size {
+        server = server.max_request_size(bytes);
+    }
+    if opt.request_threads > 0 {
+        server = server.request_pool(SharedQueueThreadPool::new(opt.request_threads)?);
+    }
+    if let Some(databases) = databases {
+        server = server.with_databases(databases);
+    }
+    if let Some(ref dir) = opt.backup_dir {
+        server = server.backup_dir(dir);
+    }
+    if let Some(addr) = opt.resp_addr {
+        server = server.resp_addr(addr)?;
+    }
+    if let Some(addr) = opt.http_addr {
+        server = server.http_addr(addr)?;
+    }
+    stop_on_signals(server.shutdown_handle())?;
     server.run()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+// stop accepting, finish the open requests and flush the engine on SIGINT or SIGTERM
+fn stop_on_signals(shutdown: ShutdownHandle) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("received signal {}, shutting down", signal);
+            shutdown.shutdown();
+        }
+    });
+    Ok(())
+}
+
+fn run_async_server(engine: AnyEngine, databases: Option<Databases<AnyEngine>>, opt: &Arguments, read_only: bool) -> Result<()> {
+    if opt.resp_addr.is_some() || opt.http_addr.is_some() {
+        return Err(KvStoreError::InvalidArgument(String::from("--resp-addr and --http-addr need the threaded server")));
+    }
+    info!("run async server");
+    let mut server = AsyncKvServer::new(opt.addr, engine)?
+        .read_only(read_only)
+        .auth_token(opt.auth_token.clone())
+        .blocking_threads(opt.blocking_threads)
+        .shutdown_timeout(Duration::from_secs(opt.shutdown_timeout))
+        .read_timeout(Duration::from_secs(opt.read_timeout))
+        .write_timeout(Duration::from_secs(opt.write_timeout))
+        .idle_timeout(Duration::from_secs(opt.idle_timeout));
+    if let Some(max) = opt.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(bytes) = opt.max_request_size {
+        server = server.max_request_size(bytes);
+    }
+    if let Some(databases) = databases {
+        server = server.with_databases(databases);
+    }
+    if let Some(ref dir) = opt.backup_dir {
+        server = server.backup_dir(dir);
+    }
+    stop_on_signals(server.shutdown_handle())?;
+    server.run()
+}
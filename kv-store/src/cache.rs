@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// a cache of recently read values, bounded by the bytes of its keys and values.
+// every value is stored with the position of its record, a lookup only hits if the index
+// still points at that record, so writes never have to invalidate the cache
+pub(crate) struct ValueCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // last use of every key, the smallest tick is evicted first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+}
+
+struct Entry {
+    // generation and offset of the record
+    position: (u64, u64),
+    value: String,
+    tick: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        ValueCache { capacity, inner: Mutex::new(Inner::default()) }
+    }
+
+    // the cached value of key if it was read from the record at position
+    pub(crate) fn get(&self, key: &str, position: (u64, u64)) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(key).filter(|entry| entry.position == position)?;
+        let old_tick = std::mem::replace(&mut entry.tick, tick);
+        let value = entry.value.clone();
+        let key = inner.lru.remove(&old_tick)?;
+        inner.lru.insert(tick, key);
+        Some(value)
+    }
+
+    pub(crate) fn insert(&self, key: String, position: (u64, u64), value: String) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, key.clone());
+        inner.entries.insert(key, Entry { position, value, tick });
+        inner.size += size;
+        // drop the least recently used values until everything fits
+        while inner.size > self.capacity {
+            let oldest = match inner.lru.keys().next() {
+                Some(&tick) => inner.lru[&tick].clone(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= key.len() + entry.value.len();
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use walkdir::WalkDir;
+
+use crate::{KvStoreError, Result};
+use crate::kvstore::sync_dir;
+use crate::lock::DirLock;
+
+/// the file listing every file of a checkpoint with its checksum
+pub const MANIFEST_PATH: &str = "checkpoint.manifest";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    files: Vec<FileEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileEntry {
+    // path relative to the checkpoint directory
+    name: String,
+    len: u64,
+    crc32: u32,
+}
+
+// a checkpoint is written into a new or empty directory
+pub(crate) fn prepare_dest(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvStoreError::StringErr(format!("checkpoint directory {} is not empty", dest.display())));
+    }
+    Ok(())
+}
+
+// record the length and checksum of every file in dir
+pub(crate) fn write_manifest(dir: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry.map_err(|e| KvStoreError::StringErr(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let name = relative_name(dir, entry.path())?;
+        let (len, crc32) = checksum(entry.path())?;
+        files.push(FileEntry { name, len, crc32 });
+    }
+    fs::write(dir.join(MANIFEST_PATH), serde_json::to_vec_pretty(&Manifest { files })?)?;
+    Ok(())
+}
+
+/// check every file of the checkpoint in dir against its manifest
+pub fn verify_checkpoint(dir: impl AsRef<Path>) -> Result<()> {
+    let dir = dir.as_ref();
+    let manifest: Manifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST_PATH))?)?;
+    for file in &manifest.files {
+        let path = dir.join(&file.name);
+        if !path.is_file() {
+            return Err(KvStoreError::ChecksumMismatch(file.name.clone()));
+        }
+        if checksum(&path)? != (file.len, file.crc32) {
+            return Err(KvStoreError::ChecksumMismatch(file.name.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// replace target_dir with the checkpoint in backup_dir. the checkpoint is verified, copied next to
+/// target_dir, verified again and only then swapped in, so a bad backup never replaces the data.
+/// fails if anyone has the store in target_dir open, which stays locked while it is restored.
+/// a restore which is cut off half way is finished or rolled back by the next open or restore
+pub fn restore_checkpoint(backup_dir: impl AsRef<Path>, target_dir: impl AsRef<Path>) -> Result<()> {
+    let backup_dir = backup_dir.as_ref();
+    let target_dir = target_dir.as_ref();
+    verify_checkpoint(backup_dir)?;
+    // the lock next to target_dir is held while it is renamed away, the one inside keeps the
+    // store closed until the checkpoint replaced it
+    fs::create_dir_all(parent(target_dir))?;
+    let _restore = DirLock::exclusive_file(&sibling(target_dir, "lock"))?;
+    recover(target_dir)?;
+    let _lock = if target_dir.exists() { Some(DirLock::exclusive(target_dir)?) } else { None };
+
+    let staging = sibling(target_dir, "restoring");
+    for entry in WalkDir::new(backup_dir) {
+        let entry = entry.map_err(|e| KvStoreError::StringErr(e.to_string()))?;
+        let dest = staging.join(relative_name(backup_dir, entry.path())?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)?;
+        } else {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    verify_checkpoint(&staging)?;
+    fs::remove_file(staging.join(MANIFEST_PATH))?;
+
+    // swap the directories, then drop the replaced data
+    if target_dir.exists() {
+        let replaced = sibling(target_dir, "replaced");
+        fs::rename(target_dir, &replaced)?;
+        sync_dir(parent(target_dir))?;
+        fs::rename(&staging, target_dir)?;
+        sync_dir(parent(target_dir))?;
+        fs::remove_dir_all(&replaced)?;
+    } else {
+        fs::rename(&staging, target_dir)?;
+        sync_dir(parent(target_dir))?;
+    }
+    Ok(())
+}
+
+// finish or roll back a restore into dir which was cut off, before anything opens dir
+pub(crate) fn recover_restore(dir: &Path) -> Result<()> {
+    if !sibling(dir, "replaced").exists() && !sibling(dir, "restoring").exists() {
+        return Ok(());
+    }
+    // fails while the restore is still running
+    let _restore = DirLock::exclusive_file(&sibling(dir, "lock"))?;
+    recover(dir)
+}
+
+// the caller holds the lock next to dir
+fn recover(dir: &Path) -> Result<()> {
+    let (staging, replaced) = (sibling(dir, "restoring"), sibling(dir, "replaced"));
+    if replaced.exists() {
+        if !dir.exists() {
+            // the swap stopped between its renames. the staged copy was verified before
+            // the first one, so it goes in, without it the old data goes back
+            let from = if staging.exists() { &staging } else { &replaced };
+            fs::rename(from, dir)?;
+            sync_dir(parent(dir))?;
+        }
+        if replaced.exists() {
+            fs::remove_dir_all(&replaced)?;
+        }
+    }
+    // a copy which was not finished
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    Ok(())
+}
+
+fn checksum(path: &Path) -> Result<(u64, u32)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0u8; 8192];
+    let mut len = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+    Ok((len, hasher.finalize()))
+}
+
+fn relative_name(dir: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(dir).map_err(|e| KvStoreError::StringErr(e.to_string()))?;
+    Ok(relative.to_string_lossy().into_owned())
+}
+
+// the directory whose entries the restore renames
+fn parent(dir: &Path) -> &Path {
+    match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// e.g. /data/db -> /data/db.restoring
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
@@ -1,74 +1,342 @@
-use std::{net::{SocketAddr, TcpStream}, io::{Write, Read, BufWriter, BufReader}};
+use std::{net::{SocketAddr, TcpStream}, io::{Write, BufWriter, BufReader}, time::Duration};
 
 use log::info;
 use serde::Deserialize;
 use serde_json::Deserializer;
 
-use crate::{Result, Request, common::Response, KvStoreError};
+use crate::{Result, Request, common::Response, KeyResult, KvStoreError, DbInfo, EngineStats, Protocol, Handshake, PROTOCOL_VERSION};
+use crate::protocol::{self, read_frame, write_frame, Frame, BINARY_MAGIC};
 
 /// used to establish a connection to server and send request
 pub struct KvClient {
-    addr: SocketAddr,
     writer: BufWriter<TcpStream>,
     reader: BufReader<TcpStream>,
+    // the namespace attached to every request
+    namespace: Option<String>,
+    // the deadline attached to every request
+    deadline: Option<Duration>,
+    protocol: Protocol,
+    // id of the next request
+    next_id: u32,
+    // json replies carry no id, they come in request order
+    next_reply: u32,
+    // requests sent without reading their reply yet
+    pending: usize,
 }
 
+// pipeline sends at most this many requests ahead of the replies it read,
+// so that neither side blocks on a full socket buffer
+const PIPELINE_WINDOW: usize = 256;
+
 impl KvClient {
     /// construct a new client
     pub fn new(addr: SocketAddr) -> Result<KvClient> {
-        let mut stream = TcpStream::connect(addr)?;
-        let writer = BufWriter::new(stream.try_clone()?);
+        KvClient::with_protocol(addr, Protocol::Json)
+    }
+
+    /// construct a new client talking the given protocol. over the binary protocol the server
+    /// only takes requests after a `hello`
+    pub fn with_protocol(addr: SocketAddr, protocol: Protocol) -> Result<KvClient> {
+        let stream = TcpStream::connect(addr)?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
         let reader = BufReader::new(stream);
+        if protocol == Protocol::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+        }
         info!("connected");
-        Ok(KvClient { addr, writer, reader })
+        Ok(KvClient { writer, reader, namespace: None, deadline: None, protocol, next_id: 0, next_reply: 0, pending: 0 })
+    }
+
+    /// introduce the client to the server, asking for the given features. the server refuses
+    /// an incompatible protocol version or a wrong auth token and closes the connection
+    pub fn hello(&mut self, client: &str, features: &[&str], token: Option<String>) -> Result<Handshake> {
+        let command = hello_request(client, features, token);
+        expect_handshake(self.call(&command)?)
+    }
+
+    /// send the following requests to namespace `name`, or the default namespace if None
+    pub fn use_namespace(&mut self, name: Option<String>) {
+        self.namespace = name;
+    }
+
+    /// give the following requests a deadline, None for no deadline. a request the server
+    /// does not get to within timeout of reading it fails with `DeadlineExceeded`
+    pub fn set_deadline(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout;
     }
 
     /// send set command to server
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         info!("set {} {}", key, value);
-        let command = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-        // self.stream.write_all(request.as_bytes())?;
-        info!("send request");
-        let response = Response::deserialize(&mut Deserializer::from_reader(&mut self.reader))?;
+        let command = Request::Set { key, value, namespace: self.namespace.clone() };
+        let response = self.call(&command)?;
         info!("receive response");
-        if let Response::Set { result } = response {
-            if !result.eq("Success") {
-                return Err(KvStoreError::StringErr(result));
-            }
-        }
-        Ok(())
+        expect_unit(response)
     }
 
     /// send get command to server and get the result
     pub fn get(&mut self, key: String) -> Result<String> {
-        let command = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-        let response = Response::deserialize(&mut Deserializer::from_reader(&mut self.reader))?;
-        if let Response::Get { value, result } = response {
-            if result.eq("Success") {
-                return Ok(value);
-            } else {
-                return Err(KvStoreError::StringErr(value));
-            }
-        }
-        Err(KvStoreError::StringErr(String::from("Key not found")))
+        let command = Request::Get { key, namespace: self.namespace.clone() };
+        expect_value(self.call(&command)?)
     }
 
     /// send rm command to server
     pub fn rm(&mut self, key: String) -> Result<()> {
-        let command = Request::Rm { key };
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-        let response = Response::deserialize(&mut Deserializer::from_reader(&mut self.reader))?;
-        if let Response::Rm { result } = response {
-            if !result.eq("Success") {
-                return Err(KvStoreError::StringErr(result));
+        let command = Request::Rm { key, namespace: self.namespace.clone() };
+        expect_unit(self.call(&command)?)
+    }
+
+    /// get the values of keys with one request, a missing key gives `NotFound`
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Result<String>>> {
+        let command = Request::MGet { keys, namespace: self.namespace.clone() };
+        Ok(expect_key_results(self.call(&command)?)?.into_iter().map(key_value).collect())
+    }
+
+    /// set the pairs with one request. an atomic mset writes every pair or fails as a whole,
+    /// the server refuses it if its engine has no atomic batches
+    pub fn mset(&mut self, pairs: Vec<(String, String)>, atomic: bool) -> Result<Vec<Result<()>>> {
+        let command = Request::MSet { pairs, atomic, namespace: self.namespace.clone() };
+        Ok(expect_key_results(self.call(&command)?)?.into_iter().map(|res| key_value(res).map(|_| ())).collect())
+    }
+
+    /// remove keys with one request, a missing key gives `NotFound`
+    pub fn mdel(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let command = Request::MDel { keys, namespace: self.namespace.clone() };
+        Ok(expect_key_results(self.call(&command)?)?.into_iter().map(|res| key_value(res).map(|_| ())).collect())
+    }
+
+    /// get the pairs whose key starts with prefix, at most limit of them
+    pub fn scan(&mut self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let command = Request::Scan { prefix, limit, namespace: self.namespace.clone() };
+        expect_pairs(self.call(&command)?)
+    }
+
+    /// get the keys whose JSON value has value at the path of the secondary index
+    pub fn query_index(&mut self, index: String, value: String) -> Result<Vec<String>> {
+        let command = Request::QueryIndex { index, value, namespace: self.namespace.clone() };
+        expect_keys(self.call(&command)?)
+    }
+
+    /// get the statistics of the engine
+    pub fn info(&mut self) -> Result<EngineStats> {
+        let command = Request::Info { namespace: self.namespace.clone() };
+        expect_stats(self.call(&command)?)
+    }
+
+    /// switch the connection to logical database `db`
+    pub fn select(&mut self, db: String) -> Result<()> {
+        let command = Request::Select { db };
+        expect_unit(self.call(&command)?)
+    }
+
+    /// get the statistics of the logical databases served by the server
+    pub fn db_stats(&mut self) -> Result<Vec<DbInfo>> {
+        expect_dbs(self.call(&Request::DbStats)?)
+    }
+
+    /// ask the server to write a checkpoint of the selected database into dest
+    pub fn backup(&mut self, dest: String) -> Result<()> {
+        let command = Request::Backup { dest };
+        expect_unit(self.call(&command)?)
+    }
+
+    /// send requests and read their responses in request order, without waiting for a response
+    /// before sending the next request. a failed request does not stop the others
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Result<Response>>> {
+        if self.pending > 0 {
+            return Err(KvStoreError::Protocol(String::from("pipelined requests are waiting for their responses")));
+        }
+        let first = self.next_id;
+        let mut responses: Vec<Option<Result<Response>>> = requests.iter().map(|_| None).collect();
+        let mut received = 0;
+        for request in requests {
+            if self.pending >= PIPELINE_WINDOW {
+                let (id, response) = self.receive()?;
+                responses[slot(first, id, requests.len())?] = Some(response);
+                received += 1;
             }
+            self.send(request)?;
+        }
+        while received < requests.len() {
+            let (id, response) = self.receive()?;
+            responses[slot(first, id, requests.len())?] = Some(response);
+            received += 1;
+        }
+        responses.into_iter()
+            .map(|response| response.ok_or_else(|| KvStoreError::Protocol(String::from("duplicate reply"))))
+            .collect()
+    }
+
+    /// queue a request without waiting for its response and return its id. requests are
+    /// buffered until `receive` is called. the server answers binary requests in any order,
+    /// json requests in order
+    pub fn send(&mut self, request: &Request) -> Result<u32> {
+        let id = self.next_id;
+        let with_deadline;
+        let request = match self.deadline {
+            Some(timeout) => {
+                with_deadline = with_deadline_request(request, timeout);
+                &with_deadline
+            },
+            None => request,
+        };
+        match self.protocol {
+            Protocol::Json => serde_json::to_writer(&mut self.writer, request)?,
+            Protocol::Binary => {
+                let frame = Frame { opcode: protocol::opcode(request), id, payload: bincode::serialize(request)? };
+                write_frame(&mut self.writer, &frame)?;
+            },
+        }
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending += 1;
+        Ok(id)
+    }
+
+    /// send the queued requests and wait for the next response, returns the id of its request.
+    /// an error response becomes the matching error
+    pub fn receive(&mut self) -> Result<(u32, Result<Response>)> {
+        if self.pending == 0 {
+            return Err(KvStoreError::Protocol(String::from("no request is waiting for a response")));
         }
+        self.writer.flush()?;
+        let (id, response) = match self.protocol {
+            Protocol::Json => {
+                let id = self.next_reply;
+                self.next_reply = self.next_reply.wrapping_add(1);
+                (id, Response::deserialize(&mut Deserializer::from_reader(&mut self.reader))?)
+            },
+            Protocol::Binary => {
+                let reply = read_frame(&mut self.reader)?
+                    .ok_or_else(|| KvStoreError::Protocol(String::from("connection closed")))?;
+                // a frame the server could not decode is answered with an error response
+                (reply.id, bincode::deserialize(&reply.payload)?)
+            },
+        };
+        self.pending -= 1;
+        let response = match response {
+            Response::Error { code, message } => Err(KvStoreError::from_code(code, message)),
+            response => Ok(response),
+        };
+        Ok((id, response))
+    }
+
+    // send a request and wait for its response
+    fn call(&mut self, request: &Request) -> Result<Response> {
+        if self.pending > 0 {
+            return Err(KvStoreError::Protocol(String::from("pipelined requests are waiting for their responses")));
+        }
+        let id = self.send(request)?;
+        let (reply, response) = self.receive()?;
+        if reply != id {
+            return Err(KvStoreError::Protocol(format!("expect reply to {}, got {}", id, reply)));
+        }
+        response
+    }
+
+}
+
+// what the operations make of their responses, shared with AsyncKvClient.
+// error responses are already turned into errors when these see them
+
+pub(crate) fn hello_request(client: &str, features: &[&str], token: Option<String>) -> Request {
+    Request::Hello {
+        version: PROTOCOL_VERSION,
+        client: client.to_owned(),
+        features: features.iter().map(|feature| feature.to_string()).collect(),
+        token,
+    }
+}
+
+pub(crate) fn with_deadline_request(request: &Request, timeout: Duration) -> Request {
+    Request::Deadline { timeout_ms: timeout.as_millis() as u64, request: Box::new(request.clone()) }
+}
+
+fn unexpected() -> KvStoreError {
+    KvStoreError::StringErr(String::from("unexpected response"))
+}
+
+// "Success", or the message of a failure
+fn success(result: String) -> Result<()> {
+    if result.eq("Success") {
         Ok(())
+    } else {
+        Err(KvStoreError::StringErr(result))
+    }
+}
+
+pub(crate) fn expect_unit(response: Response) -> Result<()> {
+    match response {
+        Response::Set { result } | Response::Rm { result } | Response::Select { result } | Response::Backup { result } => success(result),
+        _ => Err(unexpected()),
+    }
+}
+
+pub(crate) fn expect_value(response: Response) -> Result<String> {
+    match response {
+        Response::Get { value, result } if result.eq("Success") => Ok(value),
+        Response::Get { value, .. } => Err(KvStoreError::StringErr(value)),
+        _ => Err(unexpected()),
     }
 }
 
+pub(crate) fn expect_key_results(response: Response) -> Result<Vec<KeyResult>> {
+    match response {
+        Response::MGet { results, result } | Response::MSet { results, result } | Response::MDel { results, result } => {
+            success(result).map(|()| results)
+        },
+        _ => Err(unexpected()),
+    }
+}
+
+pub(crate) fn expect_pairs(response: Response) -> Result<Vec<(String, String)>> {
+    match response {
+        Response::Scan { pairs, result } => success(result).map(|()| pairs),
+        _ => Err(unexpected()),
+    }
+}
+
+pub(crate) fn expect_keys(response: Response) -> Result<Vec<String>> {
+    match response {
+        Response::QueryIndex { keys, result } => success(result).map(|()| keys),
+        _ => Err(unexpected()),
+    }
+}
+
+pub(crate) fn expect_stats(response: Response) -> Result<EngineStats> {
+    match response {
+        Response::Info { stats, result } => success(result).map(|()| stats),
+        _ => Err(unexpected()),
+    }
+}
+
+pub(crate) fn expect_dbs(response: Response) -> Result<Vec<DbInfo>> {
+    match response {
+        Response::DbStats { dbs, result } => success(result).map(|()| dbs),
+        _ => Err(unexpected()),
+    }
+}
+
+pub(crate) fn expect_handshake(response: Response) -> Result<Handshake> {
+    match response {
+        Response::Hello { version, engine, features, result } => success(result).map(|()| Handshake { version, engine, features }),
+        _ => Err(unexpected()),
+    }
+}
+
+// the value of a key of a multi-key response, empty for a written or removed key
+pub(crate) fn key_value(result: KeyResult) -> Result<String> {
+    match result {
+        KeyResult::Value(value) => Ok(value),
+        KeyResult::Done => Ok(String::new()),
+        KeyResult::Error { code, message } => Err(KvStoreError::from_code(code, message)),
+    }
+}
+
+// the position of the reply to id among count requests sent from id first
+fn slot(first: u32, id: u32, count: usize) -> Result<usize> {
+    let slot = id.wrapping_sub(first) as usize;
+    if slot >= count {
+        return Err(KvStoreError::Protocol(format!("unexpected reply to {}", id)));
+    }
+    Ok(slot)
+}
@@ -1,31 +1,265 @@
 use serde::{Serialize, Deserialize};
 
+use crate::{DbInfo, EngineStats};
+
 /// the request send to server
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     /// set request
     Set {
         /// key
         key: String, 
         /// value
-        value: String
+        value: String,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
     },
     /// get request
     Get {
         /// key
-        key: String
+        key: String,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
     },
     /// remove request
     Rm {
         /// key
-        key: String
+        key: String,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// switch the connection to another logical database
+    Select {
+        /// database name
+        db: String,
+    },
+    /// the pairs whose key starts with prefix, in key order
+    Scan {
+        /// key prefix, empty for every pair
+        prefix: String,
+        /// at most this many pairs
+        #[serde(default)]
+        limit: Option<usize>,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// the keys whose JSON value has value at the path of a secondary index
+    QueryIndex {
+        /// index name
+        index: String,
+        /// the value to look up
+        value: String,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// statistics of the engine
+    Info {
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// statistics of the logical databases
+    DbStats,
+    /// write a checkpoint of the selected database into a directory on the server
+    Backup {
+        /// destination directory below the backup directory of the server, must be new or empty
+        dest: String,
+    },
+    /// the first request of a connection, checks that client and server understand each other
+    Hello {
+        /// protocol version of the client
+        version: u32,
+        /// name of the client, only logged
+        client: String,
+        /// optional features the client would like to use
+        #[serde(default)]
+        features: Vec<String>,
+        /// required if the server is started with an auth token
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// get the values of several keys
+    MGet {
+        /// keys
+        keys: Vec<String>,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// set several key-value pairs
+    MSet {
+        /// key-value pairs, written in order
+        pairs: Vec<(String, String)>,
+        /// write every pair or none of them, refused if the engine has no atomic batches
+        #[serde(default)]
+        atomic: bool,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// remove several keys
+    MDel {
+        /// keys
+        keys: Vec<String>,
+        /// namespace, the default namespace if not given
+        #[serde(default)]
+        namespace: Option<String>,
+    },
+    /// run request only if the server gets to it within timeout_ms of reading it,
+    /// otherwise it fails with `DeadlineExceeded`
+    Deadline {
+        /// milliseconds the request may wait on the server
+        timeout_ms: u64,
+        /// the request to run
+        request: Box<Request>,
     },
 }
 
-/// the response from server
+/// the outcome of one key of a multi-key request, a failed key does not stop the others
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum KeyResult {
+    /// the value of the key
+    Value(String),
+    /// the key is written or removed
+    Done,
+    /// the key failed
+    Error {
+        /// what went wrong
+        code: ErrorCode,
+        /// a message for humans
+        message: String,
+    },
+}
+
+/// what went wrong with a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the key or the index does not exist
+    NotFound,
+    /// the value has another type than the request expects
+    WrongType,
+    /// the request conflicts with the state of the server, e.g. a backup into a non-empty directory
+    Conflict,
+    /// the server refuses writes
+    ReadOnly,
+    /// the namespace is full and does not evict
+    StoreFull,
+    /// the auth token is missing or wrong
+    Unauthorized,
+    /// a name or an argument of the request is invalid
+    InvalidArgument,
+    /// the request or its response exceeds a size limit
+    TooLarge,
+    /// the protocol version of the client is not supported
+    Unsupported,
+    /// the server failed, e.g. on an io error
+    Internal,
+    /// the server has too many connections, try again later
+    Busy,
+    /// the deadline of the request passed before the server ran it
+    DeadlineExceeded,
+}
+
+/// the response from server, a failed request gets an `Error`
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
-    Set {result: String},
-    Get {value: String, result: String},
-    Rm {result: String},
-}
\ No newline at end of file
+    /// the pair is written
+    Set {
+        /// "Success"
+        result: String,
+    },
+    /// the value of the key
+    Get {
+        /// value
+        value: String,
+        /// "Success"
+        result: String,
+    },
+    /// the key is removed
+    Rm {
+        /// "Success"
+        result: String,
+    },
+    /// the pairs in key order
+    Scan {
+        /// key-value pairs
+        pairs: Vec<(String, String)>,
+        /// "Success"
+        result: String,
+    },
+    /// the keys found in the secondary index, in key order
+    QueryIndex {
+        /// keys
+        keys: Vec<String>,
+        /// "Success"
+        result: String,
+    },
+    /// statistics of the engine
+    Info {
+        /// statistics
+        stats: EngineStats,
+        /// "Success"
+        result: String,
+    },
+    /// the connection switched the database
+    Select {
+        /// "Success"
+        result: String,
+    },
+    /// statistics of the logical databases
+    DbStats {
+        /// one entry per database
+        dbs: Vec<DbInfo>,
+        /// "Success"
+        result: String,
+    },
+    /// the checkpoint is written
+    Backup {
+        /// "Success"
+        result: String,
+    },
+    /// the server accepted the client
+    Hello {
+        /// protocol version of the server
+        version: u32,
+        /// engine type of the server
+        engine: String,
+        /// the requested features the server turned on
+        features: Vec<String>,
+        /// "Success"
+        result: String,
+    },
+    /// the request failed
+    Error {
+        /// what went wrong
+        code: ErrorCode,
+        /// a message for humans
+        message: String,
+    },
+    /// one result per key of an MGet
+    MGet {
+        /// in the order of the keys
+        results: Vec<KeyResult>,
+        /// "Success"
+        result: String,
+    },
+    /// one result per pair of an MSet
+    MSet {
+        /// in the order of the pairs
+        results: Vec<KeyResult>,
+        /// "Success"
+        result: String,
+    },
+    /// one result per key of an MDel
+    MDel {
+        /// in the order of the keys
+        results: Vec<KeyResult>,
+        /// "Success"
+        result: String,
+    },
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::{KvStoreError, Result};
+
+const KEY_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = 24;
+
+/// a 256-bit key used to encrypt the records in the log
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey([u8; KEY_LEN]);
+
+impl DataKey {
+    /// create a key from raw bytes
+    pub fn new(bytes: [u8; KEY_LEN]) -> DataKey {
+        DataKey(bytes)
+    }
+
+    /// load a key from a file, which contains either 32 raw bytes or 64 hex characters
+    pub fn from_file(path: impl AsRef<Path>) -> Result<DataKey> {
+        let content = fs::read(path)?;
+        if content.len() == KEY_LEN {
+            let mut bytes = [0u8; KEY_LEN];
+            bytes.copy_from_slice(&content);
+            return Ok(DataKey(bytes));
+        }
+        let text = String::from_utf8(content).map_err(|_| KvStoreError::InvalidEncryptionKey)?;
+        DataKey::from_hex(text.trim())
+    }
+
+    /// parse a key from 64 hex characters
+    pub fn from_hex(text: &str) -> Result<DataKey> {
+        if text.len() != KEY_LEN * 2 || !text.is_ascii() {
+            return Err(KvStoreError::InvalidEncryptionKey);
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)
+                .map_err(|_| KvStoreError::InvalidEncryptionKey)?;
+        }
+        Ok(DataKey(bytes))
+    }
+
+    /// encrypt data with a fresh random nonce, return the nonce followed by the ciphertext
+    pub(crate) fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new((&self.0).into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, data).map_err(|_| KvStoreError::WrongEncryptionKey)?;
+        let mut buf = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        Ok(buf)
+    }
+
+    /// decrypt a payload produced by seal, return None if it was not sealed with this key
+    pub(crate) fn open(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < NONCE_LEN {
+            return None;
+        }
+        let cipher = XChaCha20Poly1305::new((&self.0).into());
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+// never print the key material
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DataKey(..)")
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{KvEngine, KvStoreError, Result, ENGINE_META_PATH};
+use crate::kvstore::valid_name;
+
+type Loader<E> = Box<dyn Fn(&Path) -> Result<E> + Send + Sync>;
+
+// databases open at once unless set with max_open
+const DEFAULT_MAX_OPEN: usize = 64;
+
+/// the logical databases served by one server. every database lives in a sub directory of root,
+/// has its own engine.meta and is opened by the loader the first time a connection selects it
+pub struct Databases<E: KvEngine> {
+    root: PathBuf,
+    loader: Loader<E>,
+    opened: Mutex<HashMap<String, Database<E>>>,
+    // whether selecting a missing database creates its directory
+    create: bool,
+    max_open: usize,
+}
+
+struct Database<E> {
+    engine: E,
+    // number of requests served by this database
+    requests: Arc<AtomicU64>,
+    // held by the writes of every connection which selected this database
+    write_lock: Arc<Mutex<()>>,
+}
+
+/// statistics of one logical database
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DbInfo {
+    /// database name
+    pub name: String,
+    /// engine type recorded in the engine.meta of the database
+    pub engine: Option<String>,
+    /// whether the database has been opened
+    pub loaded: bool,
+    /// number of requests served since the server started
+    pub requests: u64,
+}
+
+impl<E: KvEngine> Databases<E> {
+    /// serve the sub directories of root, loader opens the engine in a database directory
+    pub fn new<F>(root: impl Into<PathBuf>, loader: F) -> Databases<E>
+        where
+            F: Fn(&Path) -> Result<E> + Send + Sync + 'static {
+        Databases {
+            root: root.into(),
+            loader: Box::new(loader),
+            opened: Mutex::new(HashMap::new()),
+            create: false,
+            max_open: DEFAULT_MAX_OPEN,
+        }
+    }
+
+    /// create the directory of a database selected for the first time, off by default
+    pub fn create(mut self, create: bool) -> Databases<E> {
+        self.create = create;
+        self
+    }
+
+    /// databases open at once, selecting one more fails with Busy. 64 by default
+    pub fn max_open(mut self, max_open: usize) -> Databases<E> {
+        self.max_open = max_open;
+        self
+    }
+
+    /// get the engine of database `name`, its request counter and its write lock, open it if needed
+    pub(crate) fn select(&self, name: &str) -> Result<(E, Arc<AtomicU64>, Arc<Mutex<()>>)> {
+        if !valid_name(name) {
+            return Err(KvStoreError::InvalidDatabase(name.to_owned()));
+        }
+        let mut opened = self.opened.lock().unwrap();
+        if let Some(db) = opened.get(name) {
+            return Ok((db.engine.clone(), db.requests.clone(), db.write_lock.clone()));
+        }
+        let dir = self.root.join(name);
+        if !self.create && !dir.is_dir() {
+            return Err(KvStoreError::NotFound(format!("database not found: {}", name)));
+        }
+        if opened.len() >= self.max_open {
+            return Err(KvStoreError::Busy(format!("too many open databases, {} at most", self.max_open)));
+        }
+        if self.create {
+            fs::create_dir_all(&dir)?;
+        }
+        let engine = (self.loader)(&dir)?;
+        let requests = Arc::new(AtomicU64::new(0));
+        let write_lock = Arc::new(Mutex::new(()));
+        opened.insert(name.to_owned(), Database { engine: engine.clone(), requests: requests.clone(), write_lock: write_lock.clone() });
+        Ok((engine, requests, write_lock))
+    }
+
+    // flush the engines of the opened databases
+    pub(crate) fn flush(&self) -> Result<()> {
+        for db in self.opened.lock().unwrap().values() {
+            db.engine.flush()?;
+        }
+        Ok(())
+    }
+
+    /// statistics of every database under root, sorted by name
+    pub fn stats(&self) -> Result<Vec<DbInfo>> {
+        let opened = self.opened.lock().unwrap();
+        let mut infos = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_dir() || !valid_name(&name) {
+                continue;
+            }
+            let engine = fs::read_to_string(entry.path().join(ENGINE_META_PATH)).ok();
+            let (loaded, requests) = match opened.get(&name) {
+                Some(db) => (true, db.requests.load(Ordering::SeqCst)),
+                None => (false, 0),
+            };
+            infos.push(DbInfo { name, engine, loaded, requests });
+        }
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(infos)
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::{KvStoreOptions, Result};
+use crate::kvstore::Command;
+use crate::lock::DirLock;
+use crate::verify::{check_engine, log_files, walk, Entry};
+
+/// which records dump prints
+#[derive(Debug, Clone, Default)]
+pub struct DumpFilter {
+    /// only this namespace instead of all of them
+    pub namespace: Option<String>,
+    /// only records whose key starts with prefix
+    pub prefix: String,
+    /// only records at or after this offset of their log file
+    pub from: Option<u64>,
+    /// only records before this offset of their log file
+    pub to: Option<u64>,
+}
+
+/// what a dumped record holds
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpKind {
+    /// a set command
+    Set {
+        /// the key
+        key: String,
+        /// the value
+        value: String,
+    },
+    /// a remove command
+    Rm {
+        /// the key
+        key: String,
+    },
+    /// bytes which could not be read, with the reason
+    Corrupt(String),
+}
+
+/// a record of the log as dump sees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpRecord {
+    /// the namespace of the log
+    pub namespace: String,
+    /// the log file
+    pub file: String,
+    /// offset of the record in the file
+    pub offset: u64,
+    /// length of the record including its header
+    pub len: u64,
+    /// position of the record among the records of its namespace, corrupt ranges have none
+    pub seq: Option<u64>,
+    /// the command
+    pub kind: DumpKind,
+    /// true if the index of an opened store would point at this record
+    pub live: bool,
+}
+
+/// live and dead bytes of the keys sharing a prefix
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixStats {
+    /// the part of the key before the separator
+    pub prefix: String,
+    /// number of live keys
+    pub live_keys: u64,
+    /// bytes of the records the index points at
+    pub live_bytes: u64,
+    /// bytes of overwritten values and tombstones, which compaction would reclaim
+    pub dead_bytes: u64,
+}
+
+/// call f with every record of the kvs store in dir which passes filter, in log order.
+/// the store is read directly, which fails while a writer has it open
+pub fn dump_store(dir: impl AsRef<Path>, options: &KvStoreOptions, filter: &DumpFilter, f: &mut dyn FnMut(DumpRecord)) -> Result<()> {
+    let dir = dir.as_ref();
+    check_engine(dir)?;
+    let _lock = DirLock::shared(dir)?;
+    for (name, files) in log_files(dir)?.0 {
+        if filter.namespace.as_ref().is_some_and(|namespace| *namespace != name) {
+            continue;
+        }
+        // the first pass finds the live records, the second one prints them
+        let live = live_records(dir, &files, options)?;
+        let mut seq = 0;
+        for (i, file) in files.iter().enumerate() {
+            walk(&dir.join(file), options, &mut |entry| {
+                let (offset, len, record_seq, kind) = match entry {
+                    Entry::Record { pos, len, cmd } => {
+                        seq += 1;
+                        let kind = match cmd {
+                            Command::Set { key, value, .. } => DumpKind::Set { key, value },
+                            Command::Rm { key } => DumpKind::Rm { key },
+                        };
+                        (pos, len, Some(seq - 1), kind)
+                    },
+                    Entry::Corrupt { start, end, error } => (start, end - start, None, DumpKind::Corrupt(error.to_string())),
+                };
+                let in_range = filter.from.is_none_or(|from| offset >= from) && filter.to.is_none_or(|to| offset < to);
+                let matches = match kind {
+                    DumpKind::Set { ref key, .. } | DumpKind::Rm { ref key } => key.starts_with(&filter.prefix),
+                    DumpKind::Corrupt(_) => filter.prefix.is_empty(),
+                };
+                if in_range && matches {
+                    let live = match kind {
+                        DumpKind::Set { ref key, .. } => live.get(key) == Some(&(i, offset)),
+                        _ => false,
+                    };
+                    f(DumpRecord { namespace: name.clone(), file: file.clone(), offset, len, seq: record_seq, kind, live });
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// sum up live and dead bytes of the kvs store in dir per key prefix, a key's prefix is the part
+/// before the first separator. corrupt ranges are not counted
+pub fn dump_stats(dir: impl AsRef<Path>, options: &KvStoreOptions, namespace: Option<&str>, separator: &str) -> Result<Vec<PrefixStats>> {
+    let dir = dir.as_ref();
+    let filter = DumpFilter { namespace: namespace.map(str::to_owned), ..DumpFilter::default() };
+    let mut stats: BTreeMap<String, PrefixStats> = BTreeMap::new();
+    dump_store(dir, options, &filter, &mut |record| {
+        let key = match record.kind {
+            DumpKind::Set { ref key, .. } | DumpKind::Rm { ref key } => key,
+            DumpKind::Corrupt(_) => return,
+        };
+        let prefix = match key.split_once(separator) {
+            Some((prefix, _)) if !separator.is_empty() => prefix,
+            _ => key.as_str(),
+        };
+        let entry = stats.entry(prefix.to_owned())
+            .or_insert_with(|| PrefixStats { prefix: prefix.to_owned(), ..PrefixStats::default() });
+        if record.live {
+            entry.live_keys += 1;
+            entry.live_bytes += record.len;
+        } else {
+            entry.dead_bytes += record.len;
+        }
+    })?;
+    Ok(stats.into_values().collect())
+}
+
+// the file index and offset of the latest set of every live key
+fn live_records(dir: &Path, files: &[String], options: &KvStoreOptions) -> Result<HashMap<String, (usize, u64)>> {
+    let mut live = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        walk(&dir.join(file), options, &mut |entry| match entry {
+            Entry::Record { pos, cmd: Command::Set { key, .. }, .. } => {
+                live.insert(key, (i, pos));
+            },
+            Entry::Record { cmd: Command::Rm { key }, .. } => {
+                live.remove(&key);
+            },
+            Entry::Corrupt { .. } => {},
+        })?;
+    }
+    Ok(live)
+}
@@ -1,6 +1,10 @@
+// the impls generated by #[derive(Fail)] live inside an anonymous const
+#![allow(non_local_definitions)]
 use failure::Fail;
 use std::{io, string::FromUtf8Error};
 
+use crate::ErrorCode;
+
 /// Error type for KvStore
 #[derive(Fail, Debug)]
 pub enum KvStoreError {
@@ -19,15 +23,88 @@ pub enum KvStoreError {
     /// wrong engine, try to use different engine than selected originally
     #[fail(display = "wrong engine")]
     WrongEngineError,
+    /// a record in the log is truncated or its checksum does not match
+    #[fail(display = "corrupt record in the log file")]
+    CorruptRecord,
+    /// the log contains encrypted records but no key is given
+    #[fail(display = "the store is encrypted, an encryption key is required")]
+    MissingEncryptionKey,
+    /// none of the given keys can decrypt a record
+    #[fail(display = "wrong encryption key")]
+    WrongEncryptionKey,
+    /// the key file does not contain a valid key
+    #[fail(display = "invalid encryption key, expect 32 bytes or 64 hex characters")]
+    InvalidEncryptionKey,
+    /// the namespace name is empty, too long or contains invalid characters
+    #[fail(display = "invalid namespace: {}", _0)]
+    InvalidNamespace(String),
+    /// another process has the store directory open, holds the pid of that process,
+    /// 0 if the store is only opened read-only
+    #[fail(display = "store directory is locked by process {}", _0)]
+    DirectoryLocked(u32),
+    /// the store is opened read-only
+    #[fail(display = "the store is opened read-only")]
+    ReadOnly,
+    /// the namespace reached its size limit and eviction is off, or the record alone exceeds it
+    #[fail(display = "the store is full")]
+    StoreFull,
+    /// a secondary index path is not `$` followed by `.field` and `[n]` steps
+    #[fail(display = "invalid json path: {}", _0)]
+    InvalidJsonPath(String),
+    /// no secondary index with this name is declared
+    #[fail(display = "unknown index: {}", _0)]
+    UnknownIndex(String),
+    /// the database name is invalid or the server does not serve multiple databases
+    #[fail(display = "invalid database: {}", _0)]
+    InvalidDatabase(String),
+    /// a file of a checkpoint is missing or does not match its checksum
+    #[fail(display = "checksum mismatch: {}", _0)]
+    ChecksumMismatch(String),
     /// io error
     #[fail(display = "io error: {}", _0)]
     Io(#[cause] io::Error),
     /// serde error
     #[fail(display = "serde error: {}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// bincode error
+    #[fail(display = "bincode error: {}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// the peer broke the wire protocol
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
     /// string error
     #[fail(display = "{}", _0)]
     StringErr(String),
+    /// the server did not find the key or the index
+    #[fail(display = "{}", _0)]
+    NotFound(String),
+    /// the server found a value of another type than the request expects
+    #[fail(display = "{}", _0)]
+    WrongType(String),
+    /// the request conflicts with the state of the server
+    #[fail(display = "{}", _0)]
+    Conflict(String),
+    /// the server requires an auth token, or the given one is wrong
+    #[fail(display = "{}", _0)]
+    Unauthorized(String),
+    /// the request carries an invalid name or argument
+    #[fail(display = "{}", _0)]
+    InvalidArgument(String),
+    /// the request or its response is too large
+    #[fail(display = "{}", _0)]
+    TooLarge(String),
+    /// the server does not speak the protocol version of the client
+    #[fail(display = "{}", _0)]
+    Unsupported(String),
+    /// the server failed to handle the request
+    #[fail(display = "{}", _0)]
+    Internal(String),
+    /// the server has too many connections
+    #[fail(display = "{}", _0)]
+    Busy(String),
+    /// the deadline of the request passed before the server ran it
+    #[fail(display = "{}", _0)]
+    DeadlineExceeded(String),
     /// sled error
     #[fail(display = "{}", _0)]
     Sled(#[cause] sled::Error),
@@ -36,6 +113,66 @@ pub enum KvStoreError {
     Utf8Error(#[cause] FromUtf8Error),
 }
 
+impl KvStoreError {
+    /// the code the server sends for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvStoreError::GetNonExistValue
+            | KvStoreError::RemoveNonExistKey
+            | KvStoreError::UnknownIndex(_)
+            | KvStoreError::NotFound(_) => ErrorCode::NotFound,
+            KvStoreError::WrongType(_) => ErrorCode::WrongType,
+            KvStoreError::WrongEngineError
+            | KvStoreError::DirectoryLocked(_)
+            | KvStoreError::Conflict(_) => ErrorCode::Conflict,
+            KvStoreError::ReadOnly => ErrorCode::ReadOnly,
+            KvStoreError::StoreFull => ErrorCode::StoreFull,
+            KvStoreError::Unauthorized(_) => ErrorCode::Unauthorized,
+            KvStoreError::InvalidEncryptionKey
+            | KvStoreError::InvalidNamespace(_)
+            | KvStoreError::InvalidJsonPath(_)
+            | KvStoreError::InvalidDatabase(_)
+            | KvStoreError::Protocol(_)
+            | KvStoreError::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            KvStoreError::TooLarge(_) => ErrorCode::TooLarge,
+            KvStoreError::Unsupported(_) => ErrorCode::Unsupported,
+            KvStoreError::Busy(_) => ErrorCode::Busy,
+            KvStoreError::DeadlineExceeded(_) => ErrorCode::DeadlineExceeded,
+            KvStoreError::SerializeCmdError
+            | KvStoreError::RebuildIndexError
+            | KvStoreError::CorruptRecord
+            | KvStoreError::Utf8Error(_)
+            | KvStoreError::MissingEncryptionKey
+            | KvStoreError::WrongEncryptionKey
+            | KvStoreError::ChecksumMismatch(_)
+            | KvStoreError::Io(_)
+            | KvStoreError::Serde(_)
+            | KvStoreError::Bincode(_)
+            | KvStoreError::StringErr(_)
+            | KvStoreError::Sled(_)
+            | KvStoreError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+
+    // the error a client returns for an error response
+    pub(crate) fn from_code(code: ErrorCode, message: String) -> KvStoreError {
+        match code {
+            ErrorCode::NotFound => KvStoreError::NotFound(message),
+            ErrorCode::WrongType => KvStoreError::WrongType(message),
+            ErrorCode::Conflict => KvStoreError::Conflict(message),
+            ErrorCode::ReadOnly => KvStoreError::ReadOnly,
+            ErrorCode::StoreFull => KvStoreError::StoreFull,
+            ErrorCode::Unauthorized => KvStoreError::Unauthorized(message),
+            ErrorCode::InvalidArgument => KvStoreError::InvalidArgument(message),
+            ErrorCode::TooLarge => KvStoreError::TooLarge(message),
+            ErrorCode::Unsupported => KvStoreError::Unsupported(message),
+            ErrorCode::Internal => KvStoreError::Internal(message),
+            ErrorCode::Busy => KvStoreError::Busy(message),
+            ErrorCode::DeadlineExceeded => KvStoreError::DeadlineExceeded(message),
+        }
+    }
+}
+
 impl From<io::Error> for KvStoreError {
     fn from(err: io::Error) -> KvStoreError {
         KvStoreError::Io(err)
@@ -48,6 +185,12 @@ impl From<serde_json::Error> for KvStoreError {
     }
 }
 
+impl From<bincode::Error> for KvStoreError {
+    fn from(err: bincode::Error) -> KvStoreError {
+        KvStoreError::Bincode(err)
+    }
+}
+
 impl From<sled::Error> for KvStoreError {
     fn from(err: sled::Error) -> KvStoreError {
         KvStoreError::Sled(err)
@@ -0,0 +1,95 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use crate::EvictionPolicy;
+
+// ranks the keys of a namespace for eviction, the key with the smallest rank goes first.
+//   lru:       (last use, 0)
+//   lfu:       (uses, last use)
+//   random:    (random number drawn on every write, 0)
+//   ttl first: (expiry, last use), keys without expiry rank after all others
+pub(crate) struct Tracker {
+    policy: EvictionPolicy,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    keys: HashMap<String, Meta>,
+    ranks: BTreeSet<(u64, u64, String)>,
+    tick: u64,
+}
+
+struct Meta {
+    uses: u64,
+    last_use: u64,
+    expires: Option<u64>,
+    random: u64,
+}
+
+impl Tracker {
+    pub(crate) fn new(policy: EvictionPolicy) -> Tracker {
+        Tracker { policy, inner: Mutex::new(Inner::default()) }
+    }
+
+    // key was written, with its expiry in ms since the unix epoch
+    pub(crate) fn write(&self, key: &str, expires: Option<u64>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let old = inner.remove(self.policy, key);
+        let meta = Meta {
+            uses: old.map_or(1, |meta| meta.uses + 1),
+            last_use: tick,
+            expires,
+            random: rand::random(),
+        };
+        inner.insert(self.policy, key.to_owned(), meta);
+    }
+
+    // key was read
+    pub(crate) fn read(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some(mut meta) = inner.remove(self.policy, key) {
+            meta.uses += 1;
+            meta.last_use = tick;
+            inner.insert(self.policy, key.to_owned(), meta);
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(self.policy, key);
+    }
+
+    // the key to evict next, never `keep`
+    pub(crate) fn victim(&self, keep: &str) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.ranks.iter().map(|(_, _, key)| key).find(|key| *key != keep).cloned()
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, policy: EvictionPolicy, key: String, meta: Meta) {
+        let (first, second) = rank(policy, &meta);
+        self.ranks.insert((first, second, key.clone()));
+        self.keys.insert(key, meta);
+    }
+
+    fn remove(&mut self, policy: EvictionPolicy, key: &str) -> Option<Meta> {
+        let meta = self.keys.remove(key)?;
+        let (first, second) = rank(policy, &meta);
+        self.ranks.remove(&(first, second, key.to_owned()));
+        Some(meta)
+    }
+}
+
+fn rank(policy: EvictionPolicy, meta: &Meta) -> (u64, u64) {
+    match policy {
+        EvictionPolicy::None | EvictionPolicy::Lru => (meta.last_use, 0),
+        EvictionPolicy::Lfu => (meta.uses, meta.last_use),
+        EvictionPolicy::Random => (meta.random, 0),
+        EvictionPolicy::TtlFirst => (meta.expires.unwrap_or(u64::MAX), meta.last_use),
+    }
+}
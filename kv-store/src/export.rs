@@ -0,0 +1,198 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Serialize, Deserialize};
+
+use crate::{KvEngine, KvStoreError, Result};
+
+// report progress every this many records
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// file format used by export and import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one `{"key": ..., "value": ...}` object per line
+    JsonLines,
+    /// a `key,value` header followed by one record per line
+    Csv,
+}
+
+/// how keys and values are written into the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueEncoding {
+    /// the text as it is
+    Plain,
+    /// standard base64 of the utf8 bytes
+    Base64,
+    /// lowercase hex of the utf8 bytes
+    Hex,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// write every pair whose key starts with prefix into writer, return the number of pairs.
+/// progress is called with the number of pairs written so far
+pub fn export<E: KvEngine, W: Write>(
+    engine: &E,
+    prefix: &str,
+    writer: W,
+    format: ExportFormat,
+    encoding: ValueEncoding,
+    progress: &mut dyn FnMut(u64),
+) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        ExportFormat::JsonLines => {
+            let mut writer = writer;
+            for pair in engine.scan(prefix)? {
+                let (key, value) = pair?;
+                let pair = Pair { key: encoding.encode(&key), value: encoding.encode(&value) };
+                serde_json::to_writer(&mut writer, &pair)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+                if count % PROGRESS_INTERVAL == 0 {
+                    progress(count);
+                }
+            }
+            writer.flush()?;
+        },
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for pair in engine.scan(prefix)? {
+                let (key, value) = pair?;
+                writer.serialize(Pair { key: encoding.encode(&key), value: encoding.encode(&value) })
+                    .map_err(csv_error)?;
+                count += 1;
+                if count % PROGRESS_INTERVAL == 0 {
+                    progress(count);
+                }
+            }
+            writer.flush()?;
+        },
+    }
+    progress(count);
+    Ok(count)
+}
+
+/// set every pair read from reader into engine, return the number of pairs.
+/// progress is called with the number of pairs loaded so far
+pub fn import<E: KvEngine, R: BufRead>(
+    engine: &E,
+    reader: R,
+    format: ExportFormat,
+    encoding: ValueEncoding,
+    progress: &mut dyn FnMut(u64),
+) -> Result<u64> {
+    let mut count = 0;
+    let mut load = |pair: Pair| -> Result<()> {
+        engine.set(encoding.decode(&pair.key)?, encoding.decode(&pair.value)?)?;
+        count += 1;
+        if count % PROGRESS_INTERVAL == 0 {
+            progress(count);
+        }
+        Ok(())
+    };
+    match format {
+        ExportFormat::JsonLines => {
+            for (line_no, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let pair: Pair = serde_json::from_str(&line)
+                    .map_err(|e| KvStoreError::StringErr(format!("line {}: {}", line_no + 1, e)))?;
+                load(pair)?;
+            }
+        },
+        ExportFormat::Csv => {
+            for pair in csv::Reader::from_reader(reader).deserialize() {
+                load(pair.map_err(csv_error)?)?;
+            }
+        },
+    }
+    progress(count);
+    Ok(count)
+}
+
+fn csv_error(err: csv::Error) -> KvStoreError {
+    KvStoreError::StringErr(format!("csv error: {}", err))
+}
+
+impl ValueEncoding {
+    fn encode(&self, text: &str) -> String {
+        match self {
+            ValueEncoding::Plain => text.to_owned(),
+            ValueEncoding::Base64 => BASE64.encode(text),
+            ValueEncoding::Hex => text.bytes().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    fn decode(&self, text: &str) -> Result<String> {
+        let bytes = match self {
+            ValueEncoding::Plain => return Ok(text.to_owned()),
+            ValueEncoding::Base64 => BASE64.decode(text)
+                .map_err(|e| KvStoreError::StringErr(format!("invalid base64: {}", e)))?,
+            ValueEncoding::Hex => {
+                if !text.len().is_multiple_of(2) || !text.is_ascii() {
+                    return Err(KvStoreError::StringErr(format!("invalid hex: {}", text)));
+                }
+                (0..text.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+                    .collect::<std::result::Result<Vec<u8>, _>>()
+                    .map_err(|e| KvStoreError::StringErr(format!("invalid hex: {}", e)))?
+            },
+        };
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ExportFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "jsonl" | "json-lines" => Ok(ExportFormat::JsonLines),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportFormat::JsonLines => write!(f, "jsonl"),
+            ExportFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+impl FromStr for ValueEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ValueEncoding, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "plain" => Ok(ValueEncoding::Plain),
+            "base64" => Ok(ValueEncoding::Base64),
+            "hex" => Ok(ValueEncoding::Hex),
+            _ => Err(format!("unknown encoding: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ValueEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueEncoding::Plain => write!(f, "plain"),
+            ValueEncoding::Base64 => write!(f, "base64"),
+            ValueEncoding::Hex => write!(f, "hex"),
+        }
+    }
+}
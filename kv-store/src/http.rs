@@ -0,0 +1,444 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{ErrorCode, KvEngine, KvStoreError, Result, thread_pool::ThreadPool};
+use crate::limits::{Limits, Slots, MAX_REJECTING, REJECT_WAIT};
+use crate::server::wait_request;
+use crate::shutdown::Shutdown;
+
+// requests with a longer line, more headers or a larger body are refused
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+const TEXT: &str = "text/plain; charset=utf-8";
+const JSON: &str = "application/json";
+// values are text, a binary value is stored as its base64
+const OCTET_STREAM: &str = "application/octet-stream";
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    // header names are lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct HttpResponse {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(found, _)| found == name).map(|(_, value)| value.as_str())
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(found, _)| found == name).map(|(_, value)| value.as_str())
+    }
+}
+
+impl HttpResponse {
+    fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> HttpResponse {
+        HttpResponse { status, content_type, headers: Vec::new(), body: body.into() }
+    }
+
+    fn empty(status: u16) -> HttpResponse {
+        HttpResponse::new(status, TEXT, Vec::new())
+    }
+
+    fn json(value: &impl Serialize) -> HttpResponse {
+        match serde_json::to_vec(value) {
+            Ok(body) => HttpResponse::new(200, JSON, body),
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> HttpResponse {
+        let mut body = message.into();
+        body.push('\n');
+        HttpResponse::new(status, TEXT, body)
+    }
+
+    fn with_header(mut self, name: &'static str, value: impl Into<String>) -> HttpResponse {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn write(&self, writer: &mut impl Write, keep_alive: bool) -> Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        if self.status != 204 {
+            write!(writer, "Content-Type: {}\r\nContent-Length: {}\r\n", self.content_type, self.body.len())?;
+        }
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// the state of the HTTP gateway, requests work on the default database
+#[derive(Clone)]
+pub(crate) struct HttpSession<E: KvEngine> {
+    engine: E,
+    read_only: bool,
+    // clients send it as `Authorization: Bearer <token>`
+    auth_token: Option<String>,
+    // held by PUT and DELETE, shared with the other listeners
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl<E: KvEngine> HttpSession<E> {
+    pub(crate) fn new(engine: E, read_only: bool, auth_token: Option<String>, write_lock: Arc<Mutex<()>>) -> HttpSession<E> {
+        HttpSession { engine, read_only, auth_token, write_lock }
+    }
+}
+
+// accept HTTP connections and serve them on pool, like `resp::serve`
+pub(crate) fn serve<E: KvEngine, T: ThreadPool>(
+    listener: &TcpListener,
+    session: HttpSession<E>,
+    pool: &T,
+    shutdown: &Arc<Shutdown>,
+    limits: Limits,
+    (connections, rejecting): (&Arc<Slots>, &Arc<Slots>),
+) {
+    for stream in listener.incoming() {
+        if shutdown.is_requested() {
+            break;
+        }
+        let (stream, connection) = match stream.and_then(|stream| Shutdown::register(shutdown, &stream).map(|connection| (stream, connection))) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("fail to accept http connection: {}", e);
+                continue;
+            },
+        };
+        info!("get http connection");
+        let slot = match Slots::take(connections, limits.max_connections) {
+            Some(slot) => slot,
+            None => {
+                match Slots::take(rejecting, Some(MAX_REJECTING)) {
+                    Some(rejecting) => {
+                        warn!("too many connections, refuse an http connection");
+                        thread::spawn(move || {
+                            if let Err(e) = reject(stream) {
+                                error!("fail to refuse http connection: {}", e);
+                            }
+                            drop((rejecting, connection));
+                        });
+                    },
+                    None => warn!("too many connections, close an http connection"),
+                }
+                continue;
+            },
+        };
+        let session = session.clone();
+        pool.spawn(move || {
+            if let Err(e) = serve_connection(&session, stream, limits) {
+                error!("fail to serve http connection: {}", e);
+            }
+            drop((slot, connection));
+        });
+    }
+}
+
+fn serve_connection<E: KvEngine>(session: &HttpSession<E>, stream: TcpStream, limits: Limits) -> Result<()> {
+    stream.set_write_timeout(limits.write_timeout)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let max_body_len = MAX_BODY_LEN.min(limits.max_request_size);
+    while wait_request(&mut reader, false, &limits)? {
+        let request = match read_request(&mut reader, &mut writer, max_body_len) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            // the rest of the stream can not be parsed, answer and hang up
+            Err(e) => {
+                let status = match e.code() {
+                    ErrorCode::TooLarge => 413,
+                    ErrorCode::Unsupported => 501,
+                    _ => 400,
+                };
+                HttpResponse::error(status, e.to_string()).write(&mut writer, false)?;
+                break;
+            },
+        };
+        session.handle(&request).write(&mut writer, request.keep_alive)?;
+        if !request.keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// read the request of a client over the connection limit and answer 503
+fn reject(stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_WAIT))?;
+    stream.set_write_timeout(Some(REJECT_WAIT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    // a request which fails to parse is refused all the same
+    let _ = read_request(&mut reader, &mut writer, MAX_BODY_LEN);
+    HttpResponse::error(503, "too many connections, try again later").write(&mut writer, false)?;
+    Ok(())
+}
+
+// read the next request, None if the client closed the connection between requests
+fn read_request(reader: &mut impl BufRead, writer: &mut impl Write, max_body_len: usize) -> Result<Option<HttpRequest>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => (method, target, version),
+        _ => return Err(KvStoreError::Protocol(format!("invalid request line '{}'", line))),
+    };
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| KvStoreError::Protocol(String::from("unexpected end of stream")))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(KvStoreError::TooLarge(String::from("too many headers")));
+        }
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| KvStoreError::Protocol(format!("invalid header '{}'", line)))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: percent_decode(path, false)?,
+        query: query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Ok((percent_decode(name, true)?, percent_decode(value, true)?))
+            })
+            .collect::<Result<_>>()?,
+        headers,
+        body: Vec::new(),
+        keep_alive: false,
+    };
+    // 1.1 keeps the connection open unless told otherwise, 1.0 the other way round
+    let connection = request.header("connection").map(|value| value.to_ascii_lowercase());
+    request.keep_alive = match connection.as_deref() {
+        Some("close") => false,
+        Some("keep-alive") => true,
+        _ => version == "HTTP/1.1",
+    };
+    if request.header("transfer-encoding").is_some() {
+        return Err(KvStoreError::Unsupported(String::from("chunked bodies are not supported, send a Content-Length")));
+    }
+    let len = match request.header("content-length") {
+        Some(len) => len.parse::<usize>().map_err(|_| KvStoreError::Protocol(format!("invalid content length '{}'", len)))?,
+        None => 0,
+    };
+    if len > max_body_len {
+        return Err(KvStoreError::TooLarge(format!("body of {} bytes is too large", len)));
+    }
+    // curl waits for this before it sends a large body
+    if len > 0 && request.header("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue")) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    request.body = vec![0; len];
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(KvStoreError::TooLarge(String::from("line is too long")));
+    }
+    let line = String::from_utf8(line).map_err(|_| KvStoreError::Protocol(String::from("line is not utf-8")))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+// decode `%XX` escapes, and `+` as a space in a query
+fn percent_decode(s: &str, query: bool) -> Result<String> {
+    let invalid = || KvStoreError::InvalidArgument(format!("invalid escape in '{}'", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().ok_or_else(invalid)?, iter.next().ok_or_else(invalid)?];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            },
+            b'+' if query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[derive(Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+impl<E: KvEngine> HttpSession<E> {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        if let Some(ref token) = self.auth_token {
+            if request.header("authorization") != Some(&format!("Bearer {}", token)) {
+                return HttpResponse::error(401, "unauthorized, send the auth token as a bearer token")
+                    .with_header("WWW-Authenticate", "Bearer");
+            }
+        }
+        let engine = match request.param("namespace") {
+            Some(name) => self.engine.namespace(name),
+            None => Ok(self.engine.clone()),
+        };
+        let engine = match engine {
+            Ok(engine) => engine,
+            Err(e) => return error_response(e),
+        };
+        let path = request.path.as_str();
+        let res = match (request.method.as_str(), path) {
+            ("GET", "/stats") => engine.stats().map(|stats| HttpResponse::json(&stats)),
+            (_, "/stats") => return method_not_allowed("GET"),
+            ("GET", "/kv") => self.scan(&engine, request),
+            (_, "/kv") => return method_not_allowed("GET"),
+            (method, _) if path.starts_with("/kv/") && path.len() > 4 => {
+                let key = path[4..].to_owned();
+                match method {
+                    "GET" => self.get(&engine, key, request),
+                    "PUT" => self.put(&engine, key, request),
+                    "DELETE" => self.delete(&engine, key),
+                    _ => return method_not_allowed("GET, PUT, DELETE"),
+                }
+            },
+            _ => return HttpResponse::error(404, format!("no such path {}", path)),
+        };
+        res.unwrap_or_else(error_response)
+    }
+
+    // a client accepting octet-stream gets the bytes of a base64 value
+    fn get(&self, engine: &E, key: String, request: &HttpRequest) -> Result<HttpResponse> {
+        let value = match engine.get(key)? {
+            Some(value) => value,
+            None => return Ok(HttpResponse::error(404, "Key not found")),
+        };
+        if request.header("accept").is_some_and(|accept| accept.contains(OCTET_STREAM)) {
+            return Ok(match BASE64.decode(&value) {
+                Ok(bytes) => HttpResponse::new(200, OCTET_STREAM, bytes),
+                Err(_) => HttpResponse::error(406, "the value is not binary, accept text/plain"),
+            });
+        }
+        Ok(HttpResponse::new(200, TEXT, value))
+    }
+
+    // an octet-stream body is stored as base64, any other body has to be utf-8
+    fn put(&self, engine: &E, key: String, request: &HttpRequest) -> Result<HttpResponse> {
+        if self.read_only {
+            return Err(KvStoreError::ReadOnly);
+        }
+        let binary = request.header("content-type").is_some_and(|content_type| content_type.starts_with(OCTET_STREAM));
+        let value = if binary {
+            BASE64.encode(&request.body)
+        } else {
+            match String::from_utf8(request.body.clone()) {
+                Ok(value) => value,
+                Err(_) => return Ok(HttpResponse::error(415, format!("the body is not utf-8, send binary values as {}", OCTET_STREAM))),
+            }
+        };
+        let _write = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        engine.set(key, value)?;
+        Ok(HttpResponse::empty(204))
+    }
+
+    fn delete(&self, engine: &E, key: String) -> Result<HttpResponse> {
+        if self.read_only {
+            return Err(KvStoreError::ReadOnly);
+        }
+        let _write = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        match engine.remove(key) {
+            Ok(()) => Ok(HttpResponse::empty(204)),
+            Err(KvStoreError::RemoveNonExistKey) => Ok(HttpResponse::error(404, "Key not found")),
+            Err(e) => Err(e),
+        }
+    }
+
+    // GET /kv?prefix=&limit=, the pairs as a json array in key order
+    fn scan(&self, engine: &E, request: &HttpRequest) -> Result<HttpResponse> {
+        let limit = match request.param("limit").map(|limit| limit.parse::<usize>()) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return Ok(HttpResponse::error(400, "limit is not a number")),
+            None => usize::MAX,
+        };
+        let pairs = engine.scan(request.param("prefix").unwrap_or(""))?
+            .take(limit)
+            .map(|pair| pair.map(|(key, value)| Pair { key, value }))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(HttpResponse::json(&pairs))
+    }
+}
+
+fn method_not_allowed(allow: &str) -> HttpResponse {
+    HttpResponse::error(405, "method not allowed").with_header("Allow", allow)
+}
+
+fn error_response(e: KvStoreError) -> HttpResponse {
+    let status = match e.code() {
+        ErrorCode::NotFound => 404,
+        ErrorCode::WrongType | ErrorCode::Conflict => 409,
+        ErrorCode::ReadOnly => 403,
+        ErrorCode::StoreFull => 507,
+        ErrorCode::Unauthorized => 401,
+        ErrorCode::InvalidArgument => 400,
+        ErrorCode::TooLarge => 413,
+        ErrorCode::Unsupported => 501,
+        ErrorCode::Internal => 500,
+        ErrorCode::Busy => 503,
+        ErrorCode::DeadlineExceeded => 504,
+    };
+    HttpResponse::error(status, e.to_string())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+use crate::{KvStoreError, Result};
+
+/// a secondary index over the JSON values of the keys starting with a prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexSpec {
+    /// the name queries use
+    pub name: String,
+    /// only keys starting with prefix are indexed
+    pub prefix: String,
+    /// the indexed part of the value, e.g. `$.email` or `$.tags[0]`
+    pub path: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Field(String),
+    Item(usize),
+}
+
+impl IndexSpec {
+    /// index the values of the keys starting with prefix by the JSON path, which is `$`
+    /// followed by `.field` and `[n]` steps. strings and numbers at the path are indexed,
+    /// an array is indexed under each of its items
+    pub fn new(name: &str, prefix: &str, path: &str) -> Result<IndexSpec> {
+        Ok(IndexSpec {
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+            path: path.to_owned(),
+            segments: parse_path(path)?,
+        })
+    }
+
+    // the terms the value of key is found under, none if the key has another prefix,
+    // the value is not JSON or has nothing at the path
+    pub(crate) fn terms(&self, key: &str, value: &str) -> Vec<String> {
+        if !key.starts_with(&self.prefix) {
+            return Vec::new();
+        }
+        let doc: Value = match serde_json::from_str(value) {
+            Ok(doc) => doc,
+            Err(_) => return Vec::new(),
+        };
+        let mut found = &doc;
+        for segment in &self.segments {
+            let next = match segment {
+                Segment::Field(field) => found.get(field.as_str()),
+                Segment::Item(i) => found.get(*i),
+            };
+            found = match next {
+                Some(next) => next,
+                None => return Vec::new(),
+            };
+        }
+        match found {
+            Value::Array(items) => items.iter().filter_map(term).collect(),
+            other => term(other).into_iter().collect(),
+        }
+    }
+}
+
+fn term(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>> {
+    let invalid = || KvStoreError::InvalidJsonPath(path.to_owned());
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(field) = rest.strip_prefix('.') {
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            segments.push(Segment::Field(field[..end].to_owned()));
+            rest = &field[end..];
+        } else if let Some(item) = rest.strip_prefix('[') {
+            let (i, after) = item.split_once(']').ok_or_else(invalid)?;
+            segments.push(Segment::Item(i.parse().map_err(|_| invalid())?));
+            rest = after;
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(segments)
+}
+
+// the in-memory index of a namespace, maps terms to the keys whose value has them.
+// it is updated under the writer lock of the namespace, together with the primary index
+pub(crate) struct JsonIndex {
+    pub(crate) spec: IndexSpec,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // (term, key)
+    terms: BTreeSet<(String, String)>,
+    // the terms of every indexed key and when the key expires
+    keys: HashMap<String, (Vec<String>, Option<u64>)>,
+}
+
+impl JsonIndex {
+    pub(crate) fn new(spec: IndexSpec) -> JsonIndex {
+        JsonIndex { spec, inner: Mutex::new(Inner::default()) }
+    }
+
+    // key was set to value
+    pub(crate) fn insert(&self, key: &str, value: &str, expires: Option<u64>) {
+        let terms = self.spec.terms(key, value);
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(key);
+        if terms.is_empty() {
+            return;
+        }
+        for term in &terms {
+            inner.terms.insert((term.clone(), key.to_owned()));
+        }
+        inner.keys.insert(key.to_owned(), (terms, expires));
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    // the keys indexed under term which have not expired at now, in key order
+    pub(crate) fn query(&self, term: &str, now: u64) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.terms.range((term.to_owned(), String::new())..)
+            .take_while(|(found, _)| found == term)
+            .filter(|(_, key)| inner.keys.get(key).and_then(|(_, expires)| *expires).is_none_or(|expires| expires > now))
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some((terms, _)) = self.keys.remove(key) {
+            for term in terms {
+                self.terms.remove(&(term, key.to_owned()));
+            }
+        }
+    }
+}
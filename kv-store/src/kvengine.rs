@@ -1,4 +1,16 @@
-use crate::Result;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{KvStore, KvStoreError, KvStoreOptions, Result, SledKvsEngine, EngineStats};
+
+/// the file recording which engine a data directory is created with
+pub const ENGINE_META_PATH: &str = "engine.meta";
+
+/// an iterator over key-value pairs in key order
+pub type Scan = Box<dyn Iterator<Item = Result<(String, String)>>>;
 
 /// a trait for kvengines, kvstore and sled have to impl this trait
 pub trait KvEngine: Clone + Send + 'static {
@@ -8,4 +20,180 @@ pub trait KvEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     /// remove key
     fn remove(&self, key: String) -> Result<()>;
-}
\ No newline at end of file
+    /// set key-value, the key reads as missing once ttl passed. engines without expiry return `Unsupported`
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+    /// set every pair or none of them, engines without atomic batches return `Unsupported`
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()>;
+    /// get an engine working on the namespace `name`, sharing the same storage
+    fn namespace(&self, name: &str) -> Result<Self>;
+    /// write a consistent copy of the whole engine into the empty directory dest
+    fn checkpoint(&self, dest: &Path) -> Result<()>;
+    /// write out what the engine buffers and sync it to disk, for every namespace
+    fn flush(&self) -> Result<()>;
+    /// iterate over the live pairs whose key starts with prefix, keys written during the scan
+    /// may or may not be returned
+    fn scan(&self, prefix: &str) -> Result<Scan>;
+    /// names of every namespace of the engine, sorted
+    fn namespaces(&self) -> Result<Vec<String>>;
+    /// the keys whose JSON value has `value` at the path of the secondary index `index`, in key order
+    fn query_index(&self, index: &str, value: &str) -> Result<Vec<String>>;
+    /// the type of the engine
+    fn engine_type(&self) -> EngineType;
+    /// statistics of the namespace, op counters start at zero when the engine is opened
+    fn stats(&self) -> Result<EngineStats>;
+}
+
+/// engine type, kvs or sled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineType {
+    /// the log-structured KvStore
+    Kvs,
+    /// sled
+    Sled,
+}
+
+/// the engine used when neither the arguments nor engine.meta name one
+pub const DEFAULT_ENGINE: EngineType = EngineType::Kvs;
+
+impl FromStr for EngineType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<EngineType, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "kvs" => Ok(EngineType::Kvs),
+            "sled" => Ok(EngineType::Sled),
+            _ => Err(format!("unknown engine: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for EngineType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineType::Kvs => write!(f, "kvs"),
+            EngineType::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+/// load_engine_meta is used to read engine type from the persistent file in dir
+/// if the engine type is not specified, use DEFAULT_ENGINE and write it to disk
+pub fn load_engine_meta(dir: &Path, engine_arg: Option<EngineType>) -> Result<EngineType> {
+    let path = dir.join(ENGINE_META_PATH);
+    if path.exists() {
+        let engine: EngineType = fs::read_to_string(path)?.parse().map_err(KvStoreError::StringErr)?;
+        // if the args contain --engine, check ENGINE-NAME == engine(meta data)
+        if let Some(engine_selected) = engine_arg {
+            if engine_selected != engine {
+                return Err(KvStoreError::WrongEngineError);
+            }
+        }
+        Ok(engine)
+    } else {
+        // if args do not conatain --engine, use DEFAULT_ENGINE, otherwise ENGINE-NAME
+        let engine = engine_arg.unwrap_or(DEFAULT_ENGINE);
+        // persist engine meta to disk
+        fs::write(path, engine.to_string())?;
+        Ok(engine)
+    }
+}
+
+/// an engine whose type is only known at runtime, e.g. read from engine.meta
+#[derive(Clone)]
+pub enum AnyEngine {
+    /// a KvStore
+    Kvs(KvStore),
+    /// a sled database
+    Sled(SledKvsEngine),
+}
+
+impl AnyEngine {
+    /// open an engine of the given type in dir, sled only uses the cache size of the options
+    pub fn open(dir: impl Into<PathBuf>, engine: EngineType, options: KvStoreOptions) -> Result<AnyEngine> {
+        match engine {
+            EngineType::Kvs => Ok(AnyEngine::Kvs(KvStore::open_with(dir, options)?)),
+            EngineType::Sled => Ok(AnyEngine::Sled(SledKvsEngine::open_with(dir, &options)?)),
+        }
+    }
+}
+
+impl KvEngine for AnyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.set(key, value),
+            AnyEngine::Sled(engine) => engine.set(key, value),
+        }
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.get(key),
+            AnyEngine::Sled(engine) => engine.get(key),
+        }
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.remove(key),
+            AnyEngine::Sled(engine) => engine.remove(key),
+        }
+    }
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => KvEngine::set_with_ttl(engine, key, value, ttl),
+            AnyEngine::Sled(engine) => engine.set_with_ttl(key, value, ttl),
+        }
+    }
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.set_batch(pairs),
+            AnyEngine::Sled(engine) => engine.set_batch(pairs),
+        }
+    }
+    fn namespace(&self, name: &str) -> Result<AnyEngine> {
+        match self {
+            AnyEngine::Kvs(engine) => Ok(AnyEngine::Kvs(engine.namespace(name)?)),
+            AnyEngine::Sled(engine) => Ok(AnyEngine::Sled(KvEngine::namespace(engine, name)?)),
+        }
+    }
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => KvEngine::checkpoint(engine, dest),
+            AnyEngine::Sled(engine) => engine.checkpoint(dest),
+        }
+    }
+    fn flush(&self) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.flush(),
+            AnyEngine::Sled(engine) => engine.flush(),
+        }
+    }
+    fn scan(&self, prefix: &str) -> Result<Scan> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.scan(prefix),
+            AnyEngine::Sled(engine) => engine.scan(prefix),
+        }
+    }
+    fn namespaces(&self) -> Result<Vec<String>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.namespaces(),
+            AnyEngine::Sled(engine) => engine.namespaces(),
+        }
+    }
+    fn query_index(&self, index: &str, value: &str) -> Result<Vec<String>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.query_index(index, value),
+            AnyEngine::Sled(engine) => engine.query_index(index, value),
+        }
+    }
+    fn engine_type(&self) -> EngineType {
+        match self {
+            AnyEngine::Kvs(_) => EngineType::Kvs,
+            AnyEngine::Sled(_) => EngineType::Sled,
+        }
+    }
+    fn stats(&self) -> Result<EngineStats> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.stats(),
+            AnyEngine::Sled(engine) => engine.stats(),
+        }
+    }
+}
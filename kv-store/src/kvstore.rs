@@ -1,261 +1,808 @@
-use std::collections::BTreeMap;
-use std::io::{BufReader, BufWriter, Write, Seek, SeekFrom, Read};
-use std::path::PathBuf;
-use std::fs::{File, OpenOptions, remove_file, rename};
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufReader, BufWriter, Write, Seek, SeekFrom, Read};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crossbeam_skiplist::SkipMap;
-use log::info;
 use serde::{Serialize, Deserialize};
-use serde_json::Deserializer;
 
-use crate::{KvStoreError, Result, KvEngine};
+use crate::{KvStoreError, Result, KvEngine, KvStoreOptions, CompactionPolicy, SyncPolicy, EvictionPolicy, EngineType, Scan};
+use crate::record::{self, encode};
+use crate::checkpoint;
+use crate::lock::DirLock;
+use crate::cache::ValueCache;
+use crate::evict::Tracker;
+use crate::json_index::JsonIndex;
+use crate::stats::{EngineStats, OpCounters};
 
-const COMPACT_THRESHOLD: u64 = 1024 * 1024;
+/// the namespace used when no namespace is given
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+// the single log file used before logs were split into generations
+pub(crate) const LEGACY_LOG: &str = "kvstore.log";
 
 /// 'Command' is a enum that represents various commands
 #[derive(Serialize, Deserialize)]
 pub enum Command {
-    Set{key: String, value: String},
+    Set{
+        key: String,
+        value: String,
+        // ms since the unix epoch after which the key reads as missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
     Rm{key: String},
 }
 
-/// 'KvStore' is a Hashmap that can store key-value pairs
+/// 'KvStore' is a Hashmap that can store key-value pairs.
+/// a store is split into namespaces, every handle works on one of them
 #[derive(Clone)]
 pub struct KvStore {
-    // in-memory index, stores the log pointer(file offset and command length) of the command
-    index: Arc<SkipMap<String, (u64, u64)>>,
-    // writer buffer for log
-    writer: Arc<Mutex<BufWriter<File>>>,
-    // the path of the file
-    path: Arc<PathBuf>,
-    // the dir of the file
+    // the namespace this handle reads and writes
+    keyspace: Arc<Keyspace>,
+    // the dir of the store
     dir: Arc<PathBuf>,
-    // the write offset of the log file
-    offset: Arc<Mutex<u64>>,
+    // options the store is opened with, shared by all namespaces
+    options: Arc<KvStoreOptions>,
+    // every namespace of the store
+    namespaces: Arc<Mutex<HashMap<String, Arc<Keyspace>>>>,
+    // keeps other processes out of the dir until the last handle is dropped
+    _lock: Arc<DirLock>,
+}
+
+// a namespace, which has its own log, index and compaction policy.
+// the log is split into generations `<namespace>.<gen>.log`, only the newest one is appended to,
+// the older generations are immutable until compaction deletes them
+struct Keyspace {
+    name: String,
+    dir: PathBuf,
+    // in-memory index, stores the log pointer of the latest command of every key
+    index: SkipMap<String, LogPointer>,
+    // the active generation and its writer
+    writer: Mutex<LogWriter>,
+    // when to compact the log
+    // set again by every namespace_with, it is not stored
+    policy: Mutex<CompactionPolicy>,
+    // recently read values
+    cache: ValueCache,
+    // get, set and remove counters
+    counters: OpCounters,
+    // ranks the keys for eviction, None if the namespace has no limit or never evicts
+    evict: Option<Tracker>,
+    // the secondary indexes declared in the options
+    indexes: Vec<JsonIndex>,
+}
+
+// the position of a record in the log
+#[derive(Clone, Copy, Debug)]
+struct LogPointer {
+    gen: u64,
+    pos: u64,
+    len: u64,
+}
+
+struct LogWriter {
+    // None if the store is opened read-only
+    writer: Option<BufWriter<File>>,
+    // the generation being appended to
+    gen: u64,
+    // the write offset of the active generation
+    offset: u64,
+    // bytes of records which are overwritten or removed
+    stale: u64,
+    // bytes of the records the index points at
+    live: u64,
+    // number of keys removed to stay within the limits
+    evictions: u64,
+    // when the active generation was last synced
+    last_sync: Instant,
+    // number of compactions, and when the last one finished and how long it took
+    compactions: u64,
+    last_compaction: Option<(SystemTime, Duration)>,
 }
 
 impl KvStore {
     /// open the KvStore at a given path
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        // let file = OpenOptions::new()
-        //                     .read(true).write(true).append(true)
-        //                             .open(path.into())?;
-        // let mut index = BTreeMap::new();
-        // let pos = rebuild_index(file, &mut index)?;
-        // Ok(KvStore { index, log_file: file, path: path.into(), offset: pos })
-        let mut path = path.into();
-        let dir = path.clone();
-        path.push("kvstore.log");
-        let mut file = if path.clone().as_path().exists() {
-            OpenOptions::new().read(true).write(true).open(path.clone())?
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// open the KvStore at a given path read-only. other read-only handles may open the store
+    /// at the same time, no file is created and every write fails with `KvStoreError::ReadOnly`
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::new().read_only(true))
+    }
+
+    /// open the KvStore at a given path with the given options
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let dir = path.into();
+        let lock = if options.read_only {
+            if dir.join(LEGACY_LOG).exists() {
+                return Err(KvStoreError::StringErr(format!("{} has to be opened for writing once to upgrade {}", dir.display(), LEGACY_LOG)));
+            }
+            DirLock::shared(&dir)?
         } else {
-            OpenOptions::new().read(true).write(true).create(true).open(path.clone())?
+            checkpoint::recover_restore(&dir)?;
+            fs::create_dir_all(&dir)?;
+            DirLock::exclusive(&dir)?
         };
-        // let mut file = OpenOptions::new()
-        //                 .read(true)
-        //                 .write(true)
-        //                 .create(true)
-        //                 .truncate(true)
-        //                 .open(path.clone())?;
-        let mut index= SkipMap::new();
-        let offset = rebuild_index(&file, &mut index)?;
-        file.seek(SeekFrom::End(0))?;
-        let writer = BufWriter::new(file);
+        KvStore::open_locked(dir, options, Arc::new(lock))
+    }
+
+    // open the store in dir, whose lock the caller holds
+    pub(crate) fn open_locked(dir: PathBuf, options: KvStoreOptions, lock: Arc<DirLock>) -> Result<KvStore> {
+        if !options.read_only {
+            migrate_legacy_log(&dir, &options)?;
+        }
+        let mut namespaces = HashMap::new();
+        let mut names: BTreeSet<String> = list_generations(&dir)?.into_iter().map(|(name, _)| name).collect();
+        names.insert(DEFAULT_NAMESPACE.to_owned());
+        for name in names {
+            let keyspace = Keyspace::open(&dir, &name, options.compaction, &options)?;
+            namespaces.insert(name, Arc::new(keyspace));
+        }
         Ok(KvStore { 
-            index: Arc::new((index)), 
-            writer: Arc::new(Mutex::new(writer)), 
-            path: Arc::new(path), 
+            keyspace: namespaces[DEFAULT_NAMESPACE].clone(),
             dir: Arc::new(dir), 
-            offset: Arc::new(Mutex::new(offset))
+            options: Arc::new(options),
+            namespaces: Arc::new(Mutex::new(namespaces)),
+            _lock: lock,
         })
     }
 
-    /// used to compact the kvstore and the log, remove the redundant key-value command
+    /// get a handle to the namespace `name`, the namespace is created if it does not exist
+    pub fn namespace(&self, name: &str) -> Result<KvStore> {
+        self.open_namespace(name, None)
+    }
+
+    /// get a handle to the namespace `name` and switch it to the given compaction policy.
+    /// the policy is not stored with the namespace, after reopening the store it is the one
+    /// of the options until namespace_with is called again
+    pub fn namespace_with(&self, name: &str, policy: CompactionPolicy) -> Result<KvStore> {
+        self.open_namespace(name, Some(policy))
+    }
+
+    fn open_namespace(&self, name: &str, policy: Option<CompactionPolicy>) -> Result<KvStore> {
+        if !valid_name(name) {
+            return Err(KvStoreError::InvalidNamespace(name.to_owned()));
+        }
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let keyspace = match namespaces.get(name) {
+            Some(keyspace) => keyspace.clone(),
+            None => {
+                let keyspace = Arc::new(Keyspace::open(&self.dir, name, self.options.compaction, &self.options)?);
+                namespaces.insert(name.to_owned(), keyspace.clone());
+                keyspace
+            },
+        };
+        if let Some(policy) = policy {
+            *keyspace.policy.lock().unwrap() = policy;
+        }
+        Ok(KvStore { keyspace, ..self.clone() })
+    }
+
+    /// used to compact the namespace and its log, remove the redundant key-value command.
+    /// live records are re-encoded with the current options, so old records get recompressed
     pub fn compact(&self) -> Result<()> {
-        let mut new_file_path = self.dir.clone();
-        let mut new_file_path = (*new_file_path).clone();
-        new_file_path.push("kvstore.bk");
-        // kvstore.bk
-        let new_file = OpenOptions::new()
-                    .read(true).write(true).create(true).open(new_file_path.clone())?;
-        // kvstore.log
-        let path = self.path.clone();
-        let old_file = File::open((*path).clone())?;
-        let mut stream = Deserializer::from_reader(old_file).into_iter::<Command>();
-        let mut new_file_writer = BufWriter::new(new_file);
-
-        // read every command in log file
-        let mut read_offset: u64 = 0;
-        while let Some(cmd) = stream.next() {
-            let new_offset = stream.byte_offset() as u64;
-            match cmd? {
-                Command::Set{key, value} => {
-                    let mut index = self.index.clone();
-                    let res = index.get(&key);
-                    match res {
-                        Some(kv) => {
-                            let (pos, _) = kv.value();
-                            // if the command is in in-memory index, write the command into kvstore.bk
-                            if *pos == read_offset {
-                                let cmd = Command::Set { key, value };
-                                let write_str = serde_json::to_string_pretty(&cmd)?;
-                                new_file_writer.write(write_str.as_bytes())?;
-                                new_file_writer.flush()?;
-                            }
-                            // if the command is not in in-memory index, ignore that
-                        },
-                        None => {}
-                    }
+        let start = Instant::now();
+        let keyspace = &self.keyspace;
+        // hold the writer during compaction so that no command is appended to the old generations
+        let mut writer = keyspace.writer.lock().unwrap();
+        writer.writer.as_mut().ok_or(KvStoreError::ReadOnly)?.flush()?;
+
+        // live records are copied into a new generation, which becomes the active one
+        let compact_gen = writer.gen + 1;
+        let mut new_file_writer = BufWriter::with_capacity(self.options.buffer_size, create_log(&keyspace.log_path(compact_gen))?);
+        let mut write_offset: u64 = 0;
+        let mut moved = Vec::new();
+        let mut expired = Vec::new();
+        let now = now_millis();
+        for entry in keyspace.index.iter() {
+            let data = keyspace.read(*entry.value(), &self.options)?;
+            // expired keys are dropped, there is no older generation left for them to fall back to
+            if let Command::Set { expires: Some(expires), .. } = serde_json::from_slice(&data)? {
+                if expires <= now {
+                    expired.push(entry.key().clone());
+                    continue;
+                }
+            }
+            let buf = encode(&data, &self.options)?;
+            new_file_writer.write_all(&buf)?;
+            moved.push((entry.key().clone(), LogPointer { gen: compact_gen, pos: write_offset, len: buf.len() as u64 }));
+            write_offset += buf.len() as u64;
+        }
+        new_file_writer.flush()?;
+        // the compacted generation has to be on disk before the old ones are removed
+        new_file_writer.get_ref().sync_data()?;
+        sync_dir(&keyspace.dir)?;
+
+        // point the in-memory index to the new generation
+        for (key, pointer) in moved {
+            keyspace.index.insert(key, pointer);
+        }
+        for key in expired {
+            keyspace.index.remove(&key);
+            keyspace.untrack(&key);
+        }
+        writer.writer = Some(new_file_writer);
+        writer.gen = compact_gen;
+        writer.offset = write_offset;
+        writer.stale = 0;
+        writer.live = write_offset;
+        writer.compactions += 1;
+        writer.last_compaction = Some((SystemTime::now(), start.elapsed()));
+
+        // the old generations are not referenced anymore
+        for gen in keyspace.generations()? {
+            if gen < compact_gen {
+                fs::remove_file(keyspace.log_path(gen))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write a consistent copy of the whole store into dest_dir.
+    /// writers are only blocked while the active generations are sealed and hard linked
+    pub fn checkpoint(&self, dest_dir: impl AsRef<Path>) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        checkpoint::prepare_dest(dest_dir)?;
+
+        let mut keyspaces: Vec<Arc<Keyspace>> = self.namespaces.lock().unwrap().values().cloned().collect();
+        // always lock namespaces in the same order
+        keyspaces.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut writers: Vec<MutexGuard<LogWriter>> = Vec::new();
+        for keyspace in &keyspaces {
+            writers.push(keyspace.writer.lock().unwrap());
+        }
+
+        for (keyspace, writer) in keyspaces.iter().zip(writers.iter_mut()) {
+            let sealed_gen = writer.gen;
+            // seal the active generation, new commands go to a fresh one.
+            // nothing is appended to a read-only store, so its generations are copied as they are
+            if let Some(ref mut log) = writer.writer {
+                log.flush()?;
+                writer.gen += 1;
+                writer.writer = Some(BufWriter::with_capacity(self.options.buffer_size, create_log(&keyspace.log_path(writer.gen))?));
+                writer.offset = 0;
+            }
+
+            for gen in keyspace.generations()? {
+                if gen <= sealed_gen {
+                    link_or_copy(&keyspace.log_path(gen), &dest_dir.join(log_name(&keyspace.name, gen)))?;
                 }
-                Command::Rm { .. } => {}
             }
-            read_offset = new_offset;
-        }
-        
-        // rebuild the index from kvstore.bk
-        let new_file = File::open(new_file_path.clone())?;
-        let mut index = self.index.clone();
-        index.clear();
-        let offset = self.offset.clone();
-        let mut offset = offset.lock().unwrap();
-        // *offset = rebuild_index(&new_file, &mut index)?;
-        // 这里不能调用rebuild_index的原因是不能把index作为可变借用，但是为什么呢，感觉理论上可以的
+        }
+        drop(writers);
+
+        // the engine type belongs to the data directory, keep it with the copy
+        let meta = self.dir.join(crate::ENGINE_META_PATH);
+        if meta.exists() {
+            fs::copy(&meta, dest_dir.join(crate::ENGINE_META_PATH))?;
+        }
+        checkpoint::write_manifest(dest_dir)
+    }
+
+    /// insert a key-value pair which expires after ttl. an expired key reads as missing,
+    /// compaction drops it
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_command(Command::Set { key, value, expires: Some(expires) })
+    }
+
+    fn set_command(&self, cmd: Command) -> Result<()> {
+        // write the set command into the disk and update the in-memory index
+        let existed = self.append(cmd)?;
+        self.keyspace.counters.set(existed);
+        if self.need_compact() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // encode the command, append it to the log and update the in-memory index, return whether
+    // the key had a value. the writer is held until the index is updated so commands on the same
+    // key keep their order, a remove of a missing key fails with RemoveNonExistKey
+    fn append(&self, cmd: Command) -> Result<bool> {
+        let keyspace = &self.keyspace;
+        let data = serde_json::to_vec(&cmd)?;
+        let buf = encode(&data, &self.options)?;
+        let mut guard = keyspace.writer.lock().unwrap();
+        let writer = &mut *guard;
+        if writer.writer.is_none() {
+            return Err(KvStoreError::ReadOnly);
+        }
+        match cmd {
+            Command::Set { ref key, .. } => self.make_room(writer, key, buf.len() as u64)?,
+            // checked under the lock, so a concurrent remove can not write a second tombstone
+            Command::Rm { ref key } if !keyspace.index.contains_key(key) => return Err(KvStoreError::RemoveNonExistKey),
+            Command::Rm { .. } => {},
+        }
+        let existed = self.write(writer, cmd, &buf)?;
+
+        // continue in a new generation once the active one is full
+        if self.options.max_file_size.is_some_and(|limit| writer.offset >= limit) {
+            if let Some(ref mut log) = writer.writer {
+                log.get_ref().sync_data()?;
+            }
+            writer.gen += 1;
+            writer.writer = Some(BufWriter::with_capacity(self.options.buffer_size, create_log(&keyspace.log_path(writer.gen))?));
+            writer.offset = 0;
+        }
+        Ok(existed)
+    }
+
+    // evict keys until a record of len bytes for key fits into the limits of the namespace.
+    // an evicted key gets the same tombstone a remove writes, so it stays gone after a restart
+    fn make_room(&self, writer: &mut LogWriter, key: &str, len: u64) -> Result<()> {
+        let keyspace = &self.keyspace;
+        if self.options.max_bytes.is_some_and(|max| len > max) {
+            return Err(KvStoreError::StoreFull);
+        }
+        loop {
+            let old = keyspace.index.get(key).map(|entry| entry.value().len);
+            let bytes = writer.live - old.unwrap_or(0) + len;
+            let keys = keyspace.index.len() as u64 + u64::from(old.is_none());
+            if self.options.max_bytes.is_none_or(|max| bytes <= max) && self.options.max_keys.is_none_or(|max| keys <= max) {
+                return Ok(());
+            }
+            let victim = keyspace.evict.as_ref()
+                .and_then(|tracker| tracker.victim(key))
+                .ok_or(KvStoreError::StoreFull)?;
+            let cmd = Command::Rm { key: victim };
+            let buf = encode(&serde_json::to_vec(&cmd)?, &self.options)?;
+            self.write(writer, cmd, &buf)?;
+            writer.evictions += 1;
+        }
+    }
+
+    // write an encoded command to the active generation and update the in-memory index,
+    // return whether the key had a value
+    fn write(&self, writer: &mut LogWriter, cmd: Command, buf: &[u8]) -> Result<bool> {
+        let keyspace = &self.keyspace;
+        let log = writer.writer.as_mut().ok_or(KvStoreError::ReadOnly)?;
+        log.write_all(buf)?;
+        log.flush()?;
+        let sync = match self.options.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => writer.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            log.get_ref().sync_data()?;
+            writer.last_sync = Instant::now();
+        }
+        let pointer = LogPointer { gen: writer.gen, pos: writer.offset, len: buf.len() as u64 };
+        // update the end offset of the file
+        writer.offset += pointer.len;
+        let old = match cmd {
+            Command::Set { key, value, expires } => {
+                keyspace.track(&key, &value, expires);
+                let old = keyspace.index.get(&key).map(|entry| entry.value().len);
+                keyspace.index.insert(key, pointer);
+                writer.live += pointer.len;
+                old
+            },
+            Command::Rm { key } => {
+                keyspace.untrack(&key);
+                // the remove command itself is stale once the key is compacted away
+                writer.stale += pointer.len;
+                keyspace.index.remove(&key).map(|entry| entry.value().len)
+            },
+        };
+        writer.stale += old.unwrap_or(0);
+        writer.live -= old.unwrap_or(0);
+        Ok(old.is_some())
+    }
+
+    // read the value of key, not counted as a get so that scans leave the counters alone
+    fn lookup(&self, key: String) -> Result<Option<String>> {
+        // a concurrent compaction may delete the generation between the index lookup and opening
+        // the file, the index points to the new generation by then, so look it up again
+        let mut retried = false;
+        loop {
+            // get the log pointer of the latest command corresponds to key
+            let pointer = match self.keyspace.index.get(&key) {
+                Some(kv) => *kv.value(),
+                None => return Ok(None),
+            };
+            let position = (pointer.gen, pointer.pos);
+            if let Some(value) = self.keyspace.cache.get(&key, position) {
+                return Ok(Some(value));
+            }
+            let data = match self.keyspace.read(pointer, &self.options) {
+                Ok(data) => data,
+                Err(KvStoreError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && !retried => {
+                    retried = true;
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            // read command from the log file
+            return match serde_json::from_slice(&data)? {
+                Command::Set { expires: Some(expires), .. } if expires <= now_millis() => Ok(None),
+                Command::Set { value, expires, .. } => {
+                    // expiring values are not cached, the cache would keep serving them
+                    if expires.is_none() {
+                        self.keyspace.cache.insert(key, position, value.clone());
+                    }
+                    Ok(Some(value))
+                },
+                Command::Rm { .. } => Err(KvStoreError::GetNonExistValue),
+            };
+        }
+    }
+
+    fn need_compact(&self) -> bool {
+        let policy = *self.keyspace.policy.lock().unwrap();
+        match policy {
+            CompactionPolicy::StaleBytes(threshold) => self.keyspace.writer.lock().unwrap().stale > threshold,
+            CompactionPolicy::Manual => false,
+        }
+    }
+}
+
+impl Keyspace {
+    // open the log of namespace `name` in dir and rebuild its indexes
+    fn open(dir: &Path, name: &str, policy: CompactionPolicy, options: &KvStoreOptions) -> Result<Keyspace> {
+        let gens = generations(dir, name)?;
+        // keep appending to the newest generation
+        let gen = gens.last().cloned().unwrap_or(1);
+        let writer = if options.read_only {
+            None
+        } else {
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(log_name(name, gen)))?;
+            file.seek(SeekFrom::End(0))?;
+            Some(BufWriter::with_capacity(options.buffer_size, file))
+        };
+        let limited = options.max_bytes.is_some() || options.max_keys.is_some();
+        let evict = if limited && options.eviction != EvictionPolicy::None {
+            Some(Tracker::new(options.eviction))
+        } else {
+            None
+        };
+        let mut keyspace = Keyspace {
+            name: name.to_owned(),
+            dir: dir.to_path_buf(),
+            index: SkipMap::new(),
+            writer: Mutex::new(LogWriter { writer, gen, offset: 0, stale: 0, live: 0, evictions: 0, last_sync: Instant::now(), compactions: 0, last_compaction: None }),
+            policy: Mutex::new(policy),
+            cache: ValueCache::new(options.cache_size),
+            counters: OpCounters::default(),
+            evict,
+            indexes: options.indexes.iter().cloned().map(JsonIndex::new).collect(),
+        };
+        let mut stale = 0;
+        let mut offset = 0;
+        let mut total = 0;
+        for &gen in &gens {
+            let (gen_offset, gen_stale) = keyspace.rebuild_index(gen, options)?;
+            offset = gen_offset;
+            total += gen_offset;
+            stale += gen_stale;
+        }
+        let writer = keyspace.writer.get_mut().unwrap();
+        writer.offset = offset;
+        writer.stale = stale;
+        writer.live = total - stale;
+        Ok(keyspace)
+    }
+
+    // replay generation gen into the indexes, return the end offset of the generation and the stale bytes
+    fn rebuild_index(&self, gen: u64, options: &KvStoreOptions) -> Result<(u64, u64)> {
+        // decode every record in file
+        let mut reader = BufReader::with_capacity(options.buffer_size, File::open(self.log_path(gen))?);
         let mut pos = 0;
-        let mut stream = Deserializer::from_reader(new_file).into_iter::<Command>();
-        while let Some(cmd) = stream.next() {
-            let new_pos = stream.byte_offset() as u64;
-            match cmd? {
-                Command::Set{key, ..} => {
-                    index.insert(key, (pos, new_pos - pos));
+        let mut stale = 0;
+        while let Some((data, len)) = record::read_record(&mut reader, options)? {
+            match serde_json::from_slice(&data)? {
+                Command::Set { key, value, expires } => {
+                    self.track(&key, &value, expires);
+                    if let Some(old) = self.index.get(&key) {
+                        stale += old.value().len;
+                    }
+                    self.index.insert(key, LogPointer { gen, pos, len });
                 }
                 Command::Rm { key } => {
-                    index.remove(&key);
+                    self.untrack(&key);
+                    if let Some(old) = self.index.remove(&key) {
+                        stale += old.value().len;
+                    }
+                    stale += len;
                 }
             }
-            pos = new_pos;
-        }
-        *offset = pos;
-        
-        // delete kvstore.log, rename kvstore.bk as kvstore.log
-        let path = self.path.clone();
-        remove_file((*path).clone())?;
-        rename(new_file_path.as_path(), path.as_path())?;
-        let writer = self.writer.clone();
-        let mut writer = writer.lock().unwrap();
-        let mut file = OpenOptions::new().read(true).write(true).open((*path).clone())?;
-        file.seek(SeekFrom::End(0))?;
-        // 这里可以直接对writer进行修改而不能对index进行修改，主要是因为BufWriter有deref trait，而SkipMap没有deref trait
-        *writer = BufWriter::new(file);
-        Ok(())
+            pos += len;
+        }
+        Ok((pos, stale))
+    }
+
+    // key was set, update the eviction tracker and the secondary indexes
+    fn track(&self, key: &str, value: &str, expires: Option<u64>) {
+        if let Some(ref tracker) = self.evict {
+            tracker.write(key, expires);
+        }
+        for index in &self.indexes {
+            index.insert(key, value, expires);
+        }
+    }
+
+    // key was removed
+    fn untrack(&self, key: &str) {
+        if let Some(ref tracker) = self.evict {
+            tracker.remove(key);
+        }
+        for index in &self.indexes {
+            index.remove(key);
+        }
+    }
+
+    fn log_path(&self, gen: u64) -> PathBuf {
+        self.dir.join(log_name(&self.name, gen))
+    }
+
+    fn generations(&self) -> Result<Vec<u64>> {
+        generations(&self.dir, &self.name)
+    }
+
+    // read the command bytes at pointer
+    fn read(&self, pointer: LogPointer, options: &KvStoreOptions) -> Result<Vec<u8>> {
+        // make the pointer of the file to the command offset
+        let mut file = File::open(self.log_path(pointer.gen))?;
+        file.seek(SeekFrom::Start(pointer.pos))?;
+        let mut reader = BufReader::with_capacity(options.buffer_size, file).take(pointer.len);
+        match record::read_record(&mut reader, options)? {
+            Some((data, _)) => Ok(data),
+            None => Err(KvStoreError::CorruptRecord),
+        }
     }
 }
 
-fn rebuild_index(file: &File, index: &mut SkipMap<String, (u64, u64)>) -> Result<u64> {
-    // deserialize the text in file
-    let mut stream = Deserializer::from_reader(file).into_iter::<Command>();
-    let mut pos = 0;
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set{key, ..} => {
-                index.insert(key, (pos, new_pos - pos));
-            }
-            Command::Rm { key } => {
-                index.remove(&key);
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+}
+
+// namespace and database names become part of file names, so only allow a safe subset of characters
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub(crate) fn log_name(namespace: &str, gen: u64) -> String {
+    format!("{}.{}.log", namespace, gen)
+}
+
+// list every `<namespace>.<gen>.log` file in dir
+pub(crate) fn list_generations(dir: &Path) -> Result<Vec<(String, u64)>> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        let parsed = file_name.strip_suffix(".log")
+            .and_then(|stem| stem.rsplit_once('.'))
+            .and_then(|(name, gen)| gen.parse::<u64>().ok().map(|gen| (name, gen)));
+        if let Some((name, gen)) = parsed {
+            if valid_name(name) {
+                gens.push((name.to_owned(), gen));
             }
         }
-        pos = new_pos;
     }
-    Ok(pos)
+    Ok(gens)
+}
+
+// the generations of namespace `name` on disk, sorted
+pub(crate) fn generations(dir: &Path, name: &str) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = list_generations(dir)?
+        .into_iter()
+        .filter(|(namespace, _)| namespace == name)
+        .map(|(_, gen)| gen)
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+// stores created before generations have a single kvstore.log, it becomes the first generation.
+// a log written before records were framed holds pretty printed json commands, they are framed
+// into the first generation, which replaces it once it is synced
+fn migrate_legacy_log(dir: &Path, options: &KvStoreOptions) -> Result<()> {
+    let legacy = dir.join(LEGACY_LOG);
+    if !legacy.exists() {
+        return Ok(());
+    }
+    let first = dir.join(log_name(DEFAULT_NAMESPACE, 1));
+    if !is_json_log(&legacy)? {
+        fs::rename(&legacy, &first)?;
+        return sync_dir(dir);
+    }
+    let tmp = dir.join(format!("{}.tmp", LEGACY_LOG));
+    let mut writer = BufWriter::with_capacity(options.buffer_size, create_log(&tmp)?);
+    for command in legacy_commands(&legacy)? {
+        writer.write_all(&encode(&serde_json::to_vec(&command?)?, options)?)?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, &first)?;
+    sync_dir(dir)?;
+    fs::remove_file(&legacy)?;
+    Ok(())
 }
 
+pub(crate) type LegacyCommands = serde_json::StreamDeserializer<'static, serde_json::de::IoRead<BufReader<File>>, Command>;
+
+// the json commands of a legacy kvstore.log, byte_offset tells where the last one ended
+pub(crate) fn legacy_commands(path: &Path) -> Result<LegacyCommands> {
+    Ok(serde_json::Deserializer::from_reader(BufReader::new(File::open(path)?)).into_iter())
+}
+
+// a framed log never starts with a json command
+pub(crate) fn is_json_log(path: &Path) -> Result<bool> {
+    Ok(matches!(legacy_commands(path)?.next(), Some(Ok(_))))
+}
+
+// make renames and removals in dir durable
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn create_log(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?)
+}
+
+// generations are immutable once sealed, so a hard link is a consistent copy
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    match fs::hard_link(src, dest) {
+        Ok(()) => Ok(()),
+        // e.g. the destination is on another file system
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            fs::copy(src, dest)?;
+            Ok(())
+        },
+        Err(e) => Err(e.into()),
+    }
+}
 
 impl KvEngine for KvStore {
     /// insert a key-value pair in KvStore
     fn set(&self, key: String, value: String) -> Result<()> {
-        // construct set command
-        let cmd = Command::Set{ key, value };
-        // serialize the command to a string
-        let write_str = serde_json::to_string_pretty(&cmd)?;
-        // write command into the disk
-        // 问题：这里为什么不能写成self.writer.clone().lock().unwrap()呢
-        let writer = self.writer.clone();
-        let mut writer = writer.lock().unwrap();
-        let len = writer.write(write_str.as_bytes())?;
-        writer.flush()?;
-        // take the command offset in the file as value
-        let offset = self.offset.clone();
-        let mut offset = offset.lock().unwrap();
-        let cmd_offset = *offset;
-        // update the end offset of the file
-        *offset = *offset + len as u64;
-        // update the in-memory index
-        let mut index = self.index.clone();
-        if let Command::Set { key, .. } = cmd {
-            index.insert(key, (cmd_offset, len as u64));
-        }
-        drop(writer);
-        if *offset > COMPACT_THRESHOLD {
-            drop(offset);
-            self.compact()?;
-        }
-        Ok(())
+        self.set_command(Command::Set { key, value, expires: None })
     }
     /// get the value for the given key
     fn get(&self, key: String) -> Result<Option<String>> {
-        // 这根本就没并行起来，这个index在读的时候不能也用Mutex来锁，看看要怎么改呢
-        // get the offset of the latest command corresponds to key
-        let mut index = self.index.clone();
-        let res = index.get(&key);
-        match res {
-            Some(kv) => {
-                let (pos, len) = kv.value();
-                // make the pointer of the file to the command offset
-                let path = self.path.clone();
-                let mut file = File::open((*path).clone())?;
-                file.seek(SeekFrom::Start(*pos))?;
-                let reader = BufReader::new(file).take(*len);
-                // read command from the log file
-                if let Command::Set { value, .. } = serde_json::from_reader(reader)? {
-                    // return the value
-                    return Ok(Some(value));
-                } else {
-                    return Err(KvStoreError::GetNonExistValue);
-                }
-            },
-            None => {Ok(None)},
+        let value = self.lookup(key.clone())?;
+        self.keyspace.counters.get(value.is_some());
+        if let (Some(tracker), Some(_)) = (&self.keyspace.evict, &value) {
+            tracker.read(&key);
         }
-        // Ok(None)
+        Ok(value)
     }
     /// reomve the key-value pair with given key
     fn remove(&self, key: String) -> Result<()> {
-        let mut index = self.index.clone();
-        if index.contains_key(&key) {
-            // construct remove command
-            let cmd = Command::Rm { key };
-            // serialize the command to a string
-            let write_str = serde_json::to_string_pretty(&cmd)?;
-            // write command into the disk
-            let writer = self.writer.clone();
-            let mut writer = writer.lock().unwrap();
-            let len = writer.write(write_str.as_bytes())?;
-            writer.flush()?;
-            // update the end offset of the file
-            let offset = self.offset.clone();
-            let mut offset = offset.lock().unwrap();
-            *offset += len as u64;
-            // update the in-memory index
-            if let Command::Rm { key } = cmd {
-                index.remove(&key);
-            }
-            if *offset > COMPACT_THRESHOLD {
-                self.compact()?;
+        if self.options.read_only {
+            return Err(KvStoreError::ReadOnly);
+        }
+        // construct remove command, write it into the disk and update the in-memory index
+        match self.append(Command::Rm { key }) {
+            Err(KvStoreError::RemoveNonExistKey) => {
+                self.keyspace.counters.remove(false);
+                return Err(KvStoreError::RemoveNonExistKey);
+            },
+            res => res?,
+        };
+        self.keyspace.counters.remove(true);
+        if self.need_compact() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+    /// insert a key-value pair which expires after ttl
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        KvStore::set_with_ttl(self, key, value, ttl)
+    }
+    /// the log has no batch records, a crash could leave only some of the pairs written
+    fn set_batch(&self, _pairs: Vec<(String, String)>) -> Result<()> {
+        Err(KvStoreError::Unsupported(String::from("the kvs engine does not support atomic batches")))
+    }
+    /// get a handle to another namespace of the store
+    fn namespace(&self, name: &str) -> Result<KvStore> {
+        KvStore::namespace(self, name)
+    }
+    /// write a consistent copy of the store into dest
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        KvStore::checkpoint(self, dest)
+    }
+    /// flush the writer of every namespace and sync its active generation
+    fn flush(&self) -> Result<()> {
+        let keyspaces: Vec<Arc<Keyspace>> = self.namespaces.lock().unwrap().values().cloned().collect();
+        for keyspace in keyspaces {
+            let mut writer = keyspace.writer.lock().unwrap();
+            if let Some(log) = writer.writer.as_mut() {
+                log.flush()?;
+                log.get_ref().sync_data()?;
+                writer.last_sync = Instant::now();
             }
-            return Ok(());
-        } else {
-            return Err(KvStoreError::RemoveNonExistKey);
         }
         Ok(())
-   }
-}
\ No newline at end of file
+    }
+    /// iterate over the live pairs of the namespace whose key starts with prefix
+    fn scan(&self, prefix: &str) -> Result<Scan> {
+        Ok(Box::new(KvStoreScan { store: self.clone(), prefix: prefix.to_owned(), last: None }))
+    }
+    /// names of every namespace in the store directory
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.namespaces.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+    /// the keys whose value has `value` at the path of the secondary index
+    fn query_index(&self, index: &str, value: &str) -> Result<Vec<String>> {
+        let index = self.keyspace.indexes.iter()
+            .find(|found| found.spec.name == index)
+            .ok_or_else(|| KvStoreError::UnknownIndex(index.to_owned()))?;
+        Ok(index.query(value, now_millis()))
+    }
+    /// always kvs
+    fn engine_type(&self) -> EngineType {
+        EngineType::Kvs
+    }
+    /// statistics of the namespace
+    fn stats(&self) -> Result<EngineStats> {
+        let keyspace = &self.keyspace;
+        let mut stats = EngineStats {
+            files: keyspace.generations()?.len() as u64,
+            ..EngineStats::default()
+        };
+        {
+            let writer = keyspace.writer.lock().unwrap();
+            stats.dead_bytes = writer.stale;
+            stats.compactions = writer.compactions;
+            stats.evictions = writer.evictions;
+            if let Some((finished, duration)) = writer.last_compaction {
+                stats.last_compaction = finished.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs());
+                stats.last_compaction_ms = Some(duration.as_millis() as u64);
+            }
+        }
+        for entry in keyspace.index.iter() {
+            stats.keys += 1;
+            stats.live_bytes += entry.value().len;
+        }
+        keyspace.counters.fill(&mut stats);
+        Ok(stats)
+    }
+}
+
+// walks the index one key at a time, so the scan does not hold any lock between items
+struct KvStoreScan {
+    store: KvStore,
+    prefix: String,
+    // the last key returned
+    last: Option<String>,
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = {
+                let index = &self.store.keyspace.index;
+                let entry = match self.last {
+                    Some(ref last) => index.lower_bound(Bound::Excluded(last)),
+                    None => index.lower_bound(Bound::Included(&self.prefix)),
+                }?;
+                entry.key().clone()
+            };
+            if !key.starts_with(&self.prefix) {
+                return None;
+            }
+            self.last = Some(key.clone());
+            match self.store.lookup(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // removed since the index lookup
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -1,18 +1,56 @@
 #![deny(missing_docs)]
 //! This is a simple key-value store
 
-pub use kvstore::KvStore;
+pub use kvstore::{KvStore, DEFAULT_NAMESPACE};
 pub use error::{KvStoreError, Result};
 pub use client::KvClient;
 pub use server::KvServer;
-pub use common::Request;
-pub use kvengine::KvEngine;
+pub use shutdown::ShutdownHandle;
+pub use async_client::AsyncKvClient;
+pub use async_server::AsyncKvServer;
+pub use common::{Request, Response, ErrorCode, KeyResult};
+pub use protocol::{Protocol, Handshake, PROTOCOL_VERSION};
+pub use kvengine::{KvEngine, Scan, AnyEngine, EngineType, load_engine_meta, DEFAULT_ENGINE, ENGINE_META_PATH};
+pub use sled_engine::SledKvsEngine;
+pub use stats::{EngineStats, OpStats};
+pub use export::{export, import, ExportFormat, ValueEncoding};
+pub use migrate::{migrate_engine, MigrationReport};
+pub use verify::{verify_store, repair_store, VerifyReport, RepairReport, CorruptRange, REPAIR_REPORT_PATH};
+pub use dump::{dump_store, dump_stats, DumpFilter, DumpKind, DumpRecord, PrefixStats};
+pub use checkpoint::{restore_checkpoint, verify_checkpoint, MANIFEST_PATH};
+pub use databases::{Databases, DbInfo};
+pub use options::{KvStoreOptions, Compression, CompactionPolicy, SyncPolicy, EvictionPolicy};
+pub use crypto::DataKey;
+pub use json_index::IndexSpec;
+pub use lock::LOCK_PATH;
 
 mod client;
 mod server;
+mod shutdown;
+mod limits;
+mod async_client;
+mod async_server;
 mod kvstore;
 mod error;
 mod common;
+mod protocol;
+mod resp;
+mod http;
 mod kvengine;
+mod sled_engine;
+mod databases;
+mod checkpoint;
+mod export;
+mod migrate;
+mod verify;
+mod dump;
+mod options;
+mod record;
+mod crypto;
+mod lock;
+mod cache;
+mod evict;
+mod json_index;
+mod stats;
 /// a trait to provide threadpool
 pub mod thread_pool;
\ No newline at end of file
@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::protocol::MAX_FRAME_LEN;
+
+// a connection over the limit gets this long to send its first request and read the refusal
+pub(crate) const REJECT_WAIT: Duration = Duration::from_secs(1);
+
+// connections over the limit which are refused at once, more are closed without an answer
+pub(crate) const MAX_REJECTING: usize = 16;
+
+// the limits of the connections of a server, shared by the threaded and the async server
+#[derive(Clone, Copy)]
+pub(crate) struct Limits {
+    // open connections at most, more are refused with Busy
+    pub(crate) max_connections: Option<usize>,
+    // the time a request may take to arrive once its first byte did
+    pub(crate) read_timeout: Option<Duration>,
+    // the time writing a response may block
+    pub(crate) write_timeout: Option<Duration>,
+    // the time a connection may wait between requests before it is closed
+    pub(crate) idle_timeout: Option<Duration>,
+    // bytes of a request at most
+    pub(crate) max_request_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { max_connections: None, read_timeout: None, write_timeout: None, idle_timeout: None, max_request_size: MAX_FRAME_LEN as usize }
+    }
+}
+
+// a zero timeout turns it off, sockets refuse it
+pub(crate) fn timeout(timeout: Duration) -> Option<Duration> {
+    Some(timeout).filter(|timeout| !timeout.is_zero())
+}
+
+// counts the connections holding a slot
+#[derive(Default)]
+pub(crate) struct Slots {
+    taken: AtomicUsize,
+}
+
+// a taken slot, given back when dropped
+pub(crate) struct Slot(Arc<Slots>);
+
+impl Slots {
+    // take a slot if fewer than max are taken
+    pub(crate) fn take(slots: &Arc<Slots>, max: Option<usize>) -> Option<Slot> {
+        let max = max.unwrap_or(usize::MAX);
+        slots.taken
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |taken| if taken < max { Some(taken + 1) } else { None })
+            .ok()
+            .map(|_| Slot(slots.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.taken.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,74 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{KvStoreError, Result};
+
+/// the lock file in the data directory, it holds the pid of the process which has the store open
+pub const LOCK_PATH: &str = "LOCK";
+
+// an advisory lock on a data directory, released when dropped
+pub(crate) struct DirLock {
+    // None if a read-only handle found no lock file to lock
+    file: Option<File>,
+    exclusive: bool,
+}
+
+impl DirLock {
+    // lock dir for a single writer, fail with DirectoryLocked if anyone else holds the lock
+    pub(crate) fn exclusive(dir: &Path) -> Result<DirLock> {
+        DirLock::exclusive_file(&dir.join(LOCK_PATH))
+    }
+
+    // like exclusive, with the lock file at path instead of inside a data directory
+    pub(crate) fn exclusive_file(path: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => return Err(KvStoreError::DirectoryLocked(holder(&mut file))),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        // tell whoever finds the directory locked who holds it
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(DirLock { file: Some(file), exclusive: true })
+    }
+
+    // lock dir for readers, which only fails while a writer holds the lock.
+    // nothing is created, a directory without lock file, e.g. a copied backup, is not locked at all
+    pub(crate) fn shared(dir: &Path) -> Result<DirLock> {
+        fs::metadata(dir)?;
+        let mut file = match File::open(dir.join(LOCK_PATH)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DirLock { file: None, exclusive: false }),
+            Err(e) => return Err(e.into()),
+        };
+        match file.try_lock_shared() {
+            Ok(()) => Ok(DirLock { file: Some(file), exclusive: false }),
+            Err(TryLockError::WouldBlock) => Err(KvStoreError::DirectoryLocked(holder(&mut file))),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Some(ref file) = self.file {
+            // readers do not write their pid, so do not leave ours behind for them
+            if self.exclusive {
+                let _ = file.set_len(0);
+            }
+            let _ = file.unlock();
+        }
+    }
+}
+
+// the pid written by the holder, 0 if it has not been written yet or the holders are readers
+fn holder(file: &mut File) -> u32 {
+    let mut pid = String::new();
+    if file.seek(SeekFrom::Start(0)).is_err() || file.read_to_string(&mut pid).is_err() {
+        return 0;
+    }
+    pid.trim().parse().unwrap_or(0)
+}
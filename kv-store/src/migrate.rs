@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+
+use crate::{AnyEngine, EngineType, KvEngine, KvStore, KvStoreError, KvStoreOptions, Result, ENGINE_META_PATH, LOCK_PATH};
+use crate::kvstore::{list_generations, log_name, LEGACY_LOG};
+use crate::lock::DirLock;
+
+// where the new engine is built before it is moved into the data directory
+const STAGING_DIR: &str = "migrate.tmp";
+
+// files and directories sled keeps in its data directory
+const SLED_FILES: &[&str] = &["conf", "db", "blobs"];
+const SLED_SNAPSHOT_PREFIX: &str = "snap.";
+
+/// what an engine migration copied
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    /// the engine the directory used before
+    pub from: EngineType,
+    /// the engine the directory uses now
+    pub to: EngineType,
+    /// every namespace with the number of pairs copied
+    pub namespaces: Vec<(String, u64)>,
+}
+
+/// copy every namespace of the store in dir into a new engine of type `to`, verify the copy and
+/// switch engine.meta to it. the directory is locked during the migration, it fails if anyone has it open.
+/// if anything fails before engine.meta is rewritten, the directory still uses the old engine
+pub fn migrate_engine(dir: impl AsRef<Path>, to: EngineType, options: KvStoreOptions) -> Result<MigrationReport> {
+    let dir = dir.as_ref();
+    let meta_path = dir.join(ENGINE_META_PATH);
+    if !meta_path.exists() {
+        return Err(KvStoreError::StringErr(format!("{} has no {}", dir.display(), ENGINE_META_PATH)));
+    }
+    let from: EngineType = fs::read_to_string(&meta_path)?.parse().map_err(KvStoreError::StringErr)?;
+    if from == to {
+        return Err(KvStoreError::StringErr(format!("{} already uses {}", dir.display(), to)));
+    }
+
+    // nobody may open the store until engine.meta points at the copy
+    let lock = Arc::new(DirLock::exclusive(dir)?);
+
+    let staging = dir.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+
+    // copy every namespace, remember how many pairs and their checksum
+    let source = match from {
+        EngineType::Kvs => AnyEngine::Kvs(KvStore::open_locked(dir.to_path_buf(), options.clone(), lock.clone())?),
+        EngineType::Sled => AnyEngine::open(dir, from, options.clone())?,
+    };
+    let mut expected = Vec::new();
+    {
+        let target = AnyEngine::open(&staging, to, options.clone())?;
+        for name in source.namespaces()? {
+            let source_ns = source.namespace(&name)?;
+            let target_ns = target.namespace(&name)?;
+            let mut count = 0;
+            let mut hasher = crc32fast::Hasher::new();
+            for pair in source_ns.scan("")? {
+                let (key, value) = pair?;
+                hash_pair(&mut hasher, &key, &value);
+                target_ns.set(key, value)?;
+                count += 1;
+            }
+            info!("copied {} pairs of namespace {}", count, name);
+            expected.push((name, count, hasher.finalize()));
+        }
+    }
+    drop(source);
+
+    // reopen the copy and compare it with the source
+    {
+        let target = AnyEngine::open(&staging, to, options)?;
+        for (name, count, crc) in &expected {
+            let mut copied = 0;
+            let mut hasher = crc32fast::Hasher::new();
+            for pair in target.namespace(name)?.scan("")? {
+                let (key, value) = pair?;
+                hash_pair(&mut hasher, &key, &value);
+                copied += 1;
+            }
+            if copied != *count || hasher.finalize() != *crc {
+                fs::remove_dir_all(&staging)?;
+                return Err(KvStoreError::ChecksumMismatch(format!("namespace {}", name)));
+            }
+        }
+    }
+
+    // leftovers of an earlier use of the new engine are replaced by the copy
+    remove_engine_files(dir, to)?;
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        if entry.file_name() != ENGINE_META_PATH && entry.file_name() != LOCK_PATH {
+            fs::rename(entry.path(), dir.join(entry.file_name()))?;
+        }
+    }
+    fs::remove_dir_all(&staging)?;
+
+    // switch the directory to the new engine in one rename
+    let tmp_meta = dir.join(format!("{}.tmp", ENGINE_META_PATH));
+    fs::write(&tmp_meta, to.to_string())?;
+    fs::rename(&tmp_meta, &meta_path)?;
+    info!("switched {} from {} to {}", dir.display(), from, to);
+
+    remove_engine_files(dir, from)?;
+    let namespaces = expected.into_iter().map(|(name, count, _)| (name, count)).collect();
+    Ok(MigrationReport { from, to, namespaces })
+}
+
+fn hash_pair(hasher: &mut crc32fast::Hasher, key: &str, value: &str) {
+    // lengths keep ("ab", "c") and ("a", "bc") apart
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(&(value.len() as u64).to_le_bytes());
+    hasher.update(value.as_bytes());
+}
+
+// delete the data files engine keeps in dir
+fn remove_engine_files(dir: &Path, engine: EngineType) -> Result<()> {
+    match engine {
+        EngineType::Kvs => {
+            for (name, gen) in list_generations(dir)? {
+                fs::remove_file(dir.join(log_name(&name, gen)))?;
+            }
+            let legacy = dir.join(LEGACY_LOG);
+            if legacy.exists() {
+                fs::remove_file(legacy)?;
+            }
+        },
+        EngineType::Sled => {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if SLED_FILES.contains(&name.as_str()) || name.starts_with(SLED_SNAPSHOT_PREFIX) {
+                    if entry.file_type()?.is_dir() {
+                        fs::remove_dir_all(entry.path())?;
+                    } else {
+                        fs::remove_file(entry.path())?;
+                    }
+                }
+            }
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,400 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{DataKey, IndexSpec, KvStoreError, Result};
+
+/// compression algorithm used for the records written to the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// store records as they are
+    None,
+    /// compress records with lz4
+    Lz4,
+    /// compress records with zstd
+    Zstd,
+}
+
+/// when a namespace compacts its log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionPolicy {
+    /// compact once overwritten and removed records take more than the given number of bytes
+    StaleBytes(u64),
+    /// only compact when `KvStore::compact` is called
+    Manual,
+}
+
+/// when appended records are synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// only flush records to the operating system, which writes them back when it likes
+    Never,
+    /// sync the log after every write
+    Always,
+    /// sync on a write once the given time has passed since the last sync
+    Interval(Duration),
+}
+
+/// which key a full namespace drops to make room for a write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// never drop keys, writes fail with `KvStoreError::StoreFull` instead
+    None,
+    /// drop the least recently read or written key
+    Lru,
+    /// drop the least frequently read or written key
+    Lfu,
+    /// drop a random key
+    Random,
+    /// drop the key which expires first, keys without ttl are dropped last in lru order
+    TtlFirst,
+}
+
+/// options used to open a KvStore
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) compression: Compression,
+    pub(crate) compression_threshold: usize,
+    pub(crate) encryption_key: Option<DataKey>,
+    pub(crate) old_keys: Vec<DataKey>,
+    pub(crate) compaction: CompactionPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) cache_size: usize,
+    pub(crate) buffer_size: usize,
+    pub(crate) max_bytes: Option<u64>,
+    pub(crate) max_keys: Option<u64>,
+    pub(crate) eviction: EvictionPolicy,
+    pub(crate) indexes: Vec<IndexSpec>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compression: Compression::None,
+            compression_threshold: 256,
+            encryption_key: None,
+            old_keys: Vec::new(),
+            compaction: CompactionPolicy::StaleBytes(1024 * 1024),
+            read_only: false,
+            sync: SyncPolicy::Never,
+            max_file_size: None,
+            cache_size: 0,
+            buffer_size: 8 * 1024,
+            max_bytes: None,
+            max_keys: None,
+            eviction: EvictionPolicy::None,
+            indexes: Vec::new(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// create the default options
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// set the compression algorithm for newly written records,
+    /// records written with other algorithms can still be read
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// records smaller than `threshold` bytes are never compressed
+    pub fn compression_threshold(mut self, threshold: usize) -> KvStoreOptions {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// encrypt newly written records with `key`
+    pub fn encryption_key(mut self, key: DataKey) -> KvStoreOptions {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// add a retired key that is only used to read old records,
+    /// compaction rewrites them with the current encryption key
+    pub fn old_key(mut self, key: DataKey) -> KvStoreOptions {
+        self.old_keys.push(key);
+        self
+    }
+
+    /// set the compaction policy of the default namespace,
+    /// also used by namespaces opened without their own policy
+    pub fn compaction(mut self, policy: CompactionPolicy) -> KvStoreOptions {
+        self.compaction = policy;
+        self
+    }
+
+    /// open the store read-only, see `KvStore::open_read_only`
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// when appended records are synced to disk
+    pub fn sync(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync = policy;
+        self
+    }
+
+    /// start a new generation once the active one grows past `bytes`,
+    /// compaction still writes all live records of a namespace into one generation
+    pub fn max_file_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// keep up to `bytes` of recently read keys and values in memory, 0 disables the cache.
+    /// sled uses it as the size of its page cache
+    pub fn cache_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// capacity of the buffers used to read and write the log
+    pub fn buffer_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.buffer_size = bytes;
+        self
+    }
+
+    /// limit the bytes of the live records of every namespace, sled ignores the limits
+    pub fn max_bytes(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// limit the number of keys of every namespace
+    pub fn max_keys(mut self, keys: u64) -> KvStoreOptions {
+        self.max_keys = Some(keys);
+        self
+    }
+
+    /// which keys are removed once a namespace reaches `max_bytes` or `max_keys`
+    pub fn eviction(mut self, policy: EvictionPolicy) -> KvStoreOptions {
+        self.eviction = policy;
+        self
+    }
+
+    /// keep a secondary index in every namespace, replaces a declared index with the same name.
+    /// kvs rebuilds it when the store is opened, sled answers queries by scanning the prefix
+    pub fn index(mut self, spec: IndexSpec) -> KvStoreOptions {
+        self.indexes.retain(|index| index.name != spec.name);
+        self.indexes.push(spec);
+        self
+    }
+
+    /// whether the store is opened read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// parse options from a toml config, e.g.
+    ///
+    /// ```toml
+    /// compression = "zstd"
+    /// compaction = "manual"    # or the stale bytes which trigger compaction
+    /// sync = "100ms"           # "never", "always" or an interval in ms
+    /// max_file_size = 67108864
+    /// cache_size = 16777216
+    /// max_bytes = 1073741824
+    /// eviction = "lru"         # "none", "lru", "lfu", "random" or "ttl"
+    /// key_file = "data.key"
+    ///
+    /// [[index]]
+    /// name = "email"
+    /// prefix = "user:"
+    /// path = "$.email"
+    /// ```
+    ///
+    /// relative key files are resolved against base_dir
+    pub fn from_toml(text: &str, base_dir: &Path) -> Result<KvStoreOptions> {
+        let config: OptionsFile = toml::from_str(text).map_err(|e| KvStoreError::StringErr(format!("invalid config: {}", e)))?;
+        let mut options = KvStoreOptions::new();
+        if let Some(ref compression) = config.compression {
+            options = options.compression(compression.parse().map_err(KvStoreError::StringErr)?);
+        }
+        if let Some(threshold) = config.compression_threshold {
+            options = options.compression_threshold(threshold);
+        }
+        if let Some(ref compaction) = config.compaction {
+            options = options.compaction(compaction.parse().map_err(KvStoreError::StringErr)?);
+        }
+        if let Some(ref sync) = config.sync {
+            options = options.sync(sync.parse().map_err(KvStoreError::StringErr)?);
+        }
+        if let Some(bytes) = config.max_file_size {
+            options = options.max_file_size(bytes);
+        }
+        if let Some(bytes) = config.cache_size {
+            options = options.cache_size(bytes);
+        }
+        if let Some(bytes) = config.buffer_size {
+            options = options.buffer_size(bytes);
+        }
+        if let Some(bytes) = config.max_bytes {
+            options = options.max_bytes(bytes);
+        }
+        if let Some(keys) = config.max_keys {
+            options = options.max_keys(keys);
+        }
+        if let Some(ref eviction) = config.eviction {
+            options = options.eviction(eviction.parse().map_err(KvStoreError::StringErr)?);
+        }
+        if let Some(read_only) = config.read_only {
+            options = options.read_only(read_only);
+        }
+        if let Some(ref key_file) = config.key_file {
+            options = options.encryption_key(DataKey::from_file(base_dir.join(key_file))?);
+        }
+        for index in &config.index {
+            options = options.index(IndexSpec::new(&index.name, &index.prefix, &index.path)?);
+        }
+        for key_file in &config.old_key_files {
+            options = options.old_key(DataKey::from_file(base_dir.join(key_file))?);
+        }
+        Ok(options)
+    }
+
+    /// read options from a toml config file, see `from_toml`
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<KvStoreOptions> {
+        let path = path.as_ref();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        KvStoreOptions::from_toml(&fs::read_to_string(path)?, base_dir)
+    }
+}
+
+// the layout of a toml config, every field is optional
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OptionsFile {
+    compression: Option<String>,
+    compression_threshold: Option<usize>,
+    compaction: Option<String>,
+    sync: Option<String>,
+    max_file_size: Option<u64>,
+    cache_size: Option<usize>,
+    buffer_size: Option<usize>,
+    max_bytes: Option<u64>,
+    max_keys: Option<u64>,
+    eviction: Option<String>,
+    read_only: Option<bool>,
+    key_file: Option<PathBuf>,
+    #[serde(default)]
+    old_key_files: Vec<PathBuf>,
+    #[serde(default)]
+    index: Vec<IndexFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndexFile {
+    name: String,
+    #[serde(default)]
+    prefix: String,
+    path: String,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Compression, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// "manual" or the number of stale bytes
+impl FromStr for CompactionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CompactionPolicy, String> {
+        if s.eq_ignore_ascii_case("manual") {
+            return Ok(CompactionPolicy::Manual);
+        }
+        s.parse().map(CompactionPolicy::StaleBytes).map_err(|_| format!("unknown compaction policy: {}", s))
+    }
+}
+
+impl fmt::Display for CompactionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionPolicy::StaleBytes(bytes) => write!(f, "{}", bytes),
+            CompactionPolicy::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+// "never", "always" or an interval like "100ms"
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SyncPolicy, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            other => other.strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| format!("unknown sync policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<EvictionPolicy, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(EvictionPolicy::None),
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            "random" => Ok(EvictionPolicy::Random),
+            "ttl" => Ok(EvictionPolicy::TtlFirst),
+            _ => Err(format!("unknown eviction policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionPolicy::None => write!(f, "none"),
+            EvictionPolicy::Lru => write!(f, "lru"),
+            EvictionPolicy::Lfu => write!(f, "lfu"),
+            EvictionPolicy::Random => write!(f, "random"),
+            EvictionPolicy::TtlFirst => write!(f, "ttl"),
+        }
+    }
+}
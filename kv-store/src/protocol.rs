@@ -0,0 +1,347 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{KvStoreError, Request, Result};
+
+/// the version of the request and response format, raised on every incompatible change
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// the oldest client version the server still understands, version 1 had no error codes
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 2;
+
+// the optional features the server can turn on for a connection
+pub(crate) const SERVER_FEATURES: &[&str] = &[];
+
+// a binary client sends these bytes before its first frame. json requests start with `{` or `"`,
+// so the server can tell the protocols apart by the first byte of a connection
+pub(crate) const BINARY_MAGIC: &[u8; 4] = b"KVSB";
+
+// the opcode of the reply to a frame the server could not decode, its payload is an error response
+pub(crate) const OP_ERROR: u8 = 0xff;
+
+// frames larger than this are refused instead of allocated, also the default request size limit of a server
+pub(crate) const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// what the server answered to a Hello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// the protocol version of the server
+    pub version: u32,
+    /// the engine the server runs, kvs or sled
+    pub engine: String,
+    /// the requested features the server turned on
+    pub features: Vec<String>,
+}
+
+/// the wire protocol a client talks to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// requests and responses are json documents written back to back
+    Json,
+    /// length-prefixed frames with an opcode, a request id and a bincode payload
+    Binary,
+}
+
+// a frame of the binary protocol:
+// | len: u32 | opcode: u8 | id: u32 | payload |
+// all integers are big endian, len counts the bytes after itself.
+// a reply carries the opcode and id of its request, so a frame with a broken payload
+// only fails that request and the stream stays in sync
+pub(crate) struct Frame {
+    pub(crate) opcode: u8,
+    pub(crate) id: u32,
+    pub(crate) payload: Vec<u8>,
+}
+
+// bytes of opcode and id
+const FRAME_HEADER_LEN: u32 = 5;
+
+// read the next frame, None if the peer closed the connection between frames
+pub(crate) fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>> {
+    let (opcode, id, len) = match read_head(reader)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame { opcode, id, payload }))
+}
+
+// like read_frame, for the async server and client
+pub(crate) async fn read_frame_async(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<Frame>> {
+    let (opcode, id, len) = match read_head_async(reader).await? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame { opcode, id, payload }))
+}
+
+// read the next request frame of a server, with its opcode and id. a payload longer than limit
+// is skipped and the request fails with TooLarge, so the stream stays in sync
+pub(crate) fn read_request(reader: &mut impl Read, limit: usize) -> Result<Option<(u8, u32, Result<Request>)>> {
+    let (opcode, id, len) = match read_head(reader)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    if len > limit {
+        io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+        return Ok(Some((opcode, id, Err(too_large(len, limit)))));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((opcode, id, decode_request(opcode, &payload))))
+}
+
+// like read_request, for the async server
+pub(crate) async fn read_request_async(reader: &mut (impl AsyncRead + Unpin), limit: usize) -> Result<Option<(u8, u32, Result<Request>)>> {
+    let (opcode, id, len) = match read_head_async(reader).await? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    if len > limit {
+        tokio::io::copy(&mut reader.take(len as u64), &mut tokio::io::sink()).await?;
+        return Ok(Some((opcode, id, Err(too_large(len, limit)))));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some((opcode, id, decode_request(opcode, &payload))))
+}
+
+// opcode, id and payload length of the next frame
+fn read_head(reader: &mut impl Read) -> Result<Option<(u8, u32, usize)>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = frame_len(len)?;
+    let mut header = [0; FRAME_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    Ok(Some(parse_header(header, len)))
+}
+
+async fn read_head_async(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<(u8, u32, usize)>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = frame_len(len)?;
+    let mut header = [0; FRAME_HEADER_LEN as usize];
+    reader.read_exact(&mut header).await?;
+    Ok(Some(parse_header(header, len)))
+}
+
+// the bytes after the length of a frame
+fn frame_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvStoreError::TooLarge(format!("frame of {} bytes is too large", len)));
+    }
+    if len < FRAME_HEADER_LEN {
+        return Err(KvStoreError::Protocol(format!("invalid frame length {}", len)));
+    }
+    Ok(len as usize)
+}
+
+// opcode, id and the length of the payload after them
+fn parse_header(header: [u8; FRAME_HEADER_LEN as usize], len: usize) -> (u8, u32, usize) {
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    (header[0], id, len - FRAME_HEADER_LEN as usize)
+}
+
+fn too_large(len: usize, limit: usize) -> KvStoreError {
+    KvStoreError::TooLarge(format!("request of {} bytes exceeds the limit of {} bytes", len, limit))
+}
+
+pub(crate) fn write_frame(writer: &mut impl Write, frame: &Frame) -> Result<()> {
+    let len = FRAME_HEADER_LEN as usize + frame.payload.len();
+    if len > MAX_FRAME_LEN as usize {
+        return Err(KvStoreError::TooLarge(format!("frame of {} bytes is too large", len)));
+    }
+    writer.write_all(&(len as u32).to_be_bytes())?;
+    writer.write_all(&[frame.opcode])?;
+    writer.write_all(&frame.id.to_be_bytes())?;
+    writer.write_all(&frame.payload)?;
+    Ok(())
+}
+
+pub(crate) async fn write_frame_async(writer: &mut (impl AsyncWrite + Unpin), frame: &Frame) -> Result<()> {
+    let mut buf = Vec::with_capacity(4 + FRAME_HEADER_LEN as usize + frame.payload.len());
+    write_frame(&mut buf, frame)?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// the bytes of a json stream read ahead, and how far they are scanned for the end of the
+/// next document so every byte is looked at once
+#[derive(Default)]
+pub(crate) struct JsonBuf {
+    bytes: Vec<u8>,
+    // bytes before it are scanned
+    scanned: usize,
+    // objects and arrays open at scanned
+    depth: usize,
+    in_string: bool,
+    // the byte before scanned is a backslash inside a string
+    escaped: bool,
+}
+
+impl JsonBuf {
+    // nothing but whitespace read ahead
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.iter().all(u8::is_ascii_whitespace)
+    }
+
+    // scan the bytes read since the last call, return the length of the first document once
+    // it is complete. requests and responses are objects or the names of unit variants, so a
+    // document has to be an object, an array or a string
+    fn document_end(&mut self) -> Result<Option<usize>> {
+        while self.scanned < self.bytes.len() {
+            let byte = self.bytes[self.scanned];
+            self.scanned += 1;
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return Ok(Some(self.scanned));
+                        }
+                    },
+                    _ => {},
+                }
+                continue;
+            }
+            match byte {
+                b'{' | b'[' => self.depth += 1,
+                b'"' => self.in_string = true,
+                _ if byte.is_ascii_whitespace() => {},
+                _ if self.depth == 0 => return Err(KvStoreError::Protocol(String::from("a json document has to be an object, an array or a string"))),
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Ok(Some(self.scanned));
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(None)
+    }
+}
+
+// read the next json document of a stream into buf and take it out, None if the peer closed
+// the connection between documents. a document longer than limit fails with TooLarge
+pub(crate) fn read_json<T: DeserializeOwned>(reader: &mut impl Read, buf: &mut JsonBuf, limit: usize) -> Result<Option<T>> {
+    loop {
+        if let Some(value) = take_json(buf, limit)? {
+            return Ok(Some(value));
+        }
+        let mut chunk = [0; 8 * 1024];
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return closed_json(buf);
+        }
+        buf.bytes.extend_from_slice(&chunk[..read]);
+    }
+}
+
+// like read_json, for the async server and client
+pub(crate) async fn read_json_async<T: DeserializeOwned>(reader: &mut (impl AsyncRead + Unpin), buf: &mut JsonBuf, limit: usize) -> Result<Option<T>> {
+    loop {
+        if let Some(value) = take_json(buf, limit)? {
+            return Ok(Some(value));
+        }
+        let mut chunk = [0; 8 * 1024];
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return closed_json(buf);
+        }
+        buf.bytes.extend_from_slice(&chunk[..read]);
+    }
+}
+
+// the first document of buf, None if buf only holds whitespace or part of a document so far
+fn take_json<T: DeserializeOwned>(buf: &mut JsonBuf, limit: usize) -> Result<Option<T>> {
+    let end = match buf.document_end()? {
+        Some(end) => end,
+        None if buf.bytes.len() > limit => {
+            return Err(KvStoreError::TooLarge(format!("json document of more than {} bytes", limit)));
+        },
+        None => return Ok(None),
+    };
+    let document: Vec<u8> = buf.bytes.drain(..end).collect();
+    buf.scanned = 0;
+    if end > limit {
+        return Err(KvStoreError::TooLarge(format!("json document of {} bytes exceeds the limit of {} bytes", end, limit)));
+    }
+    Ok(Some(serde_json::from_slice(&document)?))
+}
+
+// the peer closed the connection, fine between documents
+fn closed_json<T>(buf: &JsonBuf) -> Result<Option<T>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    Err(KvStoreError::Protocol(String::from("connection closed inside a json document")))
+}
+
+// the opcode of a request frame, the reply uses the same one
+pub(crate) fn opcode(request: &Request) -> u8 {
+    match request {
+        Request::Set { .. } => 1,
+        Request::Get { .. } => 2,
+        Request::Rm { .. } => 3,
+        Request::Select { .. } => 4,
+        Request::Scan { .. } => 5,
+        Request::QueryIndex { .. } => 6,
+        Request::Info { .. } => 7,
+        Request::DbStats => 8,
+        Request::Backup { .. } => 9,
+        Request::Hello { .. } => 10,
+        Request::MGet { .. } => 11,
+        Request::MSet { .. } => 12,
+        Request::MDel { .. } => 13,
+        Request::Deadline { .. } => 14,
+    }
+}
+
+// decode the payload of a request frame and check that it matches the opcode
+fn decode_request(opcode: u8, payload: &[u8]) -> Result<Request> {
+    let request: Request = bincode::deserialize(payload)?;
+    if self::opcode(&request) != opcode {
+        return Err(KvStoreError::Protocol(format!("opcode {} does not match the payload", opcode)));
+    }
+    Ok(request)
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Protocol, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Protocol::Json),
+            "binary" => Ok(Protocol::Binary),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Json => write!(f, "json"),
+            Protocol::Binary => write!(f, "binary"),
+        }
+    }
+}
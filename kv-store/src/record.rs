@@ -0,0 +1,150 @@
+use std::io::{self, Read};
+
+use crate::{KvStoreError, Result, KvStoreOptions, options::Compression};
+
+// every record in the log is framed as
+// | crc32 (4 bytes) | payload length (4 bytes) | flags (1 byte) | payload |
+// the crc covers the flags and the payload, all integers are little endian
+pub(crate) const HEADER_LEN: u64 = 9;
+
+// the payload is compressed with lz4
+const FLAG_LZ4: u8 = 0b0000_0001;
+// the payload is compressed with zstd
+const FLAG_ZSTD: u8 = 0b0000_0010;
+// the payload is encrypted, it starts with the nonce and is compressed before encryption
+const FLAG_ENCRYPTED: u8 = 0b0000_0100;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// encode a serialized command into a framed record
+pub(crate) fn encode(data: &[u8], options: &KvStoreOptions) -> Result<Vec<u8>> {
+    let mut flags = 0;
+    let mut compressed = None;
+    // small records are not worth compressing
+    if data.len() >= options.compression_threshold {
+        match options.compression {
+            Compression::None => {},
+            Compression::Lz4 => {
+                compressed = Some(lz4_flex::compress_prepend_size(data));
+                flags |= FLAG_LZ4;
+            },
+            Compression::Zstd => {
+                compressed = Some(zstd::bulk::compress(data, ZSTD_LEVEL)?);
+                flags |= FLAG_ZSTD;
+            },
+        }
+    }
+    // keep the raw bytes if compression does not help
+    let payload = match compressed {
+        Some(ref bytes) if bytes.len() < data.len() => bytes.as_slice(),
+        _ => {
+            flags = 0;
+            data
+        },
+    };
+    let sealed;
+    let payload = match options.encryption_key {
+        Some(ref key) => {
+            sealed = key.seal(payload)?;
+            flags |= FLAG_ENCRYPTED;
+            sealed.as_slice()
+        },
+        None => payload,
+    };
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[flags]);
+    hasher.update(payload);
+
+    let mut buf = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.push(flags);
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+/// read the next record from reader, return the decoded command bytes and the record length,
+/// return None when reader is at the end of the log
+pub(crate) fn read_record<R: Read>(reader: &mut R, options: &KvStoreOptions) -> Result<Option<(Vec<u8>, u64)>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(KvStoreError::CorruptRecord),
+        _ => {},
+    }
+    let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+    let flags = header[8];
+
+    // the length is not checked yet, so only allocate for the bytes which are really there
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(KvStoreError::CorruptRecord);
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[flags]);
+    hasher.update(&payload);
+    if hasher.finalize() != crc {
+        return Err(KvStoreError::CorruptRecord);
+    }
+
+    let payload = if flags & FLAG_ENCRYPTED != 0 {
+        decrypt(&payload, options)?
+    } else {
+        payload
+    };
+    let data = if flags & FLAG_LZ4 != 0 {
+        lz4_flex::decompress_size_prepended(&payload).map_err(|_| KvStoreError::CorruptRecord)?
+    } else if flags & FLAG_ZSTD != 0 {
+        zstd::stream::decode_all(payload.as_slice())?
+    } else {
+        payload
+    };
+    Ok(Some((data, HEADER_LEN + len)))
+}
+
+/// check the record at the start of bytes without decoding it,
+/// return the record length if the header is complete and the crc matches
+pub(crate) fn frame_len(bytes: &[u8]) -> Option<u64> {
+    if (bytes.len() as u64) < HEADER_LEN {
+        return None;
+    }
+    let crc = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let len = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as u64;
+    if (bytes.len() as u64) < HEADER_LEN + len {
+        return None;
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&bytes[HEADER_LEN as usize - 1..(HEADER_LEN + len) as usize]);
+    if hasher.finalize() != crc {
+        return None;
+    }
+    Some(HEADER_LEN + len)
+}
+
+// try the current key first, then the retired ones
+fn decrypt(payload: &[u8], options: &KvStoreOptions) -> Result<Vec<u8>> {
+    if options.encryption_key.is_none() && options.old_keys.is_empty() {
+        return Err(KvStoreError::MissingEncryptionKey);
+    }
+    options.encryption_key.iter()
+        .chain(options.old_keys.iter())
+        .find_map(|key| key.open(payload))
+        .ok_or(KvStoreError::WrongEncryptionKey)
+}
+
+// like read_exact, but returns how many bytes were read instead of failing at eof
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,493 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::{ErrorCode, KvEngine, KvStoreError, Result, thread_pool::ThreadPool};
+use crate::limits::{Limits, Slots, MAX_REJECTING, REJECT_WAIT};
+use crate::server::wait_request;
+use crate::shutdown::Shutdown;
+
+// bulk strings and lines longer than this are refused
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_LINE_LEN: u64 = 64 * 1024;
+// arrays with more items than this are refused
+const MAX_ARGS: usize = 1024 * 1024;
+
+// the keys a SCAN returns when the client gives no COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// a reply of RESP2
+#[derive(Debug)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    // None is the nil reply
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple(String::from("OK"))
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            // a line break would end the error early
+            Reply::Error(e) => write!(writer, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(s)) => {
+                write!(writer, "${}\r\n", s.len())?;
+                writer.write_all(s.as_bytes())?;
+                writer.write_all(b"\r\n")
+            },
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write(writer)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+// the state of a RESP connection, commands run on the default namespace of the engine
+#[derive(Clone)]
+pub(crate) struct RespSession<E: KvEngine> {
+    engine: E,
+    read_only: bool,
+    auth_token: Option<String>,
+    authenticated: bool,
+    closing: bool,
+    // SET NX/XX, INCR and MSET read before they write, every listener takes this lock for its
+    // writes to the default engine so nothing interleaves with them
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl<E: KvEngine> RespSession<E> {
+    pub(crate) fn new(engine: E, read_only: bool, auth_token: Option<String>, write_lock: Arc<Mutex<()>>) -> RespSession<E> {
+        RespSession { engine, read_only, auth_token, authenticated: false, closing: false, write_lock }
+    }
+}
+
+// accept RESP connections and serve them on pool, every connection gets a copy of session.
+// slots are the connections and the refused connections of the server
+pub(crate) fn serve<E: KvEngine, T: ThreadPool>(
+    listener: &TcpListener,
+    session: RespSession<E>,
+    pool: &T,
+    shutdown: &Arc<Shutdown>,
+    limits: Limits,
+    (connections, rejecting): (&Arc<Slots>, &Arc<Slots>),
+) {
+    for stream in listener.incoming() {
+        if shutdown.is_requested() {
+            break;
+        }
+        let (stream, connection) = match stream.and_then(|stream| Shutdown::register(shutdown, &stream).map(|connection| (stream, connection))) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("fail to accept resp connection: {}", e);
+                continue;
+            },
+        };
+        info!("get resp connection");
+        let slot = match Slots::take(connections, limits.max_connections) {
+            Some(slot) => slot,
+            None => {
+                // refused on a thread of its own, like the connections of the kvs listener
+                match Slots::take(rejecting, Some(MAX_REJECTING)) {
+                    Some(rejecting) => {
+                        warn!("too many connections, refuse a resp connection");
+                        thread::spawn(move || {
+                            if let Err(e) = reject(stream) {
+                                error!("fail to refuse resp connection: {}", e);
+                            }
+                            drop((rejecting, connection));
+                        });
+                    },
+                    None => warn!("too many connections, close a resp connection"),
+                }
+                continue;
+            },
+        };
+        let session = session.clone();
+        pool.spawn(move || {
+            if let Err(e) = serve_connection(session, stream, limits) {
+                error!("fail to serve resp connection: {}", e);
+            }
+            drop((slot, connection));
+        });
+    }
+}
+
+fn serve_connection<E: KvEngine>(mut session: RespSession<E>, stream: TcpStream, limits: Limits) -> Result<()> {
+    stream.set_write_timeout(limits.write_timeout)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let max_bulk_len = MAX_BULK_LEN.min(limits.max_request_size);
+    while wait_request(&mut reader, false, &limits)? {
+        let reply = match read_command(&mut reader, max_bulk_len) {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => session.handle(args),
+            Ok(None) => break,
+            // the stream is out of sync, tell the client and hang up like redis does
+            Err(e) => {
+                session.closing = true;
+                Reply::Error(format!("ERR Protocol error: {}", e))
+            },
+        };
+        reply.write(&mut writer)?;
+        // pipelined commands are answered together
+        if session.closing || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if session.closing {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// read the first command of a client over the connection limit and refuse it like redis does
+fn reject(stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_WAIT))?;
+    stream.set_write_timeout(Some(REJECT_WAIT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    // a command which fails to parse is refused all the same
+    let _ = read_command(&mut reader, MAX_BULK_LEN);
+    Reply::Error(String::from("ERR max number of clients reached")).write(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// read the next command, an array of bulk strings or an inline command as telnet sends it.
+// None if the client closed the connection between commands
+fn read_command(reader: &mut impl BufRead, max_bulk_len: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix('*') {
+        Some(count) => parse_len(count, MAX_ARGS)?,
+        None => return Ok(Some(line.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect())),
+    };
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| KvStoreError::Protocol(String::from("unexpected end of stream")))?;
+        let len = match header.strip_prefix('$') {
+            Some(len) => parse_len(len, max_bulk_len)?,
+            None => return Err(KvStoreError::Protocol(format!("expected '$', got '{}'", header))),
+        };
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(KvStoreError::Protocol(String::from("bulk string is not terminated by CRLF")));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(KvStoreError::Protocol(String::from("line is too long or not terminated")));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+// a negative length counts as empty, like a nil array
+fn parse_len(len: &str, max: usize) -> Result<usize> {
+    let len: i64 = len.parse().map_err(|_| KvStoreError::Protocol(format!("invalid length '{}'", len)))?;
+    if len > max as i64 {
+        return Err(KvStoreError::TooLarge(format!("length {} is too large", len)));
+    }
+    Ok(len.max(0) as usize)
+}
+
+impl<E: KvEngine> RespSession<E> {
+    fn handle(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let args = match args.into_iter().map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() {
+            Ok(args) => args,
+            Err(_) => return Reply::Error(String::from("ERR kvs only stores utf-8 strings")),
+        };
+        let name = args[0].to_ascii_lowercase();
+        let args = &args[1..];
+        if let Some(reply) = check_arity(&name, args.len()) {
+            return reply;
+        }
+        match name.as_str() {
+            "ping" => match args.first() {
+                Some(message) => Reply::Bulk(Some(message.clone())),
+                None => Reply::Simple(String::from("PONG")),
+            },
+            "quit" => {
+                self.closing = true;
+                Reply::ok()
+            },
+            "auth" => self.auth(args.last().unwrap()),
+            _ if self.auth_token.is_some() && !self.authenticated => Reply::Error(String::from("NOAUTH Authentication required.")),
+            "command" => Reply::Array(Vec::new()),
+            "set" | "del" | "mset" | "incr" if self.read_only => error_reply(KvStoreError::ReadOnly),
+            "get" => match self.engine.get(args[0].clone()) {
+                Ok(value) => Reply::Bulk(value),
+                Err(e) => error_reply(e),
+            },
+            "set" => self.set(args).unwrap_or_else(error_reply),
+            "del" => self.count(args, |engine, key| match engine.remove(key) {
+                Ok(()) => Ok(true),
+                Err(KvStoreError::RemoveNonExistKey) => Ok(false),
+                Err(e) => Err(e),
+            }),
+            "exists" => self.count(args, |engine, key| Ok(engine.get(key)?.is_some())),
+            "mget" => {
+                let values: Result<Vec<Reply>> = args.iter().map(|key| self.engine.get(key.clone()).map(Reply::Bulk)).collect();
+                values.map(Reply::Array).unwrap_or_else(error_reply)
+            },
+            "mset" => self.mset(args).unwrap_or_else(error_reply),
+            "incr" => self.incr(&args[0]).unwrap_or_else(error_reply),
+            "keys" => match self.keys(&args[0], 0, usize::MAX) {
+                Ok((keys, _)) => Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect()),
+                Err(e) => error_reply(e),
+            },
+            "scan" => self.scan(args).unwrap_or_else(error_reply),
+            "info" => match self.engine.stats() {
+                Ok(stats) => Reply::Bulk(Some(format!(
+                    "# Server\r\nkvs_version:{}\r\nengine:{}\r\n\r\n# Keyspace\r\ndb0:keys={}\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    self.engine.engine_type(),
+                    stats.keys,
+                ))),
+                Err(e) => error_reply(e),
+            },
+            _ => Reply::Error(format!("ERR unknown command '{}'", args_name(&name))),
+        }
+    }
+
+    // AUTH [username] password, the username is ignored
+    fn auth(&mut self, token: &str) -> Reply {
+        match self.auth_token {
+            None => Reply::Error(String::from("ERR AUTH called without any password configured")),
+            Some(ref expected) if expected == token => {
+                self.authenticated = true;
+                Reply::ok()
+            },
+            Some(_) => Reply::Error(String::from("WRONGPASS invalid password")),
+        }
+    }
+
+    // SET key value [EX seconds | PX milliseconds] [NX | XX]
+    fn set(&self, args: &[String]) -> Result<Reply> {
+        let (mut ttl, mut only_if) = (None, None);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_lowercase().as_str() {
+                unit @ ("ex" | "px") if ttl.is_none() => {
+                    let amount: u64 = match options.next().map(|amount| amount.parse()) {
+                        Some(Ok(amount)) if amount > 0 => amount,
+                        Some(_) => return Ok(Reply::Error(String::from("ERR invalid expire time in 'set' command"))),
+                        None => return Ok(syntax_error()),
+                    };
+                    ttl = Some(if unit == "ex" { Duration::from_secs(amount) } else { Duration::from_millis(amount) });
+                },
+                condition @ ("nx" | "xx") if only_if.is_none() => only_if = Some(condition == "xx"),
+                _ => return Ok(syntax_error()),
+            }
+        }
+        let (key, value) = (args[0].clone(), args[1].clone());
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(must_exist) = only_if {
+            if self.engine.get(key.clone())?.is_some() != must_exist {
+                return Ok(Reply::Bulk(None));
+            }
+        }
+        match ttl {
+            Some(ttl) => self.engine.set_with_ttl(key, value, ttl)?,
+            None => self.engine.set(key, value)?,
+        }
+        Ok(Reply::ok())
+    }
+
+    // MSET is atomic if the engine has batches, otherwise the pairs are set one by one
+    fn mset(&self, args: &[String]) -> Result<Reply> {
+        let pairs: Vec<(String, String)> = args.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        match self.engine.set_batch(pairs.clone()) {
+            Err(KvStoreError::Unsupported(_)) => {
+                for (key, value) in pairs {
+                    self.engine.set(key, value)?;
+                }
+            },
+            res => res?,
+        }
+        Ok(Reply::ok())
+    }
+
+    fn incr(&self, key: &str) -> Result<Reply> {
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let current = match self.engine.get(key.to_owned())? {
+            Some(value) => match value.parse::<i64>() {
+                Ok(n) => n,
+                Err(_) => return Ok(not_an_integer()),
+            },
+            None => 0,
+        };
+        let next = match current.checked_add(1) {
+            Some(next) => next,
+            None => return Ok(not_an_integer()),
+        };
+        self.engine.set(key.to_owned(), next.to_string())?;
+        Ok(Reply::Integer(next))
+    }
+
+    // the number of keys f returns true for
+    fn count(&self, keys: &[String], f: impl Fn(&E, String) -> Result<bool>) -> Reply {
+        let mut count = 0;
+        for key in keys {
+            match f(&self.engine, key.clone()) {
+                Ok(true) => count += 1,
+                Ok(false) => {},
+                Err(e) => return error_reply(e),
+            }
+        }
+        Reply::Integer(count)
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count], the cursor is the number of keys visited
+    fn scan(&self, args: &[String]) -> Result<Reply> {
+        let cursor: usize = match args[0].parse() {
+            Ok(cursor) => cursor,
+            Err(_) => return Ok(Reply::Error(String::from("ERR invalid cursor"))),
+        };
+        let (mut pattern, mut count) = ("*", DEFAULT_SCAN_COUNT);
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_lowercase().as_str(), options.next()) {
+                ("match", Some(value)) => pattern = value,
+                ("count", Some(value)) => match value.parse() {
+                    Ok(value) if value > 0 => count = value,
+                    _ => return Ok(syntax_error()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+        let (keys, visited) = self.keys(pattern, cursor, count)?;
+        // the scan is done once it visited fewer keys than asked for
+        let next = if visited < count { 0 } else { cursor + visited };
+        let keys = keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect();
+        Ok(Reply::Array(vec![Reply::Bulk(Some(next.to_string())), Reply::Array(keys)]))
+    }
+
+    // the keys matching pattern among count keys after skipping skip of them, and how many were visited.
+    // only the keys starting with the literal prefix of the pattern are visited
+    fn keys(&self, pattern: &str, skip: usize, count: usize) -> Result<(Vec<String>, usize)> {
+        let prefix: String = pattern.chars().take_while(|c| !matches!(c, '*' | '?' | '\\')).collect();
+        let pattern: Vec<char> = pattern.chars().collect();
+        let (mut keys, mut visited) = (Vec::new(), 0);
+        for pair in self.engine.scan(&prefix)?.skip(skip).take(count) {
+            let (key, _) = pair?;
+            visited += 1;
+            if glob_match(&pattern, &key.chars().collect::<Vec<_>>()) {
+                keys.push(key);
+            }
+        }
+        Ok((keys, visited))
+    }
+}
+
+// the reply to a command with the wrong number of arguments, None if the number is right
+fn check_arity(name: &str, args: usize) -> Option<Reply> {
+    let ok = match name {
+        "ping" => args <= 1,
+        "quit" | "command" => true,
+        "auth" => args == 1 || args == 2,
+        "get" | "incr" | "keys" => args == 1,
+        "set" => args >= 2,
+        "del" | "exists" | "mget" | "scan" => args >= 1,
+        "mset" => args >= 2 && args.is_multiple_of(2),
+        "info" => args <= 1,
+        _ => true,
+    };
+    if ok {
+        None
+    } else {
+        Some(Reply::Error(format!("ERR wrong number of arguments for '{}' command", name)))
+    }
+}
+
+// redis prints unknown commands as the client sent them, but they may hold anything
+fn args_name(name: &str) -> String {
+    name.chars().take(64).collect()
+}
+
+fn error_reply(e: KvStoreError) -> Reply {
+    match e.code() {
+        ErrorCode::ReadOnly => Reply::Error(String::from("READONLY You can't write against a read only server.")),
+        ErrorCode::Unauthorized => Reply::Error(String::from("NOAUTH Authentication required.")),
+        _ => Reply::Error(format!("ERR {}", e)),
+    }
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error(String::from("ERR syntax error"))
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error(String::from("ERR value is not an integer or out of range"))
+}
+
+// match key against a glob pattern with `*`, `?` and `\` escapes
+fn glob_match(pattern: &[char], key: &[char]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // the position after the last `*` and the key position it currently matches up to
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            },
+            Some('?') => {
+                p += 1;
+                k += 1;
+                continue;
+            },
+            Some('\\') if p + 1 < pattern.len() && pattern[p + 1] == key[k] => {
+                p += 2;
+                k += 1;
+                continue;
+            },
+            Some(c) if *c != '\\' && *c == key[k] => {
+                p += 1;
+                k += 1;
+                continue;
+            },
+            _ => {},
+        }
+        // let the last `*` eat one more char
+        match star {
+            Some((after, matched)) => {
+                p = after;
+                k = matched + 1;
+                star = Some((after, matched + 1));
+            },
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
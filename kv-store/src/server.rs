@@ -1,8 +1,16 @@
-use std::{net::{SocketAddr, TcpListener, TcpStream}, io::{Write, BufReader, BufWriter}};
-use log::info;
-use serde_json::Deserializer;
+use std::{net::{SocketAddr, TcpListener, TcpStream}, io::{self, Write, BufRead, BufReader, BufWriter, Read}};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError, atomic::{AtomicU64, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::path::{Component, Path, PathBuf};
+use log::{info, error, warn};
 
-use crate::{Result, Request, common::Response, KvEngine, thread_pool::ThreadPool};
+use crate::{Result, Request, common::Response, ErrorCode, KeyResult, KvEngine, KvStoreError, Databases, thread_pool::ThreadPool};
+use crate::resp::{self, RespSession};
+use crate::shutdown::{Shutdown, ShutdownHandle};
+use crate::http::{self, HttpSession};
+use crate::limits::{self, Limits, Slots, MAX_REJECTING, REJECT_WAIT};
+use crate::protocol::{read_request, read_json, JsonBuf, write_frame, Frame, BINARY_MAGIC, OP_ERROR, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, SERVER_FEATURES};
 
 /// a server used to handle request, contains a kvstore
 pub struct KvServer<E: KvEngine, T: ThreadPool> {
@@ -10,95 +18,759 @@ pub struct KvServer<E: KvEngine, T: ThreadPool> {
     listener: TcpListener,
     kvengine: E,
     thread_pool: T,
+    // the logical databases a connection can switch to with Select
+    databases: Option<Arc<Databases<E>>>,
+    // refuse requests which write
+    read_only: bool,
+    // if set, a connection has to send it in its Hello before any other request
+    auth_token: Option<String>,
+    // Backup writes into sub directories of it, no Backup without it
+    backup_dir: Option<PathBuf>,
+    // taken by every write to the default engine from any listener, see Session
+    write_lock: Arc<Mutex<()>>,
+    // runs the pipelined reads of binary connections
+    request_pool: Option<Arc<T>>,
+    // accepts redis clients
+    resp_listener: Option<TcpListener>,
+    // accepts HTTP requests
+    http_listener: Option<TcpListener>,
+    shutdown: Arc<Shutdown>,
+    // how long run waits for the open connections on shutdown
+    shutdown_timeout: Duration,
+    limits: Limits,
+    // the open connections of every listener
+    connections: Arc<Slots>,
+    // the connections being refused
+    rejecting: Arc<Slots>,
 }
 
 impl<E: KvEngine, T: ThreadPool> KvServer<E, T> {
-    /// construct a new server
+    /// construct a new server. every open connection holds a thread of thread_pool, which the
+    /// RESP and HTTP listeners share, so size it for the connections of all of them
     pub fn new(addr: SocketAddr, engine: E, thread_pool: T) -> Result<KvServer<E, T>> {
         let listener = TcpListener::bind(addr)?;
+        // the port the system picked if addr has port 0
+        let addr = listener.local_addr()?;
         info!("bind to {}", addr);
-        Ok(KvServer { addr, listener, kvengine: engine, thread_pool })
+        let shutdown = Arc::new(Shutdown::default());
+        shutdown.listen_on(addr);
+        Ok(KvServer {
+            addr,
+            listener,
+            kvengine: engine,
+            thread_pool,
+            databases: None,
+            read_only: false,
+            auth_token: None,
+            backup_dir: None,
+            write_lock: Arc::new(Mutex::new(())),
+            request_pool: None,
+            resp_listener: None,
+            http_listener: None,
+            shutdown,
+            shutdown_timeout: Duration::from_secs(10),
+            limits: Limits::default(),
+            connections: Arc::new(Slots::default()),
+            rejecting: Arc::new(Slots::default()),
+        })
     }
 
-    /// run server to catch connection and handle requests
-    pub fn run(&mut self) -> Result<()> {
+    /// serve multiple logical databases, connections start on the default engine
+    /// and switch with a Select request
+    pub fn with_databases(mut self, databases: Databases<E>) -> KvServer<E, T> {
+        self.databases = Some(Arc::new(databases));
+        self
+    }
+
+    /// serve Get and Scan only, Set and Rm are refused
+    pub fn read_only(mut self, read_only: bool) -> KvServer<E, T> {
+        self.read_only = read_only;
+        self
+    }
+
+    /// require clients to send token in their Hello before any other request
+    pub fn auth_token(mut self, token: Option<String>) -> KvServer<E, T> {
+        self.auth_token = token;
+        self
+    }
+
+    /// allow Backup requests of authenticated connections, they write into a new sub directory
+    /// of dir. without it, or without an auth token, Backup is refused
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> KvServer<E, T> {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// run the pipelined reads of a binary connection concurrently on pool. writes, Select and
+    /// Hello still wait for the requests before them, so a connection sees its requests applied
+    /// in order. without a pool, and on json connections, requests run one after another
+    pub fn request_pool(mut self, pool: T) -> KvServer<E, T> {
+        self.request_pool = Some(Arc::new(pool));
+        self
+    }
+
+    /// also accept RESP2, the protocol of redis, on addr. redis clients get GET, SET, DEL, EXISTS,
+    /// MGET, MSET, INCR, KEYS, SCAN, PING, INFO, AUTH and QUIT on the default database
+    pub fn resp_addr(mut self, addr: SocketAddr) -> Result<KvServer<E, T>> {
+        let listener = TcpListener::bind(addr)?;
+        info!("bind resp to {}", addr);
+        self.shutdown.listen_on(listener.local_addr()?);
+        self.resp_listener = Some(listener);
+        Ok(self)
+    }
+
+    /// also serve a REST gateway on addr: `GET`, `PUT` and `DELETE /kv/{key}`, `GET /kv?prefix=&limit=`
+    /// and `GET /stats`, on the default database or the one of a `namespace` query parameter
+    pub fn http_addr(mut self, addr: SocketAddr) -> Result<KvServer<E, T>> {
+        let listener = TcpListener::bind(addr)?;
+        info!("bind http to {}", addr);
+        self.shutdown.listen_on(listener.local_addr()?);
+        self.http_listener = Some(listener);
+        Ok(self)
+    }
+
+    /// how long `run` waits for the open connections to finish their requests on shutdown,
+    /// 10 seconds by default
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> KvServer<E, T> {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// serve at most max connections at once, counted over the kvs, RESP and HTTP listeners.
+    /// the first request of a connection over the limit is answered with a `Busy` error, a RESP
+    /// error or a 503, and the connection is closed. the timeouts and the request size apply to
+    /// every listener as well
+    pub fn max_connections(mut self, max: usize) -> KvServer<E, T> {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// close a connection whose request does not fully arrive within timeout once it started,
+    /// zero waits forever, the default
+    pub fn read_timeout(mut self, timeout: Duration) -> KvServer<E, T> {
+        self.limits.read_timeout = limits::timeout(timeout);
+        self
+    }
+
+    /// close a connection which does not take a response within timeout, zero waits forever, the default
+    pub fn write_timeout(mut self, timeout: Duration) -> KvServer<E, T> {
+        self.limits.write_timeout = limits::timeout(timeout);
+        self
+    }
+
+    /// close a connection which sends no request for timeout, zero keeps it open, the default
+    pub fn idle_timeout(mut self, timeout: Duration) -> KvServer<E, T> {
+        self.limits.idle_timeout = limits::timeout(timeout);
+        self
+    }
+
+    /// refuse requests larger than bytes with `TooLarge`, 64 MiB by default
+    pub fn max_request_size(mut self, bytes: usize) -> KvServer<E, T> {
+        self.limits.max_request_size = bytes;
+        self
+    }
+
+    /// a handle which stops the server from another thread, e.g. a signal handler or a test
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        Shutdown::handle(&self.shutdown)
+    }
+
+    /// the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// run server to catch connection and handle requests. returns after a shutdown, once the
+    /// open connections are done or the shutdown timeout passed and the engine is flushed
+    pub fn run(&mut self) -> Result<()>
+        where
+            T: Send + Sync + 'static {
+        let resp_session = RespSession::new(self.kvengine.clone(), self.read_only, self.auth_token.clone(), self.write_lock.clone());
+        let http_session = HttpSession::new(self.kvengine.clone(), self.read_only, self.auth_token.clone(), self.write_lock.clone());
+        let (resp_listener, http_listener, thread_pool) = (self.resp_listener.as_ref(), self.http_listener.as_ref(), &self.thread_pool);
+        // the limits count the connections of every listener together
+        let (shutdown, limits, slots) = (&self.shutdown, self.limits, (&self.connections, &self.rejecting));
+        thread::scope(|scope| {
+            if let Some(listener) = resp_listener {
+                scope.spawn(move || resp::serve(listener, resp_session, thread_pool, shutdown, limits, slots));
+            }
+            if let Some(listener) = http_listener {
+                scope.spawn(move || http::serve(listener, http_session, thread_pool, shutdown, limits, slots));
+            }
+            self.serve_kvs();
+        });
+        self.shutdown.drain(self.shutdown_timeout);
+        self.kvengine.flush()?;
+        if let Some(ref databases) = self.databases {
+            databases.flush()?;
+        }
+        info!("server stopped");
+        Ok(())
+    }
+
+    fn serve_kvs(&self)
+        where
+            T: Send + Sync + 'static {
         for stream in self.listener.incoming() {
+            if self.shutdown.is_requested() {
+                break;
+            }
             info!("get connenction");
-            let mut stream = stream.unwrap();
-            let engine = self.kvengine.clone();
-            self.thread_pool.spawn(move || { serve_connection(engine, stream); });
+            let (stream, connection) = match stream.and_then(|stream| Shutdown::register(&self.shutdown, &stream).map(|connection| (stream, connection))) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("fail to accept connection: {}", e);
+                    continue;
+                },
+            };
+            let limits = self.limits;
+            let slot = match Slots::take(&self.connections, limits.max_connections) {
+                Some(slot) => slot,
+                None => {
+                    // refused on a thread of its own, the workers are all taken
+                    match Slots::take(&self.rejecting, Some(MAX_REJECTING)) {
+                        Some(rejecting) => {
+                            warn!("too many connections, refuse one");
+                            thread::spawn(move || {
+                                if let Err(e) = reject(stream, limits) {
+                                    error!("fail to refuse connection: {}", e);
+                                }
+                                drop((rejecting, connection));
+                            });
+                        },
+                        None => warn!("too many connections, close one"),
+                    }
+                    continue;
+                },
+            };
+            let session = Session::new(self.kvengine.clone(), self.databases.clone(), self.read_only, self.auth_token.clone(), self.backup_dir.clone(), self.write_lock.clone());
+            let request_pool = self.request_pool.clone();
+            self.thread_pool.spawn(move || {
+                if let Err(e) = serve_connection(session, stream, request_pool.as_deref(), limits) {
+                    error!("fail to serve connection: {}", e);
+                }
+                drop((slot, connection));
+            });
         }
-        Ok(())
     }
 }
 
 /// handle connection
-fn serve_connection<E: KvEngine>(engine: E, mut stream: TcpStream) -> Result<()> {
+fn serve_connection<E: KvEngine, T: ThreadPool>(session: Session<E>, stream: TcpStream, request_pool: Option<&T>, limits: Limits) -> Result<()> {
+    stream.set_write_timeout(limits.write_timeout)?;
     let mut reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
+    let writer = BufWriter::new(&stream);
     info!("get stream");
-    let request_iter = Deserializer::from_reader(reader).into_iter::<Request>();
+    if !wait_request(&mut reader, false, &limits)? {
+        return Ok(());
+    }
+    // the first byte tells the protocols apart
+    let binary = reader.fill_buf()?.first() == Some(&BINARY_MAGIC[0]);
+    if binary {
+        serve_binary(session, reader, writer, request_pool, limits)
+    } else {
+        serve_json(session, reader, writer, limits)
+    }
+}
+
+// wait up to the idle timeout for the next request to start, false if the connection is closed
+// or idle for too long. the rest of the request then has to arrive within the read timeout.
+// pending tells that part of the request is already read
+pub(crate) fn wait_request(reader: &mut BufReader<&TcpStream>, pending: bool, limits: &Limits) -> Result<bool> {
+    if !pending && reader.buffer().is_empty() {
+        reader.get_ref().set_read_timeout(limits.idle_timeout)?;
+        match reader.fill_buf() {
+            Ok([]) => return Ok(false),
+            Ok(_) => {},
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                info!("close idle connection");
+                return Ok(false);
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+    reader.get_ref().set_read_timeout(limits.read_timeout)?;
+    Ok(true)
+}
+
+// answer the first request of a connection over the limit with Busy and close it
+fn reject(stream: TcpStream, limits: Limits) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_WAIT))?;
+    stream.set_write_timeout(Some(REJECT_WAIT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let busy = error_response(KvStoreError::Busy(String::from("too many connections, try again later")));
+    if reader.fill_buf()?.first() == Some(&BINARY_MAGIC[0]) {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        // the client waits for the reply to its first request
+        if let Some((_, id, _)) = read_request(&mut reader, limits.max_request_size)? {
+            write_frame(&mut writer, &Frame { opcode: OP_ERROR, id, payload: bincode::serialize(&busy)? })?;
+        }
+    } else {
+        // a request which fails to parse is refused all the same
+        let _ = read_json::<serde_json::Value>(&mut reader, &mut JsonBuf::default(), limits.max_request_size);
+        serde_json::to_writer(&mut writer, &busy)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// json documents written back to back, a malformed document ends the connection
+fn serve_json<E: KvEngine>(mut session: Session<E>, mut reader: BufReader<&TcpStream>, mut writer: BufWriter<&TcpStream>, limits: Limits) -> Result<()> {
+    // bytes of the documents read ahead
+    let mut buf = JsonBuf::default();
     info!("receive request");
-    for request in request_iter {
-        let request = request?;
+    while wait_request(&mut reader, !buf.is_empty(), &limits)? {
+        let request = match read_json(&mut reader, &mut buf, limits.max_request_size) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            // the rest of the document is not read, so the stream can not go on
+            Err(e @ KvStoreError::TooLarge(_)) => {
+                serde_json::to_writer(&mut writer, &error_response(e))?;
+                writer.flush()?;
+                break;
+            },
+            Err(e) => return Err(e),
+        };
+        let res = session.handle_received(request, Instant::now());
+        serde_json::to_writer(&mut writer, &res)?;
+        writer.flush()?;
+        if session.closing {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// length-prefixed frames, a frame which can not be decoded gets an error reply.
+// replies are written by their own thread in the order they finish, clients match them by id
+fn serve_binary<E: KvEngine, T: ThreadPool>(
+    mut session: Session<E>,
+    mut reader: BufReader<&TcpStream>,
+    writer: BufWriter<&TcpStream>,
+    request_pool: Option<&T>,
+    limits: Limits,
+) -> Result<()> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != *BINARY_MAGIC {
+        return Err(KvStoreError::Protocol(String::from("unknown handshake")));
+    }
+    session.require_hello();
+    let (replies, finished) = mpsc::channel();
+    let in_flight = Arc::new(InFlight::default());
+    thread::scope(|scope| {
+        let reply_writer = scope.spawn(move || write_replies(writer, finished));
+        let res = read_requests(&mut session, &mut reader, &replies, &in_flight, request_pool, &limits);
+        // the reads still running reply before the connection is closed
+        in_flight.wait_idle();
+        drop(replies);
+        let written = reply_writer.join().unwrap_or_else(|_| Err(KvStoreError::Internal(String::from("reply writer panicked"))));
+        res.and(written)
+    })
+}
+
+// decode and dispatch the frames of a connection until it is closed
+fn read_requests<E: KvEngine, T: ThreadPool>(
+    session: &mut Session<E>,
+    reader: &mut BufReader<&TcpStream>,
+    replies: &mpsc::Sender<Frame>,
+    in_flight: &Arc<InFlight>,
+    request_pool: Option<&T>,
+    limits: &Limits,
+) -> Result<()> {
+    while wait_request(reader, false, limits)? {
+        let (opcode, id, request) = match read_request(reader, limits.max_request_size)? {
+            Some(next) => next,
+            None => break,
+        };
+        // deadlines count from here
+        let received = Instant::now();
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                error!("fail to decode frame {}: {}", id, e);
+                let payload = bincode::serialize(&error_response(e))?;
+                if replies.send(Frame { opcode: OP_ERROR, id, payload }).is_err() {
+                    break;
+                }
+                continue;
+            },
+        };
+        match request_pool {
+            // reads do not change anything, so they may run next to each other
+            Some(pool) if is_read(&request) && !session.hello_required => {
+                let mut task = session.clone();
+                let replies = replies.clone();
+                let running = InFlight::start(in_flight);
+                pool.spawn(move || {
+                    if let Ok(payload) = bincode::serialize(&task.handle_received(request, received)) {
+                        // fails only if the connection is gone
+                        let _ = replies.send(Frame { opcode, id, payload });
+                    }
+                    drop(running);
+                });
+            },
+            // everything else waits for the requests before it and runs alone
+            _ => {
+                in_flight.wait_idle();
+                let payload = bincode::serialize(&session.handle_received(request, received))?;
+                if replies.send(Frame { opcode, id, payload }).is_err() || session.closing {
+                    break;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+// write replies as they come, flushing once no more are ready
+fn write_replies(mut writer: BufWriter<&TcpStream>, finished: mpsc::Receiver<Frame>) -> Result<()> {
+    while let Ok(mut reply) = finished.recv() {
+        loop {
+            write_frame(&mut writer, &reply)?;
+            reply = match finished.try_recv() {
+                Ok(next) => next,
+                Err(_) => break,
+            };
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// requests which only read the engine
+fn is_read(request: &Request) -> bool {
+    match request {
+        Request::Deadline { request, .. } => is_read(request),
+        _ => matches!(request, Request::Get { .. } | Request::MGet { .. } | Request::Scan { .. } | Request::QueryIndex { .. } | Request::Info { .. } | Request::DbStats),
+    }
+}
+
+// the requests of a connection running on the request pool
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+// marks a request as running until it is dropped, also if the request panics
+struct Running(Arc<InFlight>);
+
+impl InFlight {
+    fn start(in_flight: &Arc<InFlight>) -> Running {
+        *in_flight.count.lock().unwrap() += 1;
+        Running(in_flight.clone())
+    }
+
+    fn wait_idle(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.idle.wait(count).unwrap();
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+// the state of a connection, shared by the threaded and the async server
+#[derive(Clone)]
+pub(crate) struct Session<E: KvEngine> {
+    // the selected database
+    engine: E,
+    databases: Option<Arc<Databases<E>>>,
+    read_only: bool,
+    auth_token: Option<String>,
+    // sent a Hello with the right token
+    authenticated: bool,
+    // the directory Backup writes into
+    backup_dir: Option<PathBuf>,
+    // the write lock of the selected engine, the default one is shared with the RESP and HTTP
+    // sessions. writes hold it, so SET NX/XX, INCR and MSET of RESP, which read before they
+    // write, never interleave with a write of another connection to the same engine
+    write_lock: Arc<Mutex<()>>,
+    // the connection is closed after the current response
+    closing: bool,
+    // a binary connection which did not send its Hello yet
+    hello_required: bool,
+    // request counter of the selected database
+    requests: Option<Arc<AtomicU64>>,
+}
+
+impl<E: KvEngine> Session<E> {
+    pub(crate) fn new(engine: E, databases: Option<Arc<Databases<E>>>, read_only: bool, auth_token: Option<String>, backup_dir: Option<PathBuf>, write_lock: Arc<Mutex<()>>) -> Session<E> {
+        Session { engine, databases, read_only, auth_token, authenticated: false, backup_dir, write_lock, closing: false, hello_required: false, requests: None }
+    }
+
+    // binary clients are newer than the error codes of protocol version 2, so they have to
+    // say which version they speak before anything else
+    pub(crate) fn require_hello(&mut self) {
+        self.hello_required = true;
+    }
+
+    // the connection is closed after the current response
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    // run a request read at received, one whose deadline passed since is refused
+    pub(crate) fn handle_received(&mut self, mut request: Request, received: Instant) -> Response {
+        // the tightest of the nested deadlines
+        let mut timeout_ms = None;
+        while let Request::Deadline { timeout_ms: timeout, request: inner } = request {
+            timeout_ms = Some(timeout_ms.map_or(timeout, |ms: u64| ms.min(timeout)));
+            request = *inner;
+        }
+        if let Some(response) = deadline_exceeded(received, timeout_ms) {
+            return response;
+        }
+        if let Some(ref requests) = self.requests {
+            requests.fetch_add(1, Ordering::SeqCst);
+        }
+        let engine = &self.engine;
         match request {
-            Request::Set { key, value } => {
-                let res = engine.set(key, value);
+            Request::Hello { version, client, features, token } => self.hello(version, client, features, token),
+            _ if self.hello_required => {
+                self.closing = true;
+                error_response(KvStoreError::Unsupported(String::from("a binary connection has to start with a Hello")))
+            },
+            _ if self.auth_token.is_some() && !self.authenticated => {
+                error_response(KvStoreError::Unauthorized(String::from("unauthorized, send a Hello with the auth token first")))
+            },
+            Request::Set { .. } | Request::Rm { .. } | Request::MSet { .. } | Request::MDel { .. } | Request::Backup { .. } if self.read_only => {
+                error_response(KvStoreError::ReadOnly)
+            },
+            Request::Set { .. } | Request::Rm { .. } | Request::MSet { .. } | Request::MDel { .. } => {
+                let _write = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+                // waiting for the lock may have used up the deadline
+                if let Some(response) = deadline_exceeded(received, timeout_ms) {
+                    return response;
+                }
+                self.write(request)
+            },
+            Request::Get { key, namespace } => {
+                let res = select_namespace(engine, namespace).and_then(|engine| engine.get(key));
                 match res {
-                    Ok(_) => {
-                        info!("success to set value");
-                        let res = Response::Set { result: String::from("Success") };
-                        serde_json::to_writer(&mut writer, &res)?;
-                        writer.flush()?;
-                        info!("finish send response");
+                    Ok(Some(val)) => Response::Get { value: val, result: String::from("Success") },
+                    Ok(None) => not_found(),
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::MGet { keys, namespace } => {
+                match select_namespace(engine, namespace) {
+                    Ok(engine) => {
+                        let results = keys.into_iter().map(|key| match engine.get(key) {
+                            Ok(Some(value)) => KeyResult::Value(value),
+                            Ok(None) => key_not_found(),
+                            Err(e) => key_error(e),
+                        }).collect();
+                        Response::MGet { results, result: String::from("Success") }
                     },
-                    Err(_) => {
-                        let res = Response::Set { result: String::from("Failure") };
-                        serde_json::to_writer(&mut writer, &res)?;
-                        writer.flush()?;
-                    }
+                    Err(e) => error_response(e),
                 }
             },
-            Request::Get { key } => {
-                let res = engine.get(key);
+            Request::Scan { prefix, limit, namespace } => {
+                let res = select_namespace(engine, namespace).and_then(|engine| {
+                    engine.scan(&prefix)?.take(limit.unwrap_or(usize::MAX)).collect::<Result<Vec<_>>>()
+                });
                 match res {
-                    Ok(value) => {
-                        match value {
-                            Some(val) => {
-                                let res = Response::Get { value: val, result: String::from("Success") };
-                                serde_json::to_writer(&mut writer, &res)?;
-                                writer.flush()?;
-                            },
-                            None => {
-                                let res = Response::Get { value: String::from("Key not found"), result: String::from("Failure") };
-                                serde_json::to_writer(&mut writer, &res)?;
-                                writer.flush()?;
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        let res = Response::Get { value: String::from("Key not found"), result: String::from("Failure") };
-                        serde_json::to_writer(&mut writer, &res)?;
-                        writer.flush()?;
-                    }
+                    Ok(pairs) => Response::Scan { pairs, result: String::from("Success") },
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::QueryIndex { index, value, namespace } => {
+                match select_namespace(engine, namespace).and_then(|engine| engine.query_index(&index, &value)) {
+                    Ok(keys) => Response::QueryIndex { keys, result: String::from("Success") },
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::Info { namespace } => {
+                match select_namespace(engine, namespace).and_then(|engine| engine.stats()) {
+                    Ok(stats) => Response::Info { stats, result: String::from("Success") },
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::Select { db } => {
+                let res = match self.databases {
+                    Some(ref databases) => databases.select(&db),
+                    None => Err(KvStoreError::InvalidDatabase(String::from("multiple databases are not enabled"))),
+                };
+                match res {
+                    Ok((selected, counter, write_lock)) => {
+                        info!("select database {}", db);
+                        self.engine = selected;
+                        self.requests = Some(counter);
+                        self.write_lock = write_lock;
+                        Response::Select { result: String::from("Success") }
+                    },
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::DbStats => {
+                let res = match self.databases {
+                    Some(ref databases) => databases.stats(),
+                    None => Ok(Vec::new()),
+                };
+                match res {
+                    Ok(dbs) => Response::DbStats { dbs, result: String::from("Success") },
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::Deadline { .. } => unreachable!("deadlines are unwrapped above"),
+            Request::Backup { dest } => {
+                info!("backup to {}", dest);
+                let res = self.backup_path(&dest).and_then(|dest| engine.checkpoint(&dest));
+                match res {
+                    Ok(()) => Response::Backup { result: String::from("Success") },
+                    Err(e) => {
+                        error!("fail to backup: {}", e);
+                        error_response(e)
+                    },
                 }
             },
-            Request::Rm { key } => {
-                let res = engine.remove(key);
+        }
+    }
+
+    // run a write, the caller holds the write lock of the engine
+    fn write(&self, request: Request) -> Response {
+        let engine = &self.engine;
+        match request {
+            Request::Set { key, value, namespace } => {
+                let res = select_namespace(engine, namespace).and_then(|engine| engine.set(key, value));
                 match res {
                     Ok(_) => {
-                        let res = Response::Rm { result: String::from("Success") };
-                        serde_json::to_writer(&mut writer, &res)?;
-                        writer.flush()?;
+                        info!("success to set value");
+                        Response::Set { result: String::from("Success") }
                     },
-                    Err(_) => {
-                        let res = Response::Rm { result: String::from("Key not found") };
-                        serde_json::to_writer(&mut writer, &res)?;
-                        writer.flush()?;
-                    }
+                    Err(e) => error_response(e),
                 }
-            }
+            },
+            Request::Rm { key, namespace } => {
+                let res = select_namespace(engine, namespace).and_then(|engine| engine.remove(key));
+                match res {
+                    Ok(_) => Response::Rm { result: String::from("Success") },
+                    Err(KvStoreError::RemoveNonExistKey) => not_found(),
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::MSet { pairs, atomic, namespace } => {
+                match select_namespace(engine, namespace).and_then(|engine| set_pairs(&engine, pairs, atomic)) {
+                    Ok(results) => Response::MSet { results, result: String::from("Success") },
+                    Err(e) => error_response(e),
+                }
+            },
+            Request::MDel { keys, namespace } => {
+                match select_namespace(engine, namespace) {
+                    Ok(engine) => {
+                        let results = keys.into_iter().map(|key| match engine.remove(key) {
+                            Ok(()) => KeyResult::Done,
+                            Err(KvStoreError::RemoveNonExistKey) => key_not_found(),
+                            Err(e) => key_error(e),
+                        }).collect();
+                        Response::MDel { results, result: String::from("Success") }
+                    },
+                    Err(e) => error_response(e),
+                }
+            },
+            _ => unreachable!("only writes get here"),
         }
     }
-    Ok(())
-}
\ No newline at end of file
+
+    // the directory a Backup to dest writes, only a relative path below the backup directory
+    // of an authenticated connection
+    fn backup_path(&self, dest: &str) -> Result<PathBuf> {
+        let root = match self.backup_dir {
+            Some(ref root) => root,
+            None => return Err(KvStoreError::Unsupported(String::from("backup is not enabled on this server"))),
+        };
+        if self.auth_token.is_none() || !self.authenticated {
+            return Err(KvStoreError::Unauthorized(String::from("backup needs a connection authenticated with the auth token")));
+        }
+        let path = Path::new(dest);
+        if dest.is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(KvStoreError::InvalidArgument(format!("invalid backup destination {}, it must be relative and without ..", dest)));
+        }
+        Ok(root.join(path))
+    }
+
+    // check the version and the token of the client and pick the features both sides know.
+    // an incompatible client gets an error and the connection is closed
+    fn hello(&mut self, version: u32, client: String, features: Vec<String>, token: Option<String>) -> Response {
+        let error = if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            Some(KvStoreError::Unsupported(format!("unsupported protocol version {}, the server speaks {} to {}", version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)))
+        } else if self.auth_token.is_some() && token != self.auth_token {
+            Some(KvStoreError::Unauthorized(String::from("unauthorized, invalid auth token")))
+        } else {
+            None
+        };
+        if let Some(e) = error {
+            error!("reject {}: {}", client, e);
+            self.closing = true;
+            return error_response(e);
+        }
+        info!("hello from {} speaking protocol {}", client, version);
+        self.authenticated = true;
+        self.hello_required = false;
+        let features = features.into_iter().filter(|feature| SERVER_FEATURES.contains(&feature.as_str())).collect();
+        let engine = self.engine.engine_type().to_string();
+        Response::Hello { version: PROTOCOL_VERSION, engine, features, result: String::from("Success") }
+    }
+}
+
+pub(crate) fn error_response(e: KvStoreError) -> Response {
+    Response::Error { code: e.code(), message: e.to_string() }
+}
+
+// the error for a request read at received whose deadline of timeout_ms passed
+fn deadline_exceeded(received: Instant, timeout_ms: Option<u64>) -> Option<Response> {
+    let timeout_ms = timeout_ms?;
+    if received.elapsed() <= Duration::from_millis(timeout_ms) {
+        return None;
+    }
+    info!("deadline of {}ms exceeded", timeout_ms);
+    Some(error_response(KvStoreError::DeadlineExceeded(format!("deadline of {}ms exceeded before the request ran", timeout_ms))))
+}
+
+// a missing key, with the message clients have always printed
+fn not_found() -> Response {
+    Response::Error { code: ErrorCode::NotFound, message: String::from("Key not found") }
+}
+
+// write the pairs of an MSet. an atomic MSet fails as a whole, otherwise every pair has its own result
+fn set_pairs<E: KvEngine>(engine: &E, pairs: Vec<(String, String)>, atomic: bool) -> Result<Vec<KeyResult>> {
+    if atomic {
+        let count = pairs.len();
+        engine.set_batch(pairs)?;
+        return Ok(vec![KeyResult::Done; count]);
+    }
+    Ok(pairs.into_iter().map(|(key, value)| match engine.set(key, value) {
+        Ok(()) => KeyResult::Done,
+        Err(e) => key_error(e),
+    }).collect())
+}
+
+fn key_error(e: KvStoreError) -> KeyResult {
+    KeyResult::Error { code: e.code(), message: e.to_string() }
+}
+
+// a missing key of a multi-key request
+fn key_not_found() -> KeyResult {
+    KeyResult::Error { code: ErrorCode::NotFound, message: String::from("Key not found") }
+}
+
+// pick the namespace a request works on
+fn select_namespace<E: KvEngine>(engine: &E, namespace: Option<String>) -> Result<E> {
+    match namespace {
+        Some(name) => engine.namespace(&name),
+        None => Ok(engine.clone()),
+    }
+}
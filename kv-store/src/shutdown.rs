@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::net::{Shutdown as Direction, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+/// stops a running `KvServer` or `AsyncKvServer`, get one with their `shutdown_handle`
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Shutdown>,
+}
+
+impl ShutdownHandle {
+    /// ask the server to stop. it stops accepting connections, lets the requests it already read
+    /// finish and then `run` returns. may be called from any thread and more than once
+    pub fn shutdown(&self) {
+        if self.inner.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("shutdown requested");
+        // a connection wakes up the accept loops blocked on the listeners
+        for addr in self.inner.addrs.lock().unwrap().iter() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// whether shutdown has been called
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.is_requested()
+    }
+}
+
+// the shutdown state of a server, shared by its accept loops and its handles
+#[derive(Default)]
+pub(crate) struct Shutdown {
+    requested: AtomicBool,
+    // the addresses the server accepts on
+    addrs: Mutex<Vec<SocketAddr>>,
+    // the connections being served, by a running number
+    connections: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    // signalled whenever a connection ends
+    closed: Condvar,
+}
+
+// unregisters a connection when dropped, also if serving it panicked
+pub(crate) struct Connection {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+
+impl Shutdown {
+    pub(crate) fn handle(shutdown: &Arc<Shutdown>) -> ShutdownHandle {
+        ShutdownHandle { inner: shutdown.clone() }
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // wake up the listener on addr on shutdown. a wildcard address is reached through loopback
+    pub(crate) fn listen_on(&self, mut addr: SocketAddr) {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(if addr.is_ipv4() { [127, 0, 0, 1].into() } else { std::net::Ipv6Addr::LOCALHOST.into() });
+        }
+        self.addrs.lock().unwrap().push(addr);
+    }
+
+    // track an accepted connection until the returned guard is dropped
+    pub(crate) fn register(shutdown: &Arc<Shutdown>, stream: &TcpStream) -> std::io::Result<Connection> {
+        let stream = stream.try_clone()?;
+        let mut connections = shutdown.connections.lock().unwrap();
+        let id = connections.0;
+        connections.0 += 1;
+        // a connection accepted while shutting down is closed for reading right away
+        if shutdown.is_requested() {
+            let _ = stream.shutdown(Direction::Read);
+        }
+        connections.1.insert(id, stream);
+        Ok(Connection { shutdown: shutdown.clone(), id })
+    }
+
+    // close the connections for reading, so they end after the requests they already read,
+    // and wait for them. connections still open at the deadline are cut off
+    pub(crate) fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut connections = self.connections.lock().unwrap();
+        info!("wait for {} connections", connections.1.len());
+        for stream in connections.1.values() {
+            let _ = stream.shutdown(Direction::Read);
+        }
+        while !connections.1.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("close {} connections at the shutdown deadline", connections.1.len());
+                for stream in connections.1.values() {
+                    let _ = stream.shutdown(Direction::Both);
+                }
+                return;
+            }
+            connections = self.closed.wait_timeout(connections, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.shutdown.connections.lock().unwrap().1.remove(&self.id);
+        self.shutdown.closed.notify_all();
+    }
+}
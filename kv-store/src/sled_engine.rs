@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sled::{Db, Tree};
+
+use crate::{EngineType, IndexSpec, KvEngine, KvStoreError, KvStoreOptions, Result, Scan, DEFAULT_NAMESPACE, ENGINE_META_PATH};
+use crate::checkpoint;
+use crate::stats::{EngineStats, OpCounters};
+use crate::kvstore::valid_name;
+
+// the name sled gives its default tree
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// a KvEngine backed by sled, every namespace is stored in its own sled tree
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    dir: PathBuf,
+    // the tree this handle reads and writes
+    tree: Tree,
+    // op counters of the tree
+    counters: Arc<OpCounters>,
+    // op counters of every tree opened so far
+    all_counters: Arc<Mutex<HashMap<String, Arc<OpCounters>>>>,
+    // secondary indexes, answered by scanning their prefix
+    indexes: Arc<Vec<IndexSpec>>,
+}
+
+impl SledKvsEngine {
+    /// open the sled database at a given path
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with(path, &KvStoreOptions::default())
+    }
+
+    /// open the sled database at a given path, only the cache size and the indexes of the options are used.
+    /// encryption keys, size limits and read-only fail with `Unsupported` instead of being ignored
+    pub fn open_with(path: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<SledKvsEngine> {
+        if options.encryption_key.is_some() || !options.old_keys.is_empty() {
+            return Err(KvStoreError::Unsupported(String::from("the sled engine does not support encryption")));
+        }
+        if options.max_bytes.is_some() || options.max_keys.is_some() {
+            return Err(KvStoreError::Unsupported(String::from("the sled engine does not support size limits")));
+        }
+        // sled writes into its directory on open, even if nothing is set afterwards
+        if options.read_only {
+            return Err(KvStoreError::Unsupported(String::from("the sled engine can not be opened read-only")));
+        }
+        let dir = path.into();
+        let mut config = sled::Config::new().path(&dir);
+        if options.cache_size > 0 {
+            config = config.cache_capacity(options.cache_size as u64);
+        }
+        let db = config.open()?;
+        let tree = (*db).clone();
+        let counters = Arc::new(OpCounters::default());
+        let mut all_counters = HashMap::new();
+        all_counters.insert(DEFAULT_NAMESPACE.to_owned(), counters.clone());
+        Ok(SledKvsEngine { db, dir, tree, counters, all_counters: Arc::new(Mutex::new(all_counters)), indexes: Arc::new(options.indexes.clone()) })
+    }
+}
+
+impl KvEngine for SledKvsEngine {
+    /// insert a key-value pair
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let old = self.tree.insert(key, value.into_bytes())?;
+        self.counters.set(old.is_some());
+        // flush on every write, the server may be killed at any time
+        self.tree.flush()?;
+        Ok(())
+    }
+    /// get the value for the given key
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.tree.get(key)?;
+        self.counters.get(value.is_some());
+        match value {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+    /// remove the key-value pair with given key
+    fn remove(&self, key: String) -> Result<()> {
+        let old = self.tree.remove(key)?;
+        self.counters.remove(old.is_some());
+        old.ok_or(KvStoreError::RemoveNonExistKey)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+    /// sled keeps no expiry times
+    fn set_with_ttl(&self, _key: String, _value: String, _ttl: Duration) -> Result<()> {
+        Err(KvStoreError::Unsupported(String::from("the sled engine does not support expiring keys")))
+    }
+    /// apply the pairs as one sled batch
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            self.counters.set(self.tree.contains_key(&key)?);
+            batch.insert(key.into_bytes(), value.into_bytes());
+        }
+        self.tree.apply_batch(batch)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+    /// get a handle to the tree of namespace `name`
+    fn namespace(&self, name: &str) -> Result<SledKvsEngine> {
+        if !valid_name(name) {
+            return Err(KvStoreError::InvalidNamespace(name.to_owned()));
+        }
+        let tree = if name == DEFAULT_NAMESPACE {
+            (*self.db).clone()
+        } else {
+            self.db.open_tree(name)?
+        };
+        let counters = self.all_counters.lock().unwrap().entry(name.to_owned()).or_default().clone();
+        Ok(SledKvsEngine { tree, counters, ..self.clone() })
+    }
+    /// export every tree into a new sled database in dest
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        checkpoint::prepare_dest(dest)?;
+        {
+            let copy = sled::open(dest)?;
+            copy.import(self.db.export());
+            copy.flush()?;
+        }
+        let meta = self.dir.join(ENGINE_META_PATH);
+        if meta.exists() {
+            fs::copy(&meta, dest.join(ENGINE_META_PATH))?;
+        }
+        checkpoint::write_manifest(dest)
+    }
+    /// flush every tree of the database
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+    /// iterate over the pairs of the tree whose key starts with prefix
+    fn scan(&self, prefix: &str) -> Result<Scan> {
+        let iter = self.tree.scan_prefix(prefix).map(|res| {
+            let (key, value) = res?;
+            Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+        });
+        Ok(Box::new(iter))
+    }
+    /// the keys whose value has `value` at the path of the index, found by scanning its prefix
+    fn query_index(&self, index: &str, value: &str) -> Result<Vec<String>> {
+        let spec = self.indexes.iter()
+            .find(|spec| spec.name == index)
+            .ok_or_else(|| KvStoreError::UnknownIndex(index.to_owned()))?;
+        let mut keys = Vec::new();
+        for pair in self.scan(&spec.prefix)? {
+            let (key, found) = pair?;
+            if spec.terms(&key, &found).iter().any(|term| term == value) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+    /// names of the trees, the default tree is the default namespace
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.db.tree_names()
+            .into_iter()
+            .map(|name| {
+                if &*name == SLED_DEFAULT_TREE {
+                    DEFAULT_NAMESPACE.to_owned()
+                } else {
+                    String::from_utf8_lossy(&name).into_owned()
+                }
+            })
+            .filter(|name| valid_name(name))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+    /// always sled
+    fn engine_type(&self) -> EngineType {
+        EngineType::Sled
+    }
+    /// statistics of the tree, sled reclaims space by itself so nothing counts as dead
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats::default();
+        for pair in self.tree.iter() {
+            let (key, value) = pair?;
+            stats.keys += 1;
+            stats.live_bytes += (key.len() + value.len()) as u64;
+        }
+        stats.files = walkdir::WalkDir::new(&self.dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && entry.file_name() != ENGINE_META_PATH)
+            .count() as u64;
+        self.counters.fill(&mut stats);
+        Ok(stats)
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Serialize, Deserialize};
+
+/// statistics of a namespace of an engine
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    /// number of live keys
+    pub keys: u64,
+    /// bytes of the live records, for sled the bytes of the live keys and values
+    pub live_bytes: u64,
+    /// bytes of overwritten and removed records, which compaction reclaims
+    pub dead_bytes: u64,
+    /// number of data files
+    pub files: u64,
+    /// when the last compaction finished, in seconds since the unix epoch
+    pub last_compaction: Option<u64>,
+    /// how long the last compaction took, in milliseconds
+    pub last_compaction_ms: Option<u64>,
+    /// number of compactions since the engine was opened
+    pub compactions: u64,
+    /// keys removed by eviction since the engine was opened
+    #[serde(default)]
+    pub evictions: u64,
+    /// gets since the engine was opened, a hit found the key
+    pub gets: OpStats,
+    /// sets since the engine was opened, a hit overwrote an existing key
+    pub sets: OpStats,
+    /// removes since the engine was opened, a hit removed an existing key
+    pub removes: OpStats,
+}
+
+/// how often an operation found its key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// the key existed
+    pub hits: u64,
+    /// the key did not exist
+    pub misses: u64,
+}
+
+// the op counters shared by every handle of a namespace
+#[derive(Default)]
+pub(crate) struct OpCounters {
+    gets: Counter,
+    sets: Counter,
+    removes: Counter,
+}
+
+#[derive(Default)]
+struct Counter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl OpCounters {
+    pub(crate) fn get(&self, hit: bool) {
+        self.gets.count(hit);
+    }
+
+    pub(crate) fn set(&self, hit: bool) {
+        self.sets.count(hit);
+    }
+
+    pub(crate) fn remove(&self, hit: bool) {
+        self.removes.count(hit);
+    }
+
+    // copy the counters into stats
+    pub(crate) fn fill(&self, stats: &mut EngineStats) {
+        stats.gets = self.gets.load();
+        stats.sets = self.sets.load();
+        stats.removes = self.removes.load();
+    }
+}
+
+impl Counter {
+    fn count(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn load(&self) -> OpStats {
+        OpStats { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+}
@@ -15,5 +15,6 @@ mod naive;
 pub use naive::NaiveThreadPool;
 mod shared;
 pub use shared::SharedQueueThreadPool;
+#[allow(non_snake_case)]
 mod Rayon;
 pub use Rayon::RayonThreadPool;
\ No newline at end of file
@@ -7,7 +7,7 @@ pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
     /// create a new threadpool, spawn the specific number of threads
-    fn new(threads: u32) ->  Result<NaiveThreadPool>{
+    fn new(_threads: u32) ->  Result<NaiveThreadPool>{
         Ok(NaiveThreadPool)
     }
     /// spawn a function into the threadpool
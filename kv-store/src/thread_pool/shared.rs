@@ -3,6 +3,7 @@ use crate::Result;
 use std::{thread, sync::{mpsc, Arc, Mutex}, panic::{self, AssertUnwindSafe}};
 
 /// a thread with thread_id
+#[allow(dead_code)]
 struct Worker {
     id: usize,
     thread: thread::JoinHandle<()>,
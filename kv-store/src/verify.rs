@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{EngineType, KvStoreError, KvStoreOptions, Result, ENGINE_META_PATH, DEFAULT_NAMESPACE};
+use crate::kvstore::{is_json_log, legacy_commands, list_generations, log_name, sync_dir, Command, LEGACY_LOG};
+use crate::record::{self, encode, HEADER_LEN};
+use crate::lock::DirLock;
+
+/// the report repair leaves in the data directory
+pub const REPAIR_REPORT_PATH: &str = "repair.report";
+
+// how many bytes walk scans at once while it looks for the next intact record
+const RESYNC_WINDOW: usize = 1 << 20;
+
+/// a range of a log file which could not be read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CorruptRange {
+    /// the log file
+    pub file: String,
+    /// offset of the first unreadable byte
+    pub start: u64,
+    /// offset after the last unreadable byte
+    pub end: u64,
+    /// why the range could not be read
+    pub reason: String,
+}
+
+/// what verify found in a data directory
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// number of records which could be read
+    pub records: u64,
+    /// unreadable ranges of the logs
+    pub corrupt: Vec<CorruptRange>,
+    /// `.log` files open would ignore, e.g. of an invalid namespace name or without a generation
+    pub orphaned: Vec<String>,
+    /// records which contradict the rest of the log
+    pub inconsistencies: Vec<String>,
+}
+
+/// what repair salvaged and dropped
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RepairReport {
+    /// every namespace with the number of keys kept
+    pub namespaces: Vec<(String, u64)>,
+    /// the ranges which were dropped
+    pub dropped: Vec<CorruptRange>,
+}
+
+impl VerifyReport {
+    /// true if nothing is wrong with the store
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.orphaned.is_empty() && self.inconsistencies.is_empty()
+    }
+}
+
+// the log files of every namespace, oldest first
+pub(crate) type LogFiles = BTreeMap<String, Vec<String>>;
+
+// an item found while walking a log file
+pub(crate) enum Entry {
+    Record { pos: u64, len: u64, cmd: Command },
+    Corrupt { start: u64, end: u64, error: KvStoreError },
+}
+
+/// walk every record of the kvs store in dir without opening it and report what is wrong.
+/// fails while a writer has the store open
+pub fn verify_store(dir: impl AsRef<Path>, options: &KvStoreOptions) -> Result<VerifyReport> {
+    let dir = dir.as_ref();
+    check_engine(dir)?;
+    let _lock = DirLock::shared(dir)?;
+    let (namespaces, stray) = log_files(dir)?;
+    let mut report = VerifyReport { orphaned: stray, ..VerifyReport::default() };
+
+    for files in namespaces.values() {
+        // the keys which are set
+        let mut index: HashSet<String> = HashSet::new();
+        for file in files {
+            walk(&dir.join(file), options, &mut |entry| match entry {
+                Entry::Record { pos, cmd, .. } => {
+                    report.records += 1;
+                    match cmd {
+                        Command::Set { key, .. } => {
+                            index.insert(key);
+                        },
+                        Command::Rm { key } => {
+                            // compaction drops tombstones together with the value, so the set must still be there
+                            if !index.remove(&key) {
+                                report.inconsistencies.push(format!("{} offset {}: remove of key {} which is not set", file, pos, key));
+                            }
+                        },
+                    }
+                },
+                Entry::Corrupt { start, end, error } => {
+                    report.corrupt.push(CorruptRange { file: file.clone(), start, end, reason: error.to_string() });
+                },
+            })?;
+        }
+    }
+    Ok(report)
+}
+
+/// salvage every readable record of the kvs store in dir into a fresh generation of each namespace
+/// and write a report of the dropped ranges into REPAIR_REPORT_PATH.
+/// fails while anyone else has the store open
+pub fn repair_store(dir: impl AsRef<Path>, options: &KvStoreOptions) -> Result<RepairReport> {
+    let dir = dir.as_ref();
+    check_engine(dir)?;
+    let _lock = DirLock::exclusive(dir)?;
+    let (namespaces, _) = log_files(dir)?;
+    let mut report = RepairReport::default();
+
+    for (name, files) in &namespaces {
+        // replay the readable records
+        let mut pairs = BTreeMap::new();
+        for file in files {
+            let mut failed = None;
+            walk(&dir.join(file), options, &mut |entry| match entry {
+                Entry::Record { cmd: Command::Set { key, value, expires }, .. } => {
+                    pairs.insert(key, (value, expires));
+                },
+                Entry::Record { cmd: Command::Rm { key }, .. } => {
+                    pairs.remove(&key);
+                },
+                // without the right key every encrypted record looks corrupt, never drop them
+                Entry::Corrupt { error: error @ (KvStoreError::MissingEncryptionKey | KvStoreError::WrongEncryptionKey), .. } => {
+                    failed.get_or_insert(error);
+                },
+                Entry::Corrupt { start, end, error } => {
+                    report.dropped.push(CorruptRange { file: file.clone(), start, end, reason: error.to_string() });
+                },
+            })?;
+            if let Some(error) = failed {
+                return Err(error);
+            }
+        }
+
+        // write them into the generation after the newest one, then drop the old files
+        let gen = files.iter()
+            .filter_map(|file| file.strip_prefix(&format!("{}.", name))?.strip_suffix(".log")?.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0) + 1;
+        let tmp = dir.join(format!("{}.repair", name));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for (key, (value, expires)) in &pairs {
+            let data = serde_json::to_vec(&Command::Set { key: key.clone(), value: value.clone(), expires: *expires })?;
+            writer.write_all(&encode(&data, options)?)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, dir.join(log_name(name, gen)))?;
+        sync_dir(dir)?;
+        for file in files {
+            fs::remove_file(dir.join(file))?;
+        }
+        sync_dir(dir)?;
+        report.namespaces.push((name.clone(), pairs.len() as u64));
+    }
+    fs::write(dir.join(REPAIR_REPORT_PATH), serde_json::to_vec_pretty(&report)?)?;
+    Ok(report)
+}
+
+// verify and repair read the log files directly, which only makes sense for the kvs engine
+pub(crate) fn check_engine(dir: &Path) -> Result<()> {
+    let meta = dir.join(ENGINE_META_PATH);
+    if meta.exists() {
+        let engine: EngineType = fs::read_to_string(meta)?.parse().map_err(KvStoreError::StringErr)?;
+        if engine != EngineType::Kvs {
+            return Err(KvStoreError::StringErr(format!("{} uses the {} engine, only kvs logs can be checked", dir.display(), engine)));
+        }
+    }
+    Ok(())
+}
+
+// the log files of every namespace oldest first, and the `.log` files open would ignore
+pub(crate) fn log_files(dir: &Path) -> Result<(LogFiles, Vec<String>)> {
+    let mut gens = list_generations(dir)?;
+    gens.sort();
+    let mut namespaces = LogFiles::new();
+    for (name, gen) in &gens {
+        namespaces.entry(name.clone()).or_default().push(log_name(name, *gen));
+    }
+    // open turns the legacy log into the first generation of the default namespace,
+    // until then walk reads it, as json commands if it was written before records were framed
+    if dir.join(LEGACY_LOG).exists() && !dir.join(log_name(DEFAULT_NAMESPACE, 1)).exists() {
+        namespaces.entry(DEFAULT_NAMESPACE.to_owned()).or_default().insert(0, LEGACY_LOG.to_owned());
+    }
+
+    let mut stray = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        let known = namespaces.values().any(|files| files.contains(&file_name));
+        if file_name.ends_with(".log") && !known {
+            stray.push(file_name);
+        }
+    }
+    stray.sort();
+    Ok((namespaces, stray))
+}
+
+// decode every record of the log file at path, unreadable bytes are reported as one range
+// up to the next intact record
+pub(crate) fn walk(path: &Path, options: &KvStoreOptions, f: &mut dyn FnMut(Entry)) -> Result<()> {
+    if path.file_name().is_some_and(|name| name == LEGACY_LOG) && is_json_log(path)? {
+        return walk_legacy(path, f);
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    let mut pos = 0;
+    while pos < file_len {
+        let (end, error) = match read_frame(&mut reader, pos, file_len)? {
+            Some(frame) => match decode(&frame, options) {
+                Ok(cmd) => {
+                    let len = frame.len() as u64;
+                    f(Entry::Record { pos, len, cmd });
+                    pos += len;
+                    continue;
+                },
+                // the frame is intact, only this record is lost
+                Err(error) => (pos + frame.len() as u64, error),
+            },
+            None => (resync(&mut reader, pos + 1, file_len)?, KvStoreError::CorruptRecord),
+        };
+        f(Entry::Corrupt { start: pos, end, error });
+        pos = end;
+        reader.seek(SeekFrom::Start(pos))?;
+    }
+    Ok(())
+}
+
+// read the frame at pos if it is intact, without decoding it.
+// the length is checked against the file before anything is allocated for it
+fn read_frame(reader: &mut BufReader<File>, pos: u64, file_len: u64) -> Result<Option<Vec<u8>>> {
+    if pos + HEADER_LEN > file_len {
+        return Ok(None);
+    }
+    let mut frame = vec![0u8; HEADER_LEN as usize];
+    reader.read_exact(&mut frame)?;
+    let len = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]) as u64;
+    if pos + HEADER_LEN + len > file_len {
+        return Ok(None);
+    }
+    frame.resize((HEADER_LEN + len) as usize, 0);
+    reader.read_exact(&mut frame[HEADER_LEN as usize..])?;
+    Ok(record::frame_len(&frame).map(|_| frame))
+}
+
+// the offset of the next intact record at or after from, or the end of the file.
+// the file is scanned one window at a time, headers whose length reaches past the end of the
+// file are skipped without hashing anything
+fn resync(reader: &mut BufReader<File>, from: u64, file_len: u64) -> Result<u64> {
+    let mut start = from;
+    let mut window = Vec::with_capacity(RESYNC_WINDOW);
+    while start + HEADER_LEN <= file_len {
+        reader.seek(SeekFrom::Start(start))?;
+        window.clear();
+        reader.by_ref().take(RESYNC_WINDOW as u64).read_to_end(&mut window)?;
+        // the offsets with a whole header in the window, the next window starts after them
+        let headers = window.len() + 1 - HEADER_LEN as usize;
+        for i in 0..headers {
+            let at = start + i as u64;
+            let len = u32::from_le_bytes([window[i + 4], window[i + 5], window[i + 6], window[i + 7]]) as u64;
+            if at + HEADER_LEN + len > file_len {
+                continue;
+            }
+            let intact = if i + (HEADER_LEN + len) as usize <= window.len() {
+                record::frame_len(&window[i..]).is_some()
+            } else {
+                // a record larger than what is left of the window
+                reader.seek(SeekFrom::Start(at))?;
+                read_frame(reader, at, file_len)?.is_some()
+            };
+            if intact {
+                return Ok(at);
+            }
+        }
+        start += headers as u64;
+    }
+    Ok(file_len)
+}
+
+// the commands of a legacy kvstore.log, everything after the first unreadable one is corrupt
+fn walk_legacy(path: &Path, f: &mut dyn FnMut(Entry)) -> Result<()> {
+    let file_len = fs::metadata(path)?.len();
+    let mut commands = legacy_commands(path)?;
+    let mut pos = 0;
+    while let Some(cmd) = commands.next() {
+        let end = commands.byte_offset() as u64;
+        match cmd {
+            Ok(cmd) => f(Entry::Record { pos, len: end - pos, cmd }),
+            Err(e) => {
+                f(Entry::Corrupt { start: pos, end: file_len, error: e.into() });
+                break;
+            },
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+fn decode(mut bytes: &[u8], options: &KvStoreOptions) -> Result<Command> {
+    match record::read_record(&mut bytes, options)? {
+        Some((data, _)) => Ok(serde_json::from_slice(&data)?),
+        None => Err(KvStoreError::CorruptRecord),
+    }
+}
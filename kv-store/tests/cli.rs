@@ -1,6 +1,13 @@
+// the original tests pass argument arrays by reference and kill servers without waiting
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvClient, AsyncKvServer, KvClient, KvEngine, KvServer, KvStore, KvStoreError, Protocol, Request, Response, SledKvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -151,6 +158,18 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// the connection threads can not be turned off
+#[test]
+fn server_cli_no_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4031", "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--threads needs at least one thread"));
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +243,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the restarted server needs the locks the killed one held
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +326,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the restarted server needs the locks the killed one held
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +358,1250 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_namespace() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--namespace", "sessions"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--namespace", "sessions"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr, "--namespace", "sessions"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_access_server_databases() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let db_root = temp_dir.path().join("dbs");
+    // an existing database keeps the engine recorded in its engine.meta
+    fs::create_dir_all(db_root.join("legacy")).unwrap();
+    fs::write(db_root.join("legacy").join("engine.meta"), "sled").unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--create-databases", "--databases"])
+        .arg(&db_root)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--db", "app1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "legacy", "--addr", addr, "--db", "legacy"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--db", "app1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--db", "legacy"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("legacy\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--db", "../escape"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid database"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dbs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("app1\tkvs\tloaded\t2 requests"))
+        .stdout(contains("legacy\tsled\tloaded\t2 requests"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_server_databases_not_created() {
+    let addr = "127.0.0.1:4026";
+    let temp_dir = TempDir::new().unwrap();
+    let db_root = temp_dir.path().join("dbs");
+    fs::create_dir_all(db_root.join("app1")).unwrap();
+    fs::create_dir_all(db_root.join("app2")).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--max-databases", "1", "--databases"])
+        .arg(&db_root)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--db", "app1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--db", "missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("database not found"));
+    assert!(!db_root.join("missing").exists());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--db", "app2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("too many open databases"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_backup_sled_engine() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let backup_root = temp_dir.path().join("backups");
+    let backup_dir = backup_root.join("backup");
+    fs::create_dir(&data_dir).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr, "--auth-token", "secret", "--backup-dir"])
+        .arg(&backup_root)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--token", "secret"])
+        .assert()
+        .success();
+
+    // the destination has to stay below the backup directory
+    for dest in ["../escape", "/tmp/escape"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", dest, "--addr", addr, "--token", "secret"])
+            .assert()
+            .failure()
+            .stderr(contains("invalid backup destination"));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr, "--token", "secret"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // the destination must be empty
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr, "--token", "secret"])
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(!temp_dir.path().join("escape").exists());
+
+    kvs::verify_checkpoint(&backup_dir).unwrap();
+    assert_eq!(fs::read_to_string(backup_dir.join("engine.meta")).unwrap(), "sled");
+    let backup = SledKvsEngine::open(&backup_dir).unwrap();
+    assert_eq!(backup.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+#[test]
+fn cli_admin_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs_dir = temp_dir.path().join("kvs");
+    let sled_dir = temp_dir.path().join("sled");
+    let dump = temp_dir.path().join("dump.csv");
+    {
+        fs::create_dir(&kvs_dir).unwrap();
+        fs::write(kvs_dir.join("engine.meta"), "kvs").unwrap();
+        let store = KvStore::open(&kvs_dir).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "a,b\nc".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--format", "csv", "--encoding", "base64", "--output"])
+        .arg(&dump)
+        .arg(&kvs_dir)
+        .assert()
+        .success()
+        .stderr(contains("exported 2 pairs"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["import", "--engine", "sled", "--format", "csv", "--encoding", "base64", "--input"])
+        .arg(&dump)
+        .arg(&sled_dir)
+        .assert()
+        .success()
+        .stderr(contains("imported 2 pairs"));
+
+    // the imported directory is a regular sled data directory
+    assert_eq!(fs::read_to_string(sled_dir.join("engine.meta")).unwrap(), "sled");
+    let sled = SledKvsEngine::open(&sled_dir).unwrap();
+    assert_eq!(sled.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(sled.get("key2".to_owned()).unwrap(), Some("a,b\nc".to_owned()));
+    drop(sled);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("export")
+        .arg(&sled_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key1","value":"value1"}"#));
+}
+
+#[test]
+fn cli_admin_export_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs_dir = temp_dir.path().join("kvs");
+    {
+        let store = KvStore::open(&kvs_dir).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+
+    // a store opened read-only elsewhere can be exported, nothing is written into it
+    let reader = KvStore::open_read_only(&kvs_dir).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("export")
+        .arg(&kvs_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key1","value":"value1"}"#));
+    assert!(!kvs_dir.join("engine.meta").exists());
+    drop(reader);
+
+    let missing = temp_dir.path().join("missing");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("export")
+        .arg(&missing)
+        .assert()
+        .failure()
+        .stderr(contains("is not a data directory"));
+    assert!(!missing.exists());
+}
+
+#[test]
+fn cli_admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine.meta"), "kvs").unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("from kvs to sled"))
+        .stdout(contains("default\t1 pairs"));
+
+    // the server now refuses the old engine
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let sled = SledKvsEngine::open(temp_dir.path()).unwrap();
+    assert_eq!(sled.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+#[test]
+fn cli_admin_verify_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("1 records checked"));
+
+    let log = temp_dir.path().join("default.1.log");
+    let mut bytes = fs::read(&log).unwrap();
+    bytes.extend_from_slice(b"garbage");
+    fs::write(&log, &bytes).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("corrupt default.1.log"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("repair")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("dropped default.1.log"))
+        .stdout(contains("default\t1 keys kept"));
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+// a store written before the log was framed, kvs-admin reads its json commands without opening it
+#[test]
+fn cli_admin_verify_repair_legacy_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let legacy = concat!(
+        "{\n  \"Set\": {\n    \"key\": \"key1\",\n    \"value\": \"value1\"\n  }\n}",
+        "{\n  \"Set\": {\n    \"key\": \"key2\",\n    \"value\": \"value2\"\n  }\n}",
+        "{\n  \"Set\": {\n    \"key\": \"key1\",\n    \"value\": \"value3\"\n  }\n}",
+        "{\n  \"Rm\": {\n    \"key\": \"key2\"\n  }\n}",
+    );
+    fs::write(temp_dir.path().join("kvstore.log"), legacy).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("4 records checked"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("live set \"key1\" \"value3\""));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("repair")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("default\t1 keys kept"));
+
+    assert!(!temp_dir.path().join("kvstore.log").exists());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn cli_admin_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    }
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("default.1.log@0 len="))
+        .stdout(contains("seq=0 dead set \"key1\" \"value1\""))
+        .stdout(contains("seq=1 live set \"key1\" \"value2\""));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--stats", "--separator", "y"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("ke\t1\t"));
+}
+
+#[test]
+fn cli_server_locks_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // a second server on the same directory refuses to start
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::DirectoryLocked(pid)) => assert_eq!(pid, child.id()),
+        other => panic!("expected a locked directory, got {:?}", other.map(|_| ())),
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(KvStore::open(temp_dir.path()).is_ok());
+}
+
+#[test]
+fn cli_read_only_server() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine.meta"), "kvs").unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    }
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--read-only", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nkey2\tvalue2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value3", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["info", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"))
+        .stdout(contains("get: 1 hits, 0 misses"));
+
+    // errors come back as typed codes
+    let mut client = KvClient::new(addr.parse().unwrap()).unwrap();
+    assert!(matches!(client.set("key3".to_owned(), "value3".to_owned()), Err(KvStoreError::ReadOnly)));
+    assert!(matches!(client.get("key3".to_owned()), Err(KvStoreError::NotFound(_))));
+    assert!(matches!(client.query_index("email".to_owned(), "a".to_owned()), Err(KvStoreError::NotFound(_))));
+    assert!(matches!(client.select("db1".to_owned()), Err(KvStoreError::InvalidArgument(_))));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn cli_read_only_server_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine.meta"), "sled").unwrap();
+    // sled can not open its directory without writing into it
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--read-only", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn cli_server_config_file() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "max_file_size = 100\ncompaction = \"manual\"\n").unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--config"])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..5 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    // the log is split into generations of about 100 bytes
+    assert!(temp_dir.path().join("default.2.log").exists());
+
+    // a broken config keeps the server from starting
+    fs::write(&config, "sync = \"sometimes\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4014", "--config"])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_query_index() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "[[index]]\nname = \"email\"\nprefix = \"user:\"\npath = \"$.email\"\n").unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--config"])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, email) in [("user:1", "a@example.com"), ("user:2", "b@example.com"), ("user:3", "a@example.com")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, &format!("{{\"email\": \"{}\"}}", email), "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["query", "email", "a@example.com", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("user:1\nuser:3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["query", "phone", "1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("unknown index: phone"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// write a frame of the binary protocol and read the opcode and id of the reply
+fn binary_round_trip(stream: &mut TcpStream, opcode: u8, id: u32, payload: &[u8]) -> (u8, u32) {
+    stream.write_all(&(5 + payload.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(&[opcode]).unwrap();
+    stream.write_all(&id.to_be_bytes()).unwrap();
+    stream.write_all(payload).unwrap();
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut reply = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply).unwrap();
+    (reply[0], u32::from_be_bytes([reply[1], reply[2], reply[3], reply[4]]))
+}
+
+#[test]
+fn cli_binary_protocol() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "line1\nline2 \"quoted\"", "--protocol", "binary", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--protocol", "binary", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("line1\nline2 \"quoted\"\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("line1\nline2 \"quoted\"\n");
+
+    // a binary connection has to start with a Hello
+    let mut client = KvClient::with_protocol(addr.parse().unwrap(), Protocol::Binary).unwrap();
+    assert!(matches!(client.get("key1".to_owned()), Err(KvStoreError::Unsupported(_))));
+
+    // a broken payload only fails its own frame
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"KVSB").unwrap();
+    let hello = Request::Hello { version: kvs::PROTOCOL_VERSION, client: "test".to_owned(), features: Vec::new(), token: None };
+    assert_eq!(binary_round_trip(&mut stream, 10, 6, &bincode::serialize(&hello).unwrap()), (10, 6));
+    assert_eq!(binary_round_trip(&mut stream, 1, 7, b"\xff\xff\xff"), (0xff, 7));
+    let set = Request::Set { key: "key2".to_owned(), value: "value2".to_owned(), namespace: None };
+    assert_eq!(binary_round_trip(&mut stream, 1, 8, &bincode::serialize(&set).unwrap()), (1, 8));
+    let mut client = KvClient::with_protocol(addr.parse().unwrap(), Protocol::Binary).unwrap();
+    client.hello("test", &[], None).unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), "value2");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_hello_handshake() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("invalid auth token"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--token", "secret", "--addr", addr])
+        .assert()
+        .success();
+
+    let mut client = KvClient::new(addr.parse().unwrap()).unwrap();
+    // requests before the Hello are refused
+    assert!(matches!(client.get("key1".to_owned()), Err(KvStoreError::Unauthorized(_))));
+    let handshake = client.hello("test", &["watch"], Some("secret".to_owned())).unwrap();
+    assert_eq!(handshake.version, kvs::PROTOCOL_VERSION);
+    assert_eq!(handshake.engine, "kvs");
+    assert!(handshake.features.is_empty());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), "value1");
+
+    // an unknown version is rejected and the connection closed
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(br#"{"Hello":{"version":99,"client":"future","token":"secret"}}"#).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert!(reply.contains("unsupported protocol version 99"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_pipelined_requests() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--request-threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut client = KvClient::with_protocol(addr.parse().unwrap(), protocol).unwrap();
+        client.hello("test", &[], None).unwrap();
+        // every get follows the set of its key, so it has to see the value
+        let mut requests = Vec::new();
+        for i in 0..1000 {
+            let key = format!("{}{}", protocol, i);
+            requests.push(Request::Set { key: key.clone(), value: format!("value{}", i), namespace: None });
+            requests.push(Request::Get { key, namespace: None });
+        }
+        requests.push(Request::Get { key: "missing".to_owned(), namespace: None });
+        let responses = client.pipeline(&requests).unwrap();
+        assert_eq!(responses.len(), requests.len());
+        for (i, pair) in responses.chunks(2).take(1000).enumerate() {
+            assert!(matches!(pair[0], Ok(Response::Set { .. })));
+            match pair[1] {
+                Ok(Response::Get { ref value, .. }) => assert_eq!(*value, format!("value{}", i)),
+                ref other => panic!("unexpected response {:?}", other),
+            }
+        }
+        assert!(matches!(responses.last(), Some(Err(KvStoreError::NotFound(_)))));
+
+        // replies are matched by id
+        let first = client.send(&Request::Get { key: format!("{}1", protocol), namespace: None }).unwrap();
+        let second = client.send(&Request::Get { key: format!("{}2", protocol), namespace: None }).unwrap();
+        let mut replies = [client.receive().unwrap(), client.receive().unwrap()];
+        replies.sort_by_key(|(id, _)| *id);
+        assert_eq!((replies[0].0, replies[1].0), (first, second));
+        assert!(matches!(replies[1].1, Ok(Response::Get { ref value, .. }) if value == "value2"));
+        assert_eq!(client.get(format!("{}999", protocol)).unwrap(), "value999");
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_multi_key() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2", "value2", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "missing", "key2", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("missing the value of key key2"));
+    // the kvs engine has no atomic batches
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key3", "value3", "--atomic", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("atomic batches"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mdel", "key1", "missing", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("missing: Key not found"));
+
+    let mut client = KvClient::with_protocol(addr.parse().unwrap(), Protocol::Binary).unwrap();
+    client.hello("test", &[], None).unwrap();
+    let values = client.mget(vec!["key1".to_owned(), "key2".to_owned(), "key3".to_owned()]).unwrap();
+    assert!(matches!(values[0], Err(KvStoreError::NotFound(_))));
+    assert_eq!(values[1].as_ref().unwrap(), "value2");
+    assert!(matches!(values[2], Err(KvStoreError::NotFound(_))));
+    let results = client.mset(vec![("key3".to_owned(), "value3".to_owned())], true);
+    assert!(matches!(results, Err(KvStoreError::Unsupported(_))));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// write a RESP command and check the reply byte for byte
+fn resp_round_trip(stream: &mut TcpStream, request: &str, reply: &str) {
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut buf = vec![0; reply.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), reply, "reply to {:?}", request);
+}
+
+#[test]
+fn cli_resp_protocol() {
+    let addr = "127.0.0.1:4020";
+    let resp_addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--resp-addr", resp_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    resp_round_trip(&mut stream, "PING\r\n", "+PONG\r\n");
+    resp_round_trip(&mut stream, "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n", "+OK\r\n");
+    resp_round_trip(&mut stream, "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", "$6\r\nvalue1\r\n");
+    resp_round_trip(&mut stream, "*2\r\n$3\r\nget\r\n$7\r\nmissing\r\n", "$-1\r\n");
+    // NX and XX
+    resp_round_trip(&mut stream, "*4\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$1\r\nx\r\n$2\r\nNX\r\n", "$-1\r\n");
+    resp_round_trip(&mut stream, "*4\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$1\r\nx\r\n$2\r\nXX\r\n", "$-1\r\n");
+    resp_round_trip(&mut stream, "*4\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n$2\r\nnx\r\n", "+OK\r\n");
+    // an expiring key
+    resp_round_trip(&mut stream, "*5\r\n$3\r\nSET\r\n$3\r\ntmp\r\n$1\r\nx\r\n$2\r\nPX\r\n$2\r\n50\r\n", "+OK\r\n");
+    resp_round_trip(&mut stream, "*4\r\n$3\r\nSET\r\n$3\r\ntmp\r\n$1\r\nx\r\n$2\r\nEX\r\n", "-ERR syntax error\r\n");
+    thread::sleep(Duration::from_millis(100));
+    resp_round_trip(&mut stream, "*3\r\n$6\r\nEXISTS\r\n$3\r\ntmp\r\n$4\r\nkey1\r\n", ":1\r\n");
+    // pipelined commands
+    resp_round_trip(
+        &mut stream,
+        "*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\nx\r\n*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nc\r\n",
+        "+OK\r\n*2\r\n$1\r\n1\r\n$-1\r\n",
+    );
+    resp_round_trip(&mut stream, "*2\r\n$4\r\nINCR\r\n$1\r\na\r\n", ":2\r\n");
+    resp_round_trip(&mut stream, "*2\r\n$4\r\nINCR\r\n$1\r\nb\r\n", "-ERR value is not an integer or out of range\r\n");
+    resp_round_trip(&mut stream, "*2\r\n$4\r\nKEYS\r\n$4\r\nkey*\r\n", "*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n");
+    resp_round_trip(
+        &mut stream,
+        "*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nCOUNT\r\n$1\r\n2\r\n",
+        "*2\r\n$1\r\n2\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n",
+    );
+    resp_round_trip(
+        &mut stream,
+        "*6\r\n$4\r\nSCAN\r\n$1\r\n2\r\n$5\r\nMATCH\r\n$2\r\n?2\r\n$5\r\nCOUNT\r\n$2\r\n10\r\n",
+        "*2\r\n$1\r\n0\r\n*0\r\n",
+    );
+    resp_round_trip(&mut stream, "*4\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$7\r\nmissing\r\n", ":2\r\n");
+    resp_round_trip(&mut stream, "*1\r\n$5\r\nFLUSH\r\n", "-ERR unknown command 'flush'\r\n");
+    resp_round_trip(&mut stream, "*1\r\n$3\r\nGET\r\n", "-ERR wrong number of arguments for 'get' command\r\n");
+    stream.write_all(b"*1\r\n$4\r\nINFO\r\n").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut header = String::new();
+    reader.read_line(&mut header).unwrap();
+    let mut info = vec![0; header[1..].trim_end().parse::<usize>().unwrap() + 2];
+    reader.read_exact(&mut info).unwrap();
+    let info = String::from_utf8(info).unwrap();
+    assert!(info.contains("engine:kvs\r\n") && info.contains("db0:keys="), "{}", info);
+    resp_round_trip(&mut stream, "QUIT\r\n", "+OK\r\n");
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // the keys are shared with the kvs protocol
+    let mut client = KvClient::new(addr.parse().unwrap()).unwrap();
+    assert_eq!(client.get("a".to_owned()).unwrap(), "2");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// send one HTTP request on a new connection and read the whole response
+fn http_request(addr: &str, request: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    response
+}
+
+fn http_text(addr: &str, request: &str) -> String {
+    String::from_utf8(http_request(addr, request.as_bytes())).unwrap()
+}
+
+#[test]
+fn cli_http_gateway() {
+    let addr = "127.0.0.1:4022";
+    let http_addr = "127.0.0.1:4023";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--http-addr", http_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let response = http_text(http_addr, "PUT /kv/key%201 HTTP/1.1\r\nContent-Length: 6\r\nConnection: close\r\n\r\nvalue1");
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+    let response = http_text(http_addr, "GET /kv/key%201 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    assert!(response.ends_with("\r\n\r\nvalue1"));
+    let response = http_text(http_addr, "GET /kv/missing HTTP/1.0\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+    // binary values round trip as octet streams
+    let mut request = b"PUT /kv/bin HTTP/1.1\r\nContent-Type: application/octet-stream\r\nContent-Length: 4\r\nConnection: close\r\n\r\n".to_vec();
+    request.extend_from_slice(&[0, 159, 146, 150]);
+    assert!(http_request(http_addr, &request).starts_with(b"HTTP/1.1 204"));
+    let response = http_request(http_addr, b"GET /kv/bin HTTP/1.1\r\nAccept: application/octet-stream\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with(b"Content-Type: application/octet-stream\r\nContent-Length: 4\r\nConnection: close\r\n\r\n\x00\x9f\x92\x96"));
+    let mut request = b"PUT /kv/bin HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\n".to_vec();
+    request.extend_from_slice(&[0xff, 0xfe]);
+    assert!(http_request(http_addr, &request).starts_with(b"HTTP/1.1 415"));
+
+    // two requests on one keep-alive connection
+    let response = http_text(
+        http_addr,
+        "PUT /kv/key2 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue2GET /kv?prefix=key&limit=5 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"), "{}", response);
+    assert!(response.ends_with(r#"[{"key":"key 1","value":"value1"},{"key":"key2","value":"value2"}]"#), "{}", response);
+
+    let response = http_text(http_addr, "DELETE /kv/key2 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    let response = http_text(http_addr, "DELETE /kv/key2 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let response = http_text(http_addr, "POST /kv/key2 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 405") && response.contains("Allow: GET, PUT, DELETE\r\n"), "{}", response);
+    let response = http_text(http_addr, "GET /stats HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"), "{}", response);
+    assert!(response.contains(r#""keys":2"#), "{}", response);
+    let response = http_text(http_addr, "GET /nowhere HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+    // the gateway writes the same store as the kvs protocol
+    let mut client = KvClient::new(addr.parse().unwrap()).unwrap();
+    assert_eq!(client.get("key 1".to_owned()).unwrap(), "value1");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_async_server() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--async", "--blocking-threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // idle connections do not hold a thread
+    let idle: Vec<TcpStream> = (0..64).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--protocol", "binary"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        for protocol in [Protocol::Json, Protocol::Binary] {
+            let mut client = AsyncKvClient::with_protocol(addr.parse().unwrap(), protocol).await.unwrap();
+            assert_eq!(client.hello("test", &[], None).await.unwrap().engine, "kvs");
+            client.set(format!("{}1", protocol), "a".to_owned()).await.unwrap();
+            client.mset(vec![(format!("{}2", protocol), "b".to_owned())], false).await.unwrap();
+            assert_eq!(client.get(format!("{}1", protocol)).await.unwrap(), "a");
+            assert!(matches!(client.get("missing".to_owned()).await, Err(KvStoreError::NotFound(_))));
+            let pairs = client.scan(protocol.to_string(), None).await.unwrap();
+            assert_eq!(pairs, vec![(format!("{}1", protocol), "a".to_owned()), (format!("{}2", protocol), "b".to_owned())]);
+            client.rm(format!("{}1", protocol)).await.unwrap();
+            let values = client.mget(vec![format!("{}1", protocol), format!("{}2", protocol)]).await.unwrap();
+            assert!(matches!(values[0], Err(KvStoreError::NotFound(_))));
+            assert_eq!(values[1].as_ref().unwrap(), "b");
+        }
+        // many clients at once
+        let tasks: Vec<_> = (0..32)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let mut client = AsyncKvClient::new(addr.parse().unwrap()).await.unwrap();
+                    client.set(format!("task{}", i), i.to_string()).await.unwrap();
+                    client.get(format!("task{}", i)).await.unwrap()
+                })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), i.to_string());
+        }
+    });
+    drop(idle);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn server_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = KvServer::new("127.0.0.1:0".parse().unwrap(), store, SharedQueueThreadPool::new(4).unwrap())
+        .unwrap()
+        .shutdown_timeout(Duration::from_secs(5));
+    let (addr, handle) = (server.addr(), server.shutdown_handle());
+    let (sender, receiver) = mpsc::channel();
+    let server_thread = thread::spawn(move || sender.send(server.run().map(|_| ())).unwrap());
+
+    let mut client = KvClient::new(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // an idle connection does not hold up the shutdown
+    let _idle = TcpStream::connect(addr).unwrap();
+    handle.shutdown();
+    assert!(handle.is_shutdown());
+    receiver.recv_timeout(Duration::from_secs(5)).expect("server did not stop").unwrap();
+    server_thread.join().unwrap();
+
+    // the server stopped accepting, and released the directory with the data synced
+    assert!(TcpStream::connect(addr).is_err());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+#[test]
+fn cli_server_stops_on_sigterm() {
+    let addr = "127.0.0.1:4025";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--shutdown-timeout", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).assert().success();
+    assert!(child.wait().unwrap().success());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+#[test]
+fn cli_async_server_stops_on_sigterm() {
+    let addr = "127.0.0.1:4028";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--async", "--shutdown-timeout", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    // an idle connection does not hold up the shutdown
+    let _idle = TcpStream::connect(addr).unwrap();
+
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).assert().success();
+    assert!(child.wait().unwrap().success());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+// a server with max_connections(1), idle_timeout(500ms) and max_request_size(1024) on addr
+fn check_connection_limits(addr: std::net::SocketAddr) {
+    let mut client = KvClient::new(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // the second connection is refused politely in either protocol
+    for protocol in [Protocol::Json, Protocol::Binary] {
+        let mut refused = KvClient::with_protocol(addr, protocol).unwrap();
+        assert!(matches!(refused.get("key1".to_owned()), Err(KvStoreError::Busy(_))));
+    }
+
+    // requests may wait on the server as long as their deadline allows
+    client.set_deadline(Some(Duration::from_secs(10)));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), "value1");
+    client.set_deadline(Some(Duration::ZERO));
+    assert!(matches!(client.get("key1".to_owned()), Err(KvStoreError::DeadlineExceeded(_))));
+    client.set_deadline(None);
+    // a large binary request fails alone, a json one ends the connection
+    let large = "x".repeat(2048);
+    assert!(matches!(client.set("large".to_owned(), large.clone()), Err(KvStoreError::TooLarge(_))));
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+    let mut client = KvClient::with_protocol(addr, Protocol::Binary).unwrap();
+    client.hello("test", &[], None).unwrap();
+    assert!(matches!(client.set("large".to_owned(), large), Err(KvStoreError::TooLarge(_))));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), "value1");
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+    // a json request which is past its deadline is refused, writes included
+    let mut client = KvClient::with_protocol(addr, Protocol::Json).unwrap();
+    client.set_deadline(Some(Duration::ZERO));
+    assert!(matches!(client.set("key1".to_owned(), "value2".to_owned()), Err(KvStoreError::DeadlineExceeded(_))));
+    client.set_deadline(None);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), "value1");
+    // brackets and quotes inside strings do not end a document
+    let tricky = r#"}]"\{"#.to_owned();
+    client.set("key2".to_owned(), tricky.clone()).unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), tricky);
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+
+    // an idle connection is closed and gives its slot back
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(KvClient::new(addr).unwrap().get("key1".to_owned()).unwrap(), "value1");
+}
+
+#[test]
+fn server_connection_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = KvServer::new("127.0.0.1:0".parse().unwrap(), store, SharedQueueThreadPool::new(4).unwrap())
+        .unwrap()
+        .max_connections(1)
+        .idle_timeout(Duration::from_millis(500))
+        .max_request_size(1024);
+    let (addr, handle) = (server.addr(), server.shutdown_handle());
+    let server_thread = thread::spawn(move || server.run().map(|_| ()));
+    check_connection_limits(addr);
+    handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let server = AsyncKvServer::new("127.0.0.1:0".parse().unwrap(), store)
+        .unwrap()
+        .max_connections(1)
+        .idle_timeout(Duration::from_millis(500))
+        .max_request_size(1024);
+    let addr = server.addr();
+    thread::spawn(move || server.run());
+    check_connection_limits(addr);
+}
+
+#[test]
+fn server_connection_limits_resp_http() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let (resp_addr, http_addr) = ("127.0.0.1:4029", "127.0.0.1:4030");
+    let mut server = KvServer::new("127.0.0.1:0".parse().unwrap(), store, SharedQueueThreadPool::new(4).unwrap())
+        .unwrap()
+        .resp_addr(resp_addr.parse().unwrap())
+        .unwrap()
+        .http_addr(http_addr.parse().unwrap())
+        .unwrap()
+        .max_connections(1)
+        .idle_timeout(Duration::from_millis(500));
+    let (addr, handle) = (server.addr(), server.shutdown_handle());
+    let server_thread = thread::spawn(move || server.run().map(|_| ()));
+
+    // the limit counts the connections of every listener
+    let mut client = KvClient::new(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let exchange = |addr: &str, request: &[u8]| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    };
+    assert!(exchange(resp_addr, b"PING\r\n").starts_with("-ERR max number of clients reached"));
+    assert!(exchange(http_addr, b"GET /stats HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 503"));
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+
+    // an idle connection is closed and gives its slot back
+    let mut resp = TcpStream::connect(resp_addr).unwrap();
+    resp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    resp.write_all(b"GET key1\r\n").unwrap();
+    let mut reply = [0; 64];
+    let n = resp.read(&mut reply).unwrap();
+    assert_eq!(&reply[..n], b"$6\r\nvalue1\r\n");
+    assert_eq!(resp.read(&mut reply).unwrap(), 0);
+    thread::sleep(Duration::from_millis(100));
+    assert!(exchange(http_addr, b"GET /kv/key1 HTTP/1.1\r\nConnection: close\r\n\r\n").starts_with("HTTP/1.1 200"));
+
+    handle.shutdown();
+    server_thread.join().unwrap().unwrap();
+}
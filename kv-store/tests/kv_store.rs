@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvEngine, Result};
+use kvs::{
+    AnyEngine, CompactionPolicy, Compression, DataKey, DumpFilter, DumpKind, EngineType, EvictionPolicy,
+    ExportFormat, IndexSpec, KvEngine, KvStore, KvStoreError, KvStoreOptions, OpStats, Result, SledKvsEngine,
+    SyncPolicy, ValueEncoding,
+};
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -158,6 +165,29 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// removes racing on the same key write one tombstone, the others fail
+#[test]
+fn concurrent_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..20 {
+        let key = format!("key{}", round);
+        store.set(key.clone(), "value".to_owned())?;
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8).map(|_| {
+            let (store, barrier, key) = (store.clone(), barrier.clone(), key.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                store.remove(key)
+            })
+        }).collect();
+        let removed = handles.into_iter().map(|handle| handle.join().unwrap()).filter(Result::is_ok).count();
+        assert_eq!(removed, 1);
+    }
+    assert_eq!(store.stats()?.removes, OpStats { hits: 20, misses: 140 });
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +239,1030 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// total size of the log generations of a namespace
+fn log_size(dir: &Path, namespace: &str) -> u64 {
+    fs::read_dir(dir)
+        .expect("fail to read store directory")
+        .map(|entry| entry.expect("fail to read directory entry"))
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with(&format!("{}.", namespace)) && name.ends_with(".log")
+        })
+        .map(|entry| entry.metadata().expect("fail to get log size").len())
+        .sum()
+}
+
+fn json_blob(i: usize) -> String {
+    let item = format!("{{\"id\": {}, \"name\": \"user{}\", \"active\": true}}", i, i);
+    format!("[{}]", vec![item; 64].join(","))
+}
+
+// Records written with different compression settings should be readable from the same log
+#[test]
+fn mixed_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Lz4),
+    )?;
+    store.set("lz4".to_owned(), json_blob(1))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Zstd),
+    )?;
+    store.set("zstd".to_owned(), json_blob(2))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), json_blob(3))?;
+    assert_eq!(store.get("lz4".to_owned())?, Some(json_blob(1)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("zstd".to_owned())?, Some(json_blob(2)));
+    assert_eq!(store.get("plain".to_owned())?, Some(json_blob(3)));
+
+    Ok(())
+}
+
+// Compaction should re-encode old records with the current compression
+#[test]
+fn compaction_recompresses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || log_size(temp_dir.path(), "default");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), json_blob(i))?;
+    }
+    drop(store);
+    let plain_size = log_size();
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Zstd),
+    )?;
+    store.compact()?;
+    assert!(log_size() * 4 < plain_size);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(json_blob(i)));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(json_blob(i)));
+    }
+
+    Ok(())
+}
+
+// a store written before the log was framed holds pretty printed json commands in kvstore.log
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let legacy = [
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+        r#"{"Set":{"key":"key1","value":"value3"}}"#,
+        r#"{"Rm":{"key":"key2"}}"#,
+    ];
+    let content: String = legacy
+        .iter()
+        .map(|command| serde_json::to_string_pretty(&serde_json::from_str::<serde_json::Value>(command).unwrap()).unwrap())
+        .collect();
+    fs::write(dir.join("kvstore.log"), content)?;
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    assert!(!dir.join("kvstore.log").exists());
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+    assert!(kvs::verify_store(dir, &KvStoreOptions::new())?.is_clean());
+    Ok(())
+}
+
+// a framed kvstore.log written before generations becomes the first generation
+#[test]
+fn open_legacy_framed_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    KvStore::open(dir)?.set("key1".to_owned(), "value1".to_owned())?;
+    fs::rename(dir.join("default.1.log"), dir.join("kvstore.log"))?;
+    assert!(kvs::verify_store(dir, &KvStoreOptions::new())?.is_clean());
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!dir.join("kvstore.log").exists());
+    Ok(())
+}
+
+// a corrupt length in a record header must not allocate what it claims
+#[test]
+fn corrupt_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    KvStore::open(dir)?.set("key1".to_owned(), "value1".to_owned())?;
+    let log = dir.join("default.1.log");
+    let mut bytes = fs::read(&log)?;
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, b'{']);
+    fs::write(&log, &bytes)?;
+    assert!(matches!(KvStore::open(dir), Err(KvStoreError::CorruptRecord)));
+    let report = kvs::verify_store(dir, &KvStoreOptions::new())?;
+    assert_eq!(report.corrupt.len(), 1);
+    Ok(())
+}
+
+// Values should not be stored in plain text and the store should not open without the key
+#[test]
+fn encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = DataKey::new([7; 32]);
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(key.clone()),
+    )?;
+    store.set("key1".to_owned(), "secret-value".to_owned())?;
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let content = fs::read(entry?.path())?;
+        assert!(!String::from_utf8_lossy(&content).contains("secret-value"));
+    }
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::MissingEncryptionKey) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("encrypted store opened without key"),
+    }
+    match KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(DataKey::new([8; 32])),
+    ) {
+        Err(KvStoreError::WrongEncryptionKey) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("encrypted store opened with wrong key"),
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().encryption_key(key))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret-value".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should rewrite records encrypted with an old key using the new key
+// sled can not honour encryption or size limits, so it refuses them instead of writing plain data
+#[test]
+fn sled_refuses_unsupported_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = [
+        KvStoreOptions::new().encryption_key(DataKey::new([7; 32])),
+        KvStoreOptions::new().old_key(DataKey::new([7; 32])),
+        KvStoreOptions::new().max_bytes(1024),
+        KvStoreOptions::new().max_keys(10),
+        KvStoreOptions::new().read_only(true),
+    ];
+    for options in options {
+        assert!(matches!(
+            AnyEngine::open(temp_dir.path(), EngineType::Sled, options),
+            Err(KvStoreError::Unsupported(_))
+        ));
+    }
+    assert!(fs::read_dir(temp_dir.path())?.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn encryption_key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = DataKey::new([1; 32]);
+    let new_key = DataKey::from_hex(&"2a".repeat(32))?;
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new()
+            .encryption_key(old_key.clone())
+            .compression(Compression::Lz4),
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), json_blob(i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new()
+            .encryption_key(new_key.clone())
+            .old_key(old_key),
+    )?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().encryption_key(new_key))?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(json_blob(i)));
+    }
+
+    Ok(())
+}
+
+// Namespaces should keep separate keys and survive reopening
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = store.namespace("sessions")?;
+
+    store.set("key1".to_owned(), "default".to_owned())?;
+    sessions.set("key1".to_owned(), "sessions".to_owned())?;
+    sessions.set("key2".to_owned(), "only-sessions".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert!(store.namespace("bad/name").is_err());
+    assert!(store.namespace("").is_err());
+
+    // Open from disk again and check persistent data
+    drop(sessions);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = store.namespace("sessions")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(sessions.get("key1".to_owned())?, Some("sessions".to_owned()));
+    assert_eq!(sessions.get("key2".to_owned())?, Some("only-sessions".to_owned()));
+    assert_eq!(
+        store.namespace(kvs::DEFAULT_NAMESPACE)?.get("key1".to_owned())?,
+        Some("default".to_owned())
+    );
+
+    Ok(())
+}
+
+// Every namespace should follow its own compaction policy
+#[test]
+fn namespace_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = |name: &str| log_size(temp_dir.path(), name);
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction(CompactionPolicy::Manual),
+    )?;
+    let eager = store.namespace_with("eager", CompactionPolicy::StaleBytes(4096))?;
+    for iter in 0..300 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        eager.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert!(log_size("eager") < 8192);
+    let manual_size = log_size("default");
+    assert!(manual_size > 8192);
+
+    store.compact()?;
+    assert!(log_size("default") < manual_size);
+    assert_eq!(store.get("key".to_owned())?, Some("299".to_owned()));
+    assert_eq!(eager.get("key".to_owned())?, Some("299".to_owned()));
+
+    // after a restart the namespace is already open, namespace_with still switches its policy
+    drop(eager);
+    drop(store);
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction(CompactionPolicy::Manual),
+    )?;
+    let eager = store.namespace_with("eager", CompactionPolicy::StaleBytes(4096))?;
+    for iter in 0..300 {
+        eager.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert!(log_size("eager") < 8192);
+    // a plain handle keeps the policy
+    store.namespace("eager")?.set("key".to_owned(), "last".to_owned())?;
+    assert_eq!(eager.get("key".to_owned())?, Some("last".to_owned()));
+
+    Ok(())
+}
+
+// A checkpoint taken while other threads keep writing should open as a consistent store
+#[test]
+fn checkpoint_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = store.namespace("sessions")?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        sessions.set(format!("key{}", i), format!("session{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 100..2000 {
+                store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+                if i % 500 == 0 {
+                    store.compact().unwrap();
+                }
+            }
+        })
+    };
+    let backup_dir = temp_dir.path().join("backup");
+    store.checkpoint(&backup_dir)?;
+    writer.join().unwrap();
+    kvs::verify_checkpoint(&backup_dir)?;
+
+    let backup = KvStore::open(&backup_dir)?;
+    let backup_sessions = backup.namespace("sessions")?;
+    for i in 0..100 {
+        assert_eq!(backup.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(backup_sessions.get(format!("key{}", i))?, Some(format!("session{}", i)));
+    }
+    // a key written after the checkpoint is either missing or complete
+    let mut missing = false;
+    for i in 100..2000 {
+        match backup.get(format!("key{}", i))? {
+            Some(value) => {
+                assert!(!missing);
+                assert_eq!(value, format!("value{}", i));
+            }
+            None => missing = true,
+        }
+    }
+    for i in 0..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Restore should replace the target directory only with a valid checkpoint
+#[test]
+fn restore_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    let store = KvStore::open(&data_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint(&backup_dir)?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+
+    // an open store is never replaced
+    match kvs::restore_checkpoint(&backup_dir, &data_dir) {
+        Err(KvStoreError::DirectoryLocked(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(()) => panic!("restored over an open store"),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    kvs::restore_checkpoint(&backup_dir, &data_dir)?;
+    let store = KvStore::open(&data_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // a restore cut off between its renames is finished by the next open with the staged copy,
+    // or rolled back without it
+    let (replaced, staging) = (temp_dir.path().join("data.replaced"), temp_dir.path().join("data.restoring"));
+    fs::create_dir(&staging)?;
+    for entry in fs::read_dir(&backup_dir)? {
+        let path = entry?.path();
+        if path.file_name().is_some_and(|name| name != kvs::MANIFEST_PATH) {
+            fs::copy(&path, staging.join(path.file_name().unwrap()))?;
+        }
+    }
+    fs::rename(&data_dir, &replaced)?;
+    let store = KvStore::open(&data_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(!replaced.exists() && !staging.exists());
+    fs::rename(&data_dir, &replaced)?;
+    let store = KvStore::open(&data_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    assert!(!replaced.exists());
+
+    // corrupt the backup, the data directory must stay untouched
+    let log = fs::read_dir(&backup_dir)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .expect("no log in checkpoint");
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&log, content)?;
+    match kvs::restore_checkpoint(&backup_dir, &data_dir) {
+        Err(KvStoreError::ChecksumMismatch(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(()) => panic!("restored a corrupt checkpoint"),
+    }
+    let store = KvStore::open(&data_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Scan should return the live pairs with the prefix in key order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:2", "user:1", "order:1", "user:3", "users"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("user:3".to_owned())?;
+    store.set("user:1".to_owned(), "updated".to_owned())?;
+
+    let pairs = store.scan("user:")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("user:1".to_owned(), "updated".to_owned()),
+            ("user:2".to_owned(), "value-user:2".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan("")?.count(), 4);
+    assert_eq!(store.namespace("other")?.scan("")?.count(), 0);
+
+    Ok(())
+}
+
+// Pairs exported from one engine should be imported unchanged into another
+#[test]
+fn export_import_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    let tricky = "line1\nline2, \"quoted\" \u{1F600}";
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("tricky,key".to_owned(), tricky.to_owned())?;
+    for i in 0..2500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    for &format in &[ExportFormat::JsonLines, ExportFormat::Csv] {
+        for &encoding in &[ValueEncoding::Plain, ValueEncoding::Base64, ValueEncoding::Hex] {
+            let mut buf = Vec::new();
+            let mut reports = Vec::new();
+            let count = kvs::export(&store, "", &mut buf, format, encoding, &mut |n| {
+                reports.push(n)
+            })?;
+            assert_eq!(count, 2502);
+            assert_eq!(reports, vec![1000, 2000, 2502]);
+            if encoding != ValueEncoding::Plain {
+                assert!(!String::from_utf8_lossy(&buf).contains("tricky"));
+            }
+
+            let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+            let sled = SledKvsEngine::open(sled_dir.path())?;
+            let count = kvs::import(&sled, buf.as_slice(), format, encoding, &mut |_| {})?;
+            assert_eq!(count, 2502);
+            assert_eq!(sled.get("tricky,key".to_owned())?, Some(tricky.to_owned()));
+            assert_eq!(sled.get("plain".to_owned())?, Some("value".to_owned()));
+            assert_eq!(sled.get("key2499".to_owned())?, Some("value2499".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+// Migrating back and forth should keep every namespace and switch engine.meta
+#[test]
+fn migrate_engine_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    fs::write(dir.join("engine.meta"), "kvs")?;
+    {
+        let store = KvStore::open(dir)?;
+        let sessions = store.namespace("sessions")?;
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+            sessions.set(format!("session{}", i), format!("user{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+    }
+
+    let report = kvs::migrate_engine(dir, EngineType::Sled, KvStoreOptions::new())?;
+    assert_eq!(report.from, EngineType::Kvs);
+    assert_eq!(
+        report.namespaces,
+        vec![("default".to_owned(), 199), ("sessions".to_owned(), 200)]
+    );
+    assert_eq!(fs::read_to_string(dir.join("engine.meta"))?, "sled");
+    assert_eq!(log_size(dir, "default"), 0);
+    {
+        let sled = SledKvsEngine::open(dir)?;
+        assert_eq!(sled.get("key0".to_owned())?, None);
+        assert_eq!(sled.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(
+            sled.namespace("sessions")?.get("session5".to_owned())?,
+            Some("user5".to_owned())
+        );
+    }
+
+    assert!(kvs::migrate_engine(dir, EngineType::Sled, KvStoreOptions::new()).is_err());
+    let report = kvs::migrate_engine(dir, EngineType::Kvs, KvStoreOptions::new())?;
+    assert_eq!(report.from, EngineType::Sled);
+    assert_eq!(fs::read_to_string(dir.join("engine.meta"))?, "kvs");
+    assert!(!dir.join("db").exists());
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+    assert_eq!(
+        store.namespace("sessions")?.get("session199".to_owned())?,
+        Some("user199".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn verify_and_repair_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    {
+        let store = KvStore::open(dir)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.namespace("sessions")?.set("session1".to_owned(), "user1".to_owned())?;
+    }
+    let report = kvs::verify_store(dir, &KvStoreOptions::new())?;
+    assert!(report.is_clean());
+    assert_eq!(report.records, 101);
+
+    // scribble over the middle of the log and leave a half written record at its end
+    let log = dir.join("default.1.log");
+    let mut bytes = fs::read(&log)?;
+    let middle = bytes.len() / 2;
+    bytes[middle..middle + 20].fill(0xff);
+    bytes.extend_from_slice(&[1, 2, 3, 4, 5]);
+    fs::write(&log, &bytes)?;
+    assert!(KvStore::open(dir).is_err());
+
+    let report = kvs::verify_store(dir, &KvStoreOptions::new())?;
+    assert_eq!(report.corrupt.len(), 2);
+    assert!(report.corrupt[0].start <= middle as u64 && report.corrupt[0].end > middle as u64);
+    assert_eq!(report.corrupt[1].end, bytes.len() as u64);
+
+    let report = kvs::repair_store(dir, &KvStoreOptions::new())?;
+    assert_eq!(report.dropped.len(), 2);
+    let (_, kept) = &report.namespaces[0];
+    assert!(*kept >= 97 && *kept < 100);
+    assert!(dir.join(kvs::REPAIR_REPORT_PATH).exists());
+    assert!(kvs::verify_store(dir, &KvStoreOptions::new())?.is_clean());
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.scan("")?.count() as u64, *kept);
+    assert_eq!(
+        store.namespace("sessions")?.get("session1".to_owned())?,
+        Some("user1".to_owned())
+    );
+
+    Ok(())
+}
+
+// older generations without live keys are still read by open, only files open skips are orphaned
+#[test]
+fn verify_orphaned_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    {
+        let store = KvStore::open(dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        // seal default.1.log, then overwrite its only key in the next generation
+        store.checkpoint(dir.join("backup"))?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+    }
+    assert!(kvs::verify_store(dir, &KvStoreOptions::new())?.is_clean());
+
+    fs::write(dir.join("default.log"), b"")?;
+    fs::write(dir.join("bad name.1.log"), b"")?;
+    let report = kvs::verify_store(dir, &KvStoreOptions::new())?;
+    assert_eq!(report.orphaned, vec!["bad name.1.log".to_owned(), "default.log".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn dump_log_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    {
+        let store = KvStore::open(dir)?;
+        store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set("user:2".to_owned(), "bob".to_owned())?;
+        store.set("user:1".to_owned(), "carol".to_owned())?;
+        store.set("post:1".to_owned(), "hello".to_owned())?;
+        store.remove("user:2".to_owned())?;
+    }
+
+    let mut records = Vec::new();
+    kvs::dump_store(dir, &KvStoreOptions::new(), &DumpFilter::default(), &mut |record| {
+        records.push(record)
+    })?;
+    assert_eq!(records.len(), 5);
+    assert_eq!(
+        records.iter().map(|record| record.seq).collect::<Vec<_>>(),
+        (0..5).map(Some).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        records.iter().map(|record| record.live).collect::<Vec<_>>(),
+        vec![false, false, true, true, false]
+    );
+    assert_eq!(records[0].offset, 0);
+    assert_eq!(records[1].offset, records[0].len);
+    assert_eq!(
+        records[4].kind,
+        DumpKind::Rm {
+            key: "user:2".to_owned()
+        }
+    );
+
+    // filter by prefix and offset range
+    let filter = DumpFilter {
+        prefix: "user:1".to_owned(),
+        from: Some(1),
+        ..DumpFilter::default()
+    };
+    let mut filtered = Vec::new();
+    kvs::dump_store(dir, &KvStoreOptions::new(), &filter, &mut |record| {
+        filtered.push(record)
+    })?;
+    assert_eq!(filtered.len(), 1);
+    assert_eq!(
+        filtered[0].kind,
+        DumpKind::Set {
+            key: "user:1".to_owned(),
+            value: "carol".to_owned()
+        }
+    );
+
+    let stats = kvs::dump_stats(dir, &KvStoreOptions::new(), None, ":")?;
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].prefix, "post");
+    assert_eq!(stats[0].live_keys, 1);
+    assert_eq!(stats[0].dead_bytes, 0);
+    assert_eq!(stats[1].prefix, "user");
+    assert_eq!(stats[1].live_keys, 1);
+    assert_eq!(stats[1].live_bytes, records[2].len);
+    assert_eq!(
+        stats[1].dead_bytes,
+        records[0].len + records[1].len + records[4].len
+    );
+
+    Ok(())
+}
+
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::DirectoryLocked(pid)) => assert_eq!(pid, std::process::id()),
+        other => panic!("expected a locked directory, got {:?}", other.map(|_| ())),
+    }
+    // clones share the lock, the last one releases it
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    assert!(KvStore::open_read_only(dir.join("missing")).is_err());
+    assert!(!dir.join("missing").exists());
+    {
+        let store = KvStore::open(dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.namespace("sessions")?.set("session1".to_owned(), "user1".to_owned())?;
+        assert!(matches!(
+            KvStore::open_read_only(dir),
+            Err(KvStoreError::DirectoryLocked(_))
+        ));
+    }
+    let files = |dir: &Path| -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files(dir);
+
+    // readers share the directory, writers are kept out
+    let reader = KvStore::open_read_only(dir)?;
+    let other = KvStore::open_read_only(dir)?;
+    assert!(matches!(
+        KvStore::open(dir),
+        Err(KvStoreError::DirectoryLocked(0))
+    ));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        other.namespace("sessions")?.get("session1".to_owned())?,
+        Some("user1".to_owned())
+    );
+    assert_eq!(reader.scan("")?.count(), 1);
+    assert!(matches!(
+        reader.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert!(matches!(reader.compact(), Err(KvStoreError::ReadOnly)));
+    assert_eq!(reader.namespace("empty")?.get("key1".to_owned())?, None);
+    assert_eq!(files(dir), before);
+
+    drop(reader);
+    drop(other);
+    let store = KvStore::open(dir)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn store_options_file_size_sync_and_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .sync(SyncPolicy::Always)
+        .cache_size(256)
+        .buffer_size(64)
+        .compaction(CompactionPolicy::Manual);
+    {
+        let store = KvStore::open_with(dir, options.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        // the cache never returns a value which has been overwritten
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key1".to_owned(), "changed".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+        for i in 0..100 {
+            assert!(store.get(format!("key{}", i))?.is_some());
+        }
+        store.remove("key2".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    let logs = fs::read_dir(dir)?
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with("default.")
+        })
+        .count();
+    assert!(logs > 2, "expect the log to be split, got {} files", logs);
+
+    let store = KvStore::open_with(dir, options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    store.compact()?;
+    assert_eq!(store.scan("")?.count(), 99);
+    Ok(())
+}
+
+#[test]
+fn store_options_from_toml() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("data.key"), "42".repeat(32))?;
+    let options = KvStoreOptions::from_toml(
+        r#"
+        compression = "zstd"
+        compaction = "manual"
+        sync = "100ms"
+        max_file_size = 4096
+        cache_size = 1024
+        read_only = false
+        key_file = "data.key"
+        "#,
+        temp_dir.path(),
+    )?;
+    assert!(!options.is_read_only());
+    let store = KvStore::open_with(temp_dir.path().join("db"), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    // the key from the config is needed to read the store
+    assert!(KvStore::open(temp_dir.path().join("db")).is_err());
+
+    assert!(KvStoreOptions::from_toml("sync = \"sometimes\"", temp_dir.path()).is_err());
+    assert!(KvStoreOptions::from_toml("unknown = 1", temp_dir.path()).is_err());
+    assert_eq!(
+        "250ms".parse::<SyncPolicy>(),
+        Ok(SyncPolicy::Interval(std::time::Duration::from_millis(250)))
+    );
+    assert_eq!(
+        "1024".parse::<CompactionPolicy>(),
+        Ok(CompactionPolicy::StaleBytes(1024))
+    );
+    Ok(())
+}
+
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path().join("kvs"),
+        KvStoreOptions::new().compaction(CompactionPolicy::Manual),
+    )?;
+    let sled = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+
+    fn exercise<E: KvEngine>(engine: &E) -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        engine.set("key1".to_owned(), "value3".to_owned())?;
+        engine.get("key1".to_owned())?;
+        engine.get("missing".to_owned())?;
+        engine.remove("key2".to_owned())?;
+        assert!(engine.remove("key2".to_owned()).is_err());
+        let stats = engine.stats()?;
+        assert_eq!(stats.keys, 1);
+        assert!(stats.live_bytes > 0);
+        assert!(stats.files > 0);
+        assert_eq!(stats.gets, OpStats { hits: 1, misses: 1 });
+        assert_eq!(stats.sets, OpStats { hits: 1, misses: 2 });
+        assert_eq!(stats.removes, OpStats { hits: 1, misses: 1 });
+        // other namespaces count separately
+        assert_eq!(engine.namespace("other")?.stats()?.keys, 0);
+        assert_eq!(engine.namespace("other")?.stats()?.sets, OpStats::default());
+        Ok(())
+    }
+    exercise(&store)?;
+    exercise(&sled)?;
+
+    let stats = store.stats()?;
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction, None);
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.files, 1);
+    // counters are shared by the handles of a namespace
+    assert_eq!(store.clone().stats()?.gets, OpStats { hits: 1, misses: 1 });
+    Ok(())
+}
+
+#[test]
+fn eviction_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limited = |policy| KvStoreOptions::new().max_keys(3).eviction(policy);
+
+    // lru drops the key read longest ago
+    let store = KvStore::open_with(temp_dir.path().join("lru"), limited(EvictionPolicy::Lru))?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), "v".to_owned())?;
+    }
+    store.get("a".to_owned())?;
+    store.set("d".to_owned(), "v".to_owned())?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.stats()?.evictions, 1);
+    // overwriting a key needs no room
+    store.set("a".to_owned(), "w".to_owned())?;
+    assert_eq!(store.stats()?.keys, 3);
+    drop(store);
+    // the eviction is a tombstone in the log
+    let store = KvStore::open_with(temp_dir.path().join("lru"), limited(EvictionPolicy::Lru))?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("a".to_owned())?, Some("w".to_owned()));
+    drop(store);
+
+    // lfu drops the key read least often
+    let store = KvStore::open_with(temp_dir.path().join("lfu"), limited(EvictionPolicy::Lfu))?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), "v".to_owned())?;
+        store.get(key.to_string())?;
+    }
+    store.get("a".to_owned())?;
+    store.get("c".to_owned())?;
+    store.set("d".to_owned(), "v".to_owned())?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    drop(store);
+
+    // ttl first drops the key expiring first, keys without ttl come last
+    let store = KvStore::open_with(temp_dir.path().join("ttl"), limited(EvictionPolicy::TtlFirst))?;
+    store.set("a".to_owned(), "v".to_owned())?;
+    store.set_with_ttl("b".to_owned(), "v".to_owned(), Duration::from_secs(60))?;
+    store.set_with_ttl("c".to_owned(), "v".to_owned(), Duration::from_secs(30))?;
+    store.set("d".to_owned(), "v".to_owned())?;
+    assert_eq!(store.get("c".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("v".to_owned()));
+    store.set_with_ttl("e".to_owned(), "v".to_owned(), Duration::from_millis(0))?;
+    assert_eq!(store.get("e".to_owned())?, None);
+    drop(store);
+
+    // random keeps the store within its limit
+    let store = KvStore::open_with(temp_dir.path().join("random"), limited(EvictionPolicy::Random))?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "v".to_owned())?;
+    }
+    assert_eq!(store.stats()?.keys, 3);
+    assert_eq!(store.get("key19".to_owned())?, Some("v".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn store_full_without_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().max_bytes(200))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let full = (0..10).map(|i| store.set(format!("key{}", i), "x".repeat(20))).find(|res| res.is_err());
+    assert!(matches!(full, Some(Err(KvStoreError::StoreFull))));
+    // a record larger than the limit never fits
+    assert!(matches!(store.set("big".to_owned(), "x".repeat(300)), Err(KvStoreError::StoreFull)));
+    // removing a key makes room again
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.stats()?.live_bytes <= 200);
+
+    let store = KvStore::open_with(temp_dir.path().join("evict"), KvStoreOptions::new().max_bytes(200).eviction(EvictionPolicy::Lru))?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "x".repeat(20))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.live_bytes <= 200);
+    assert!(stats.evictions > 0);
+    assert_eq!("ttl".parse::<EvictionPolicy>(), Ok(EvictionPolicy::TtlFirst));
+    Ok(())
+}
+
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || -> Result<KvStoreOptions> {
+        Ok(KvStoreOptions::new()
+            .index(IndexSpec::new("email", "user:", "$.email")?)
+            .index(IndexSpec::new("tag", "user:", "$.profile.tags")?))
+    };
+    let store = KvStore::open_with(temp_dir.path().join("kvs"), options()?)?;
+    let sled = SledKvsEngine::open_with(temp_dir.path().join("sled"), &options()?)?;
+    for engine in [AnyEngine::Kvs(store.clone()), AnyEngine::Sled(sled)] {
+        engine.set("user:1".to_owned(), r#"{"email": "a@example.com", "profile": {"tags": ["x", "y"]}}"#.to_owned())?;
+        engine.set("user:2".to_owned(), r#"{"email": "b@example.com", "profile": {"tags": ["y"]}}"#.to_owned())?;
+        engine.set("user:3".to_owned(), r#"{"email": "a@example.com"}"#.to_owned())?;
+        // other prefixes and values which are no JSON are not indexed
+        engine.set("admin:1".to_owned(), r#"{"email": "a@example.com"}"#.to_owned())?;
+        engine.set("user:4".to_owned(), "a@example.com".to_owned())?;
+        assert_eq!(engine.query_index("email", "a@example.com")?, vec!["user:1", "user:3"]);
+        assert_eq!(engine.query_index("tag", "y")?, vec!["user:1", "user:2"]);
+
+        engine.set("user:1".to_owned(), r#"{"email": "c@example.com"}"#.to_owned())?;
+        engine.remove("user:3".to_owned())?;
+        assert!(engine.query_index("email", "a@example.com")?.is_empty());
+        assert_eq!(engine.query_index("email", "c@example.com")?, vec!["user:1"]);
+        assert_eq!(engine.query_index("tag", "y")?, vec!["user:2"]);
+        assert!(matches!(engine.query_index("phone", "1"), Err(KvStoreError::UnknownIndex(_))));
+    }
+    drop(store);
+
+    // the index is rebuilt from the log
+    let store = KvStore::open_with(temp_dir.path().join("kvs"), options()?)?;
+    assert_eq!(store.query_index("email", "c@example.com")?, vec!["user:1"]);
+    assert_eq!(store.query_index("tag", "y")?, vec!["user:2"]);
+    store.set_with_ttl("user:5".to_owned(), r#"{"email": "c@example.com"}"#.to_owned(), Duration::from_millis(0))?;
+    assert_eq!(store.query_index("email", "c@example.com")?, vec!["user:1"]);
+
+    assert!(matches!(IndexSpec::new("bad", "", "email"), Err(KvStoreError::InvalidJsonPath(_))));
+    assert!(IndexSpec::new("item", "", "$.tags[1].name").is_ok());
+    Ok(())
+}
+
+// sled writes a batch as a whole, kvs refuses it
+#[test]
+fn set_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs = vec![("key1".to_owned(), "value1".to_owned()), ("key2".to_owned(), "value2".to_owned())];
+    {
+        let engine = SledKvsEngine::open(temp_dir.path())?;
+        engine.set("key1".to_owned(), "old".to_owned())?;
+        engine.set_batch(pairs.clone())?;
+        let stats = engine.stats()?;
+        assert_eq!(stats.keys, 2);
+        assert_eq!((stats.sets.hits, stats.sets.misses), (1, 2));
+    }
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.set_batch(pairs), Err(KvStoreError::Unsupported(_))));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}